@@ -0,0 +1,73 @@
+//! Measures the memory that reading and writing take at their peak, for every USFM book in a
+//! directory (the bundled books by default). Run with `cargo bench --bench memory -- path/to/bible`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use hallomai::{deserialize, serialize};
+
+/// Counts the bytes allocated, and the most there have been at once.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Runs `f`, giving back its result and the most memory it took at once.
+fn peak_of<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let result = f();
+    (result, PEAK.load(Ordering::Relaxed) - before)
+}
+
+fn megabytes(bytes: usize) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+}
+
+fn main() {
+    // `cargo bench` passes its own flags along
+    let directory = std::env::args().skip(1).find(|arg| !arg.starts_with("--")).unwrap_or_else(|| "assets/usfm".to_string());
+    let mut books: Vec<PathBuf> = fs::read_dir(&directory).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("usfm") || extension.eq_ignore_ascii_case("sfm")))
+        .collect();
+    books.sort();
+
+    const FORMATS: [&str; 3] = ["usx", "usj", "usfm"];
+    let (mut input_bytes, mut reading, mut writing) = (0, 0, [0; FORMATS.len()]);
+    for book in &books {
+        let usfm = fs::read_to_string(book).unwrap();
+        input_bytes += usfm.len();
+        let (root, peak) = peak_of(|| deserialize(usfm, "usfm").unwrap());
+        reading = reading.max(peak);
+        for (format, writing) in FORMATS.iter().zip(writing.iter_mut()) {
+            let (_, peak) = peak_of(|| serialize(&root, format).unwrap());
+            *writing = (*writing).max(peak);
+        }
+    }
+    println!("{} books, {} of USFM; the largest peak of a book:", books.len(), megabytes(input_bytes));
+    println!("reading USFM {:>10}", megabytes(reading));
+    for (format, writing) in FORMATS.iter().zip(writing) {
+        println!("writing {:<5}{:>10}", format.to_uppercase(), megabytes(writing));
+    }
+}
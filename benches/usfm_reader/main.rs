@@ -0,0 +1,54 @@
+//! Times reading USFM into the typed model, on the Song of Songs and on the Psalms of the
+//! benchmark in the README, and times the lexer of the reader against the regex tokenizer it
+//! replaced on the same books. Run with `cargo bench --bench usfm_reader`.
+
+#[path = "../../src/lexer_usfm.rs"]
+mod lexer_usfm;
+mod regex_tokenizer;
+
+use std::fs;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use hallomai::{deserialize, serialize};
+use lexer_usfm::Lexer;
+use regex_tokenizer::RegexTokenizer;
+
+const RUNS: u32 = 20;
+
+/// Runs `work` once to warm up, then `RUNS` times, and prints the time of a run.
+fn time(name: &str, usfm: &str, mut work: impl FnMut()) {
+    work();
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        work();
+        total += start.elapsed();
+    }
+    let per_run = total / RUNS;
+    let mb_per_s = usfm.len() as f64 / per_run.as_secs_f64() / 1_000_000.0;
+    println!("{:<28} {:>8} bytes {:>10.2?} per run {:>8.1} MB/s", name, usfm.len(), per_run, mb_per_s);
+}
+
+fn main() {
+    let song_of_songs = fs::read_to_string("assets/usfm/22-SNG.usfm").unwrap();
+    // The Psalms are only bundled as USX
+    let psalms_usx = fs::read_to_string("assets/usx/web_psa.usx").unwrap();
+    let psalms = serialize(&deserialize(psalms_usx, "usx").unwrap(), "usfm").unwrap();
+    let regex_tokenizer = RegexTokenizer::new();
+
+    for (name, usfm) in [("22-SNG.usfm", &song_of_songs), ("web_psa.usfm", &psalms)] {
+        let lexemes: Vec<_> = Lexer::new(usfm).collect();
+        let tokens = regex_tokenizer.tokenize(usfm);
+        println!("{}: {} tokens from the lexer, {} from the regex tokenizer", name, lexemes.len(), tokens.len());
+
+        time(&format!("{} lexer", name), usfm, || {
+            black_box(Lexer::new(black_box(usfm)).collect::<Vec<_>>());
+        });
+        time(&format!("{} regex tokenizer", name), usfm, || {
+            black_box(regex_tokenizer.tokenize(black_box(usfm)));
+        });
+        time(&format!("{} read", name), usfm, || {
+            black_box(deserialize(usfm.to_string(), "usfm").unwrap());
+        });
+    }
+}
@@ -0,0 +1,53 @@
+//! The tokenizer the USFM reader used before `Lexer`: one alternation of every kind of token,
+//! then each kind tried again on the text matched to find which one it was. Kept to time the
+//! lexer against it.
+
+use regex::Regex;
+
+pub struct RegexTokenizer {
+    kinds: Vec<(&'static str, Regex)>,
+    main_regex: Regex,
+}
+
+impl RegexTokenizer {
+    pub fn new() -> Self {
+        let patterns = [
+            ("chapter", r"([\r\n]*\\c[ \t]+(\d+)[ \t\r\n]*)"),
+            ("pubchapter", r"([\r\n]*\\cp[ \t]+([^\r\n]+)[ \t\r\n]*)"),
+            ("verses", r"(\\v[ \t]+([\w\-]+)[ \t\r\n]*)"),
+            ("attribute", r#"([ \t]*\|?[ \t]*([A-Za-z0-9\-]+)="((?:[^"\\]|\\.)*)"[ \t]?)"#),
+            ("defaultAttribute", r"([ \t]*\|[ \t]*([^|\\]*))"),
+            ("emptyMilestone", r"(\\([a-z1-9]+)\\[*])"),
+            ("startMilestoneTag", r"(\\([a-z1-9]+)-([se]))"),
+            ("endMilestoneTag", r"(\\([*]))"),
+            ("endTag", r"(\\([+]?[a-z\-]+)([1-9]?(-([1-9]))?)[*])"),
+            ("startTag", r"(\\([+]?[a-z\-]+)([1-9]?(-([1-9]))?)[ \t]?)"),
+            ("bareSlash", r"(\\)"),
+            ("quote", r#"(")"#),
+            ("eol", r"([ \t]*[\r\n]+[ \t]*)"),
+            ("noBreakSpace", r"~"),
+            ("softLinebreak", r"//"),
+            ("wordLike", r"([\p{L}\p{N}\p{M}\u2060]{1,127})"),
+            ("lineSpace", r"([\p{Zs}\t]{1,127})"),
+            ("punctuation", r"([\p{P}\p{Sm}\p{Sc}\p{Sk}\p{So}])"),
+            ("unknown", r"(.)"),
+        ];
+        let main_regex = Regex::new(&patterns.map(|(_, pattern)| pattern).join("|")).unwrap();
+        let kinds = patterns.iter().map(|&(subclass, pattern)| (subclass, Regex::new(pattern).unwrap())).collect();
+        RegexTokenizer { kinds, main_regex }
+    }
+
+    /// The kind of every token, with the text it was read from and the parts of it.
+    pub fn tokenize<'a>(&self, content: &'a str) -> Vec<(&'static str, Vec<&'a str>)> {
+        let mut tokens = Vec::new();
+        for found in self.main_regex.find_iter(content) {
+            for (subclass, regex) in &self.kinds {
+                if let Some(captures) = regex.captures(found.as_str()) {
+                    tokens.push((*subclass, captures.iter().map(|m| m.map_or("", |m| m.as_str())).collect()));
+                    break;
+                }
+            }
+        }
+        tokens
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+use regex::Regex;
+use crate::utils_usfm;
+use crate::structs_model::{CharMarkerObject, Content, InParaObject, MilestoneObject, Root};
+
+/// # A word of the original text, as written in a `\zaln-s` milestone.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SourceWord {
+    /// The word itself (`x-content`).
+    pub content: String,
+    pub strong: Option<String>,
+    pub lemma: Option<String>,
+    pub morph: Option<String>,
+    pub occurrence: u32,
+    pub occurrences: u32,
+    /// Any other attribute of the milestone, e.g. `x-tw`.
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// # A word of the translation, as written in a `\w` with its `x-occurrence`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetWord {
+    pub text: String,
+    /// Which occurrence of `text` in the verse this is, from 1.
+    pub occurrence: u32,
+    pub occurrences: u32,
+}
+
+/// # Words of the translation aligned to one or more words of the original.
+///
+/// Nested `\zaln-s` milestones give several source words, the outermost first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alignment {
+    pub sources: Vec<SourceWord>,
+    pub targets: Vec<TargetWord>,
+}
+
+/// # The alignments of a verse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerseAlignments {
+    pub chapter: String,
+    pub verse: String,
+    pub alignments: Vec<Alignment>,
+}
+
+impl SourceWord {
+    fn from_milestone(ms: &MilestoneObject) -> Self {
+        let mut attributes = ms.additional_properties.clone();
+        SourceWord {
+            content: attributes.remove("x-content").unwrap_or_default(),
+            strong: attributes.remove("x-strong"),
+            lemma: attributes.remove("x-lemma"),
+            morph: attributes.remove("x-morph"),
+            occurrence: occurrence(&mut attributes, "x-occurrence"),
+            occurrences: occurrence(&mut attributes, "x-occurrences"),
+            attributes,
+        }
+    }
+
+    fn to_milestone(&self) -> MilestoneObject {
+        let mut attributes = self.attributes.clone();
+        attributes.insert("x-content".to_string(), self.content.clone());
+        attributes.extend(self.strong.clone().map(|strong| ("x-strong".to_string(), strong)));
+        attributes.extend(self.lemma.clone().map(|lemma| ("x-lemma".to_string(), lemma)));
+        attributes.extend(self.morph.clone().map(|morph| ("x-morph".to_string(), morph)));
+        attributes.insert("x-occurrence".to_string(), self.occurrence.to_string());
+        attributes.insert("x-occurrences".to_string(), self.occurrences.to_string());
+        milestone("zaln-s", attributes)
+    }
+}
+
+fn occurrence(attributes: &mut BTreeMap<String, String>, key: &str) -> u32 {
+    attributes.remove(key).and_then(|value| value.parse().ok()).unwrap_or(1)
+}
+
+fn milestone(marker: &str, attributes: BTreeMap<String, String>) -> MilestoneObject {
+    MilestoneObject {
+        index: 0,
+        marker: marker.to_string(),
+        who: None,
+        sid: None,
+        eid: None,
+        additional_properties: attributes,
+        source: None,
+    }
+}
+
+/// Lists the alignments of every verse, in document order.
+///
+/// Verses without alignments are listed too, with no `alignments`. Target words aligned
+/// to the same source words in several places of a verse make a single `Alignment`.
+///
+/// # Example
+/// ```
+/// use hallomai::{deserialize, extract_alignments};
+///
+/// let usfm = "\\id 3JN\n\\c 1\n\\p\n\\v 1 \\zaln-s |x-strong=\"G4245\" x-lemma=\"πρεσβύτερος\" x-occurrence=\"1\" x-occurrences=\"1\" x-content=\"πρεσβύτερος\"\\*\\w The|x-occurrence=\"1\" x-occurrences=\"1\"\\w* \\w elder|x-occurrence=\"1\" x-occurrences=\"1\"\\w*\\zaln-e\\*,";
+/// let verses = extract_alignments(&deserialize(usfm.to_string(), "usfm").unwrap());
+/// let alignment = &verses[0].alignments[0];
+/// assert_eq!(alignment.sources[0].lemma.as_deref(), Some("πρεσβύτερος"));
+/// assert_eq!(alignment.targets.iter().map(|word| word.text.as_str()).collect::<Vec<_>>(), ["The", "elder"]);
+/// ```
+pub fn extract_alignments(root: &Root) -> Vec<VerseAlignments> {
+    let mut extractor = Extractor::default();
+    extractor.blocks(&root.content);
+    extractor.verses
+}
+
+#[derive(Default)]
+struct Extractor {
+    chapter: String,
+    verses: Vec<VerseAlignments>,
+    /// The source words of the `\zaln-s` milestones not yet ended.
+    open: Vec<SourceWord>,
+}
+
+impl Extractor {
+    fn blocks(&mut self, blocks: &[Content]) {
+        for block in blocks {
+            match block {
+                Content::Chapter(chapter) => self.chapter = chapter.number.clone(),
+                Content::Para(para) => self.in_para(para.content.iter().flatten()),
+                Content::Sidebar(sidebar) => self.blocks(sidebar.content.as_deref().unwrap_or_default()),
+                Content::Table(table) => {
+                    for cell in table.content.iter().flatten().flat_map(|row| row.content.iter().flatten()) {
+                        self.in_para(cell.content.iter().flatten());
+                    }
+                }
+                Content::Book(_) => {}
+            }
+        }
+    }
+
+    fn in_para<'a>(&mut self, content: impl Iterator<Item = &'a InParaObject>) {
+        for object in content {
+            match object {
+                InParaObject::Verse(verse) => self.verses.push(VerseAlignments {
+                    chapter: self.chapter.clone(),
+                    verse: verse.number.clone(),
+                    alignments: Vec::new(),
+                }),
+                InParaObject::Milestone(ms) if ms.marker == "zaln-s" => self.open.push(SourceWord::from_milestone(ms)),
+                InParaObject::Milestone(ms) if ms.marker == "zaln-e" => {
+                    self.open.pop();
+                }
+                InParaObject::Char(char) if char.marker == "w" => self.add_target(char),
+                InParaObject::Char(char) => self.in_para(char.content.iter().flatten()),
+                _ => {}
+            }
+        }
+    }
+
+    fn add_target(&mut self, word: &CharMarkerObject) {
+        let Some(verse) = self.verses.last_mut().filter(|_| !self.open.is_empty()) else {
+            return;
+        };
+        let mut attributes = word.additional_properties.clone();
+        let target = TargetWord {
+            text: text_of(word),
+            occurrence: occurrence(&mut attributes, "x-occurrence"),
+            occurrences: occurrence(&mut attributes, "x-occurrences"),
+        };
+        match verse.alignments.iter_mut().find(|alignment| alignment.sources == self.open) {
+            Some(alignment) => alignment.targets.push(target),
+            None => verse.alignments.push(Alignment {
+                sources: self.open.clone(),
+                targets: vec![target],
+            }),
+        }
+    }
+}
+
+fn text_of(char: &CharMarkerObject) -> String {
+    char.content.iter().flatten().filter_map(|object| match object {
+        InParaObject::String(text) => Some(text.as_str()),
+        _ => None,
+    }).collect()
+}
+
+/// Removes the alignments: `\zaln-s` and `\zaln-e` milestones are dropped and `\w` words
+/// that only carried their occurrence become plain text.
+pub fn strip_alignments(root: &mut Root) {
+    strip_blocks(&mut root.content);
+}
+
+fn strip_blocks(blocks: &mut [Content]) {
+    for block in blocks {
+        match block {
+            Content::Para(para) => strip_in_para(para.content.get_or_insert_with(Vec::new)),
+            Content::Sidebar(sidebar) => strip_blocks(sidebar.content.as_deref_mut().unwrap_or_default()),
+            Content::Table(table) => {
+                for cell in table.content.iter_mut().flatten().flat_map(|row| row.content.iter_mut().flatten()) {
+                    strip_in_para(cell.content.get_or_insert_with(Vec::new));
+                }
+            }
+            Content::Chapter(_) | Content::Book(_) => {}
+        }
+    }
+}
+
+fn strip_in_para(content: &mut Vec<InParaObject>) {
+    let mut stripped = Vec::with_capacity(content.len());
+    for object in content.drain(..) {
+        match object {
+            InParaObject::Milestone(ms) if ms.marker == "zaln-s" || ms.marker == "zaln-e" => {}
+            InParaObject::Char(mut char) => {
+                strip_in_para(char.content.get_or_insert_with(Vec::new));
+                if char.marker == "w" {
+                    char.additional_properties.remove("x-occurrence");
+                    char.additional_properties.remove("x-occurrences");
+                    let bare = char.additional_properties.is_empty() && char.link_id.is_none() && char.link_href.is_none() && char.srcloc.is_none() && char.strong.is_none();
+                    if bare {
+                        char.content.into_iter().flatten().for_each(|object| push_merged(&mut stripped, object));
+                        continue;
+                    }
+                }
+                stripped.push(InParaObject::Char(char));
+            }
+            object => push_merged(&mut stripped, object),
+        }
+    }
+    *content = stripped;
+}
+
+/// Pushes an object, appending text to the text before it.
+fn push_merged(content: &mut Vec<InParaObject>, object: InParaObject) {
+    match (content.last_mut(), object) {
+        (Some(InParaObject::String(last)), InParaObject::String(text)) => last.push_str(&text),
+        (_, object) => content.push(object),
+    }
+}
+
+/// Headings and introductions are not aligned.
+fn holds_verse_text(marker: &str) -> bool {
+    !utils_usfm::heading_markers().iter().any(|heading| heading == utils_usfm::split_level(marker).0)
+}
+
+fn word_regex() -> &'static Regex {
+    static WORD: OnceLock<Regex> = OnceLock::new();
+    WORD.get_or_init(|| Regex::new(r"[\p{L}\p{M}\p{N}]+").unwrap())
+}
+
+/// Aligns unaligned text: every word of the verses becomes a `\w` with its occurrence,
+/// and the words listed in `alignments` are wrapped in the `\zaln-s`/`\zaln-e`
+/// milestones of their source words.
+///
+/// Only the text directly in paragraphs is aligned, not the text of notes or character
+/// markers. Target words of an alignment that are not next to each other are wrapped
+/// separately. Run `strip_alignments` first on a document that is already aligned.
+pub fn apply_alignments(root: &mut Root, alignments: &[VerseAlignments]) {
+    let by_verse: HashMap<(&str, &str), &[Alignment]> = alignments.iter()
+        .map(|verse| ((verse.chapter.as_str(), verse.verse.as_str()), verse.alignments.as_slice()))
+        .collect();
+    let mut counter = Counter::default();
+    counter.blocks(&root.content);
+    let mut aligner = Aligner {
+        alignments: &by_verse,
+        occurrences: counter.occurrences,
+        chapter: String::new(),
+        verse: String::new(),
+        seen: HashMap::new(),
+    };
+    aligner.blocks(&mut root.content);
+}
+
+/// Counts how many times each word appears in each verse.
+#[derive(Default)]
+struct Counter {
+    chapter: String,
+    verse: String,
+    occurrences: HashMap<(String, String, String), u32>,
+}
+
+impl Counter {
+    fn blocks(&mut self, blocks: &[Content]) {
+        for block in blocks {
+            match block {
+                Content::Chapter(chapter) => self.chapter = chapter.number.clone(),
+                Content::Para(para) if holds_verse_text(&para.marker) => self.in_para(para.content.iter().flatten()),
+                Content::Sidebar(sidebar) => self.blocks(sidebar.content.as_deref().unwrap_or_default()),
+                Content::Table(table) => {
+                    for cell in table.content.iter().flatten().flat_map(|row| row.content.iter().flatten()) {
+                        self.in_para(cell.content.iter().flatten());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn in_para<'a>(&mut self, content: impl Iterator<Item = &'a InParaObject>) {
+        for object in content {
+            match object {
+                InParaObject::Verse(verse) => self.verse = verse.number.clone(),
+                InParaObject::String(text) if !self.verse.is_empty() => {
+                    for word in word_regex().find_iter(text) {
+                        *self.occurrences.entry((self.chapter.clone(), self.verse.clone(), word.as_str().to_string())).or_default() += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+struct Aligner<'a> {
+    alignments: &'a HashMap<(&'a str, &'a str), &'a [Alignment]>,
+    occurrences: HashMap<(String, String, String), u32>,
+    chapter: String,
+    verse: String,
+    /// How many times each word was met so far in the verse.
+    seen: HashMap<String, u32>,
+}
+
+impl<'a> Aligner<'a> {
+    fn blocks(&mut self, blocks: &mut [Content]) {
+        for block in blocks {
+            match block {
+                Content::Chapter(chapter) => self.chapter = chapter.number.clone(),
+                Content::Para(para) if holds_verse_text(&para.marker) => self.in_para(para.content.get_or_insert_with(Vec::new)),
+                Content::Sidebar(sidebar) => self.blocks(sidebar.content.as_deref_mut().unwrap_or_default()),
+                Content::Table(table) => {
+                    for cell in table.content.iter_mut().flatten().flat_map(|row| row.content.iter_mut().flatten()) {
+                        self.in_para(cell.content.get_or_insert_with(Vec::new));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Which alignment of the current verse a word belongs to.
+    fn alignment_of(&self, text: &str, occurrence: u32) -> Option<&'a Alignment> {
+        self.alignments.get(&(self.chapter.as_str(), self.verse.as_str()))?.iter()
+            .find(|alignment| alignment.targets.iter().any(|target| target.text == text && target.occurrence == occurrence))
+    }
+
+    fn in_para(&mut self, content: &mut Vec<InParaObject>) {
+        let mut aligned = Vec::with_capacity(content.len());
+        // The alignment whose milestones are open, and the text read since its last word
+        let mut open: Option<&'a Alignment> = None;
+        let mut pending = String::new();
+        for object in content.drain(..) {
+            let text = match object {
+                InParaObject::String(text) if !self.verse.is_empty() => text,
+                object => {
+                    close(&mut aligned, &mut open, &mut pending);
+                    if let InParaObject::Verse(verse) = &object {
+                        self.verse = verse.number.clone();
+                        self.seen.clear();
+                    }
+                    aligned.push(object);
+                    continue;
+                }
+            };
+            let mut end = 0;
+            for word in word_regex().find_iter(&text) {
+                pending.push_str(&text[end..word.start()]);
+                end = word.end();
+                let seen = self.seen.entry(word.as_str().to_string()).or_default();
+                *seen += 1;
+                let occurrence = *seen;
+                let occurrences = self.occurrences.get(&(self.chapter.clone(), self.verse.clone(), word.as_str().to_string())).copied().unwrap_or(occurrence);
+                let alignment = self.alignment_of(word.as_str(), occurrence);
+                if open.is_some_and(|open| !alignment.is_some_and(|alignment| std::ptr::eq(open, alignment))) {
+                    close(&mut aligned, &mut open, &mut pending);
+                }
+                push_merged(&mut aligned, InParaObject::String(std::mem::take(&mut pending)));
+                if open.is_none() {
+                    if let Some(alignment) = alignment {
+                        for source in &alignment.sources {
+                            aligned.push(InParaObject::Milestone(source.to_milestone()));
+                        }
+                        open = Some(alignment);
+                    }
+                }
+                aligned.push(InParaObject::Char(target_word(word.as_str(), occurrence, occurrences)));
+            }
+            pending.push_str(&text[end..]);
+        }
+        close(&mut aligned, &mut open, &mut pending);
+        aligned.retain(|object| !matches!(object, InParaObject::String(text) if text.is_empty()));
+        *content = aligned;
+    }
+}
+
+/// Ends the open alignment, then writes the text read since its last word.
+fn close(aligned: &mut Vec<InParaObject>, open: &mut Option<&Alignment>, pending: &mut String) {
+    if let Some(alignment) = open.take() {
+        for _ in &alignment.sources {
+            aligned.push(InParaObject::Milestone(milestone("zaln-e", BTreeMap::new())));
+        }
+    }
+    push_merged(aligned, InParaObject::String(std::mem::take(pending)));
+}
+
+fn target_word(text: &str, occurrence: u32, occurrences: u32) -> CharMarkerObject {
+    let mut attributes = BTreeMap::new();
+    attributes.insert("x-occurrence".to_string(), occurrence.to_string());
+    attributes.insert("x-occurrences".to_string(), occurrences.to_string());
+    CharMarkerObject {
+        index: 0,
+        marker: "w".to_string(),
+        content: Some(vec![InParaObject::String(text.to_string())]),
+        link_id: None,
+        link_href: None,
+        srcloc: None,
+        strong: None,
+        additional_properties: attributes,
+        source: None,
+    }
+}
@@ -1,80 +1,435 @@
-// #![allow(dead_code)]
-//
-// use std::collections::BTreeMap;
-// use crate::aosj_string::element::Element;
-// use crate::model_traits::AosjModel;
-// use crate::structs_model::model;
-//
-// impl AosjModel for model::Model {
-//     fn new() -> Self {
-//         todo!()
-//     }
-//
-//     fn push_element(&mut self, attributes: BTreeMap<String, String>, tag_name: String) {
-//         todo!()
-//     }
-//
-//     fn get_attributes(&self) -> String {
-//         todo!()
-//     }
-//
-//     fn add_root_metadata(&mut self, version_value: &String) {
-//         todo!()
-//     }
-//
-//     fn start_book(&mut self, attributes: String) {
-//         todo!()
-//     }
-//
-//     fn end_book(&mut self) {
-//         todo!()
-//     }
-//
-//     fn start_new_para(&mut self, attributes: String) {
-//         todo!()
-//     }
-//
-//     fn end_new_para(&mut self) {
-//         todo!()
-//     }
-//
-//     fn add_string_to_in_para(&mut self, txt: &mut Vec<String>) {
-//         todo!()
-//     }
-//
-//     fn add_chapter(&mut self, attributes: String) {
-//         todo!()
-//     }
-//
-//     fn add_verse_to_in_para(&mut self, attributes: String) {
-//         todo!()
-//     }
-//
-//     fn add_milestone(&mut self, attributes: String) {
-//         todo!()
-//     }
-//
-//     fn start_add_char_marker(&mut self, attributes: String) {
-//         todo!()
-//     }
-//
-//     fn end_add_char_marker(&mut self, txt: &mut Vec<String>) {
-//         todo!()
-//     }
-//
-//     fn start_add_note(&mut self, attributes: String) {
-//         todo!()
-//     }
-//
-//     fn end_add_note(&mut self, txt: &mut Vec<String>) {
-//         todo!()
-//     }
-//
-//     fn assemble_model(&self) -> String {
-//         todo!()
-//     }
-//
-//     fn parent_els(&mut self) -> &mut Vec<Element> {
-//         todo!()
-//     }
-// }
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use crate::aosj_string::element::Element;
+use crate::model_traits::AosjModel;
+use crate::structs_model::book_object::BookObject;
+use crate::structs_model::cell_object::CellObject;
+use crate::structs_model::chapter_object::ChapterObject;
+use crate::structs_model::char_marker_object::CharMarkerObject;
+use crate::structs_model::figure_object::FigureObject;
+use crate::structs_model::milestone_object::MilestoneObject;
+use crate::structs_model::model::{self, Content, InParaObject, OpenObject};
+use crate::structs_model::note_object::NoteObject;
+use crate::structs_model::para_marker_object::ParaMarkerObject;
+use crate::structs_model::ref_object::RefObject;
+use crate::structs_model::root::Root;
+use crate::structs_model::row_object::RowObject;
+use crate::structs_model::sidebar_object::SidebarObject;
+use crate::structs_model::table_object::TableObject;
+use crate::structs_model::usfm_source::UsfmSource;
+use crate::structs_model::verse_object::VerseObject;
+
+impl Default for model::Model {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl model::Model {
+    pub fn new() -> Self {
+        model::Model {
+            root: Root::new(""),
+            stack: Vec::new(),
+            parent_els: Vec::new(),
+            usfm_open: None,
+            usfm_close: None,
+        }
+    }
+
+    /// Returns the attributes of the current element, with USX `style` renamed
+    /// to `marker` and the `sid`/`vid`/`eid` ids left out.
+    fn current_attributes(&self) -> BTreeMap<String, String> {
+        self.parent_els.last().map(Element::usj_attribute_map).unwrap_or_default()
+    }
+
+    /// Returns how the next object was written in USFM, if it was kept.
+    fn take_source(&mut self) -> Option<UsfmSource> {
+        self.usfm_open.take().map(UsfmSource::new)
+    }
+
+    /// Gives the current object the USFM markup that closed it, if it was kept.
+    fn close_source(&mut self) {
+        if let Some(close) = self.usfm_close.take() {
+            if let Some(Some(source)) = self.stack.last_mut().and_then(OpenObject::source_mut) {
+                source.close = close;
+            }
+        }
+    }
+
+    /// Returns the USJ type of the current element, e.g. `table:row` or `row`.
+    fn current_type(&self) -> String {
+        self.parent_els.last().map_or_else(String::new, |element| element.tag_name.clone())
+    }
+
+    /// Appends an object to the innermost open object.
+    fn push_in_para(&mut self, object: InParaObject) {
+        match self.stack.last_mut() {
+            Some(OpenObject::Para(para)) => para.content.get_or_insert_with(Vec::new).push(object),
+            Some(OpenObject::Char(char)) => char.content.get_or_insert_with(Vec::new).push(object),
+            Some(OpenObject::Note(note)) => note.content.get_or_insert_with(Vec::new).push(object),
+            Some(OpenObject::Cell(cell)) => cell.content.get_or_insert_with(Vec::new).push(object),
+            Some(OpenObject::Book(book)) => {
+                if let InParaObject::String(text) = object {
+                    book.content.get_or_insert_with(Vec::new).push(text);
+                }
+            }
+            Some(OpenObject::Figure(figure)) => {
+                if let InParaObject::String(text) = object {
+                    figure.content.get_or_insert_with(Vec::new).push(text);
+                }
+            }
+            Some(OpenObject::Ref(r#ref)) => {
+                if let InParaObject::String(text) = object {
+                    r#ref.content.get_or_insert_with(Vec::new).push(text);
+                }
+            }
+            Some(OpenObject::Table(_)) | Some(OpenObject::Row(_)) | Some(OpenObject::Sidebar(_)) | None => {}
+        }
+    }
+
+    /// Appends a block-level object to the open sidebar, or to the root.
+    fn push_block(&mut self, content: Content) {
+        match self.stack.last_mut() {
+            Some(OpenObject::Sidebar(sidebar)) => sidebar.content.get_or_insert_with(Vec::new).push(content),
+            _ => self.root.content.push(content),
+        }
+    }
+
+    /// Closes the current block-level object and everything opened inside it,
+    /// leaving the sidebars it belongs to open.
+    fn close_block(&mut self) {
+        while self.stack.last().is_some_and(|object| !matches!(object, OpenObject::Sidebar(_))) {
+            self.close_last();
+        }
+    }
+
+    /// Closes the innermost open object and moves it into its parent.
+    fn close_last(&mut self) {
+        match self.stack.pop() {
+            Some(OpenObject::Book(book)) => self.push_block(Content::Book(book)),
+            Some(OpenObject::Para(para)) => self.push_block(Content::Para(para)),
+            Some(OpenObject::Char(char)) => self.push_in_para(InParaObject::Char(char)),
+            Some(OpenObject::Note(note)) => self.push_in_para(InParaObject::Note(note)),
+            Some(OpenObject::Figure(figure)) => self.push_in_para(InParaObject::Figure(figure)),
+            Some(OpenObject::Ref(r#ref)) => self.push_in_para(InParaObject::Ref(r#ref)),
+            Some(OpenObject::Table(table)) => self.push_block(Content::Table(table)),
+            Some(OpenObject::Sidebar(sidebar)) => self.push_block(Content::Sidebar(sidebar)),
+            Some(OpenObject::Row(row)) => {
+                if let Some(OpenObject::Table(table)) = self.stack.last_mut() {
+                    table.content.get_or_insert_with(Vec::new).push(row);
+                }
+            }
+            Some(OpenObject::Cell(cell)) => {
+                if let Some(OpenObject::Row(row)) = self.stack.last_mut() {
+                    row.content.get_or_insert_with(Vec::new).push(cell);
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// # We implement all the functions of the trait for the typed model
+impl AosjModel for model::Model {
+    type Output = Root;
+
+    fn push_element(&mut self, attributes: BTreeMap<String, String>, tag_name: String) {
+        self.parent_els.push(Element {
+            tag_name,
+            attributes,
+        });
+    }
+
+    fn get_attributes(&self) -> String {
+        match self.parent_els.last() {
+            Some(element) => element.usj_attributes(),
+            None => String::new(),
+        }
+    }
+
+    fn add_root_metadata(&mut self, version_value: &str) {
+        self.root.set_version(version_value);
+        self.root.source = self.take_source();
+        if let (Some(source), Some(close)) = (self.root.source.as_mut(), self.usfm_close.take()) {
+            source.close = close;
+        }
+    }
+
+    fn keep_usfm_open(&mut self, open: String) {
+        self.usfm_open = Some(open);
+    }
+
+    fn keep_usfm_close(&mut self, close: String) {
+        self.usfm_close = Some(close);
+    }
+
+    fn start_book(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.stack.push(OpenObject::Book(BookObject {
+            index: 0,
+            marker: attributes.remove("marker").unwrap_or_default(),
+            code: attributes.remove("code").unwrap_or_default(),
+            content: Some(Vec::new()),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn end_book(&mut self) {
+        self.close_source();
+        self.close_block();
+    }
+
+    fn start_new_para(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.stack.push(OpenObject::Para(ParaMarkerObject {
+            index: 0,
+            marker: attributes.remove("marker").unwrap_or_default(),
+            content: Some(Vec::new()),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn end_new_para(&mut self) {
+        self.close_source();
+        self.close_block();
+    }
+
+    fn add_string_to_in_para(&mut self, txt: &mut Vec<String>) {
+        if !txt.is_empty() {
+            self.push_in_para(InParaObject::String(txt.join("")));
+            txt.clear();
+        }
+    }
+
+    fn add_chapter(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.root.content.push(Content::Chapter(ChapterObject {
+            index: 0,
+            marker: attributes.remove("marker").unwrap_or_default(),
+            number: attributes.remove("number").unwrap_or_default(),
+            sid: None,
+            altnumber: attributes.remove("altnumber"),
+            pubnumber: attributes.remove("pubnumber"),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn add_verse_to_in_para(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.push_in_para(InParaObject::Verse(VerseObject {
+            index: 0,
+            marker: attributes.remove("marker").unwrap_or_default(),
+            number: attributes.remove("number").unwrap_or_default(),
+            sid: None,
+            altnumber: attributes.remove("altnumber"),
+            pubnumber: attributes.remove("pubnumber"),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn add_milestone(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.push_in_para(InParaObject::Milestone(MilestoneObject {
+            index: 0,
+            marker: attributes.remove("marker").unwrap_or_default(),
+            who: attributes.remove("who"),
+            sid: None,
+            eid: None,
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn start_add_char_marker(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.stack.push(OpenObject::Char(CharMarkerObject {
+            index: 0,
+            marker: attributes.remove("marker").unwrap_or_default(),
+            content: Some(Vec::new()),
+            link_id: attributes.remove("link-id"),
+            link_href: attributes.remove("link-href"),
+            srcloc: attributes.remove("srcloc"),
+            strong: attributes.remove("strong"),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn add_char_attributes(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        if let Some(OpenObject::Char(char)) = self.stack.last_mut() {
+            char.link_id = attributes.remove("link-id").or(char.link_id.take());
+            char.link_href = attributes.remove("link-href").or(char.link_href.take());
+            char.srcloc = attributes.remove("srcloc").or(char.srcloc.take());
+            char.strong = attributes.remove("strong").or(char.strong.take());
+            char.additional_properties.append(&mut attributes);
+        }
+    }
+
+    fn end_add_char_marker(&mut self, txt: &mut Vec<String>) {
+        self.add_string_to_in_para(txt);
+        self.close_source();
+        self.close_last();
+    }
+
+    fn start_add_note(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.stack.push(OpenObject::Note(NoteObject {
+            index: 0,
+            marker: attributes.remove("marker").unwrap_or_default(),
+            content: Some(Vec::new()),
+            caller: attributes.remove("caller"),
+            category: attributes.remove("category"),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn end_add_note(&mut self, txt: &mut Vec<String>) {
+        self.add_string_to_in_para(txt);
+        self.close_source();
+        self.close_last();
+    }
+
+    fn add_optbreak(&mut self, _attributes: String) {
+        self.push_in_para(InParaObject::OptBreak);
+    }
+
+    fn start_ref(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.stack.push(OpenObject::Ref(RefObject {
+            index: 0,
+            content: Some(Vec::new()),
+            loc: attributes.remove("loc"),
+            gen: attributes.remove("gen"),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn end_ref(&mut self, txt: &mut Vec<String>) {
+        self.add_string_to_in_para(txt);
+        self.close_source();
+        self.close_last();
+    }
+
+    fn start_figure(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.stack.push(OpenObject::Figure(FigureObject {
+            index: 0,
+            marker: attributes.remove("marker").unwrap_or_default(),
+            content: Some(Vec::new()),
+            alt: attributes.remove("alt"),
+            file: attributes.remove("file"),
+            size: attributes.remove("size"),
+            loc: attributes.remove("loc"),
+            copy: attributes.remove("copy"),
+            r#ref: attributes.remove("ref"),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn end_figure(&mut self, txt: &mut Vec<String>) {
+        self.add_string_to_in_para(txt);
+        self.close_source();
+        self.close_last();
+    }
+
+    fn start_table(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        self.stack.push(OpenObject::Table(TableObject {
+            index: 0,
+            marker: attributes.remove("marker"),
+            content: Some(Vec::new()),
+            additional_properties: attributes,
+        }));
+    }
+
+    fn end_table(&mut self) {
+        self.close_block();
+    }
+
+    fn start_row(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.stack.push(OpenObject::Row(RowObject {
+            index: 0,
+            r#type: self.current_type(),
+            marker: attributes.remove("marker").unwrap_or_default(),
+            content: Some(Vec::new()),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn end_row(&mut self) {
+        self.close_source();
+        self.close_last();
+    }
+
+    fn start_cell(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.stack.push(OpenObject::Cell(CellObject {
+            index: 0,
+            r#type: self.current_type(),
+            marker: attributes.remove("marker").unwrap_or_default(),
+            content: Some(Vec::new()),
+            align: attributes.remove("align"),
+            colspan: attributes.remove("colspan"),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn end_cell(&mut self, txt: &mut Vec<String>) {
+        self.add_string_to_in_para(txt);
+        self.close_source();
+        self.close_last();
+    }
+
+    fn start_sidebar(&mut self, _attributes: String) {
+        let mut attributes = self.current_attributes();
+        let source = self.take_source();
+        self.stack.push(OpenObject::Sidebar(SidebarObject {
+            index: 0,
+            marker: attributes.remove("marker").unwrap_or_default(),
+            content: Some(Vec::new()),
+            category: attributes.remove("category"),
+            additional_properties: attributes,
+            source,
+        }));
+    }
+
+    fn end_sidebar(&mut self) {
+        self.close_block();
+        self.close_source();
+        self.close_last();
+    }
+
+    fn assemble_model(mut self) -> Root {
+        while !self.stack.is_empty() {
+            self.close_last();
+        }
+        self.root
+    }
+
+    fn parent_els(&mut self) -> &mut Vec<Element> {
+        &mut self.parent_els
+    }
+}
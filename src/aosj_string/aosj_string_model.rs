@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use std::collections::BTreeMap;
-use crate::aosj_string::element::Element;
+use crate::aosj_string::element::{json_string, Element};
 use crate::model_traits::AosjModel;
 
 /// # Represents the model that contains all utility objects and their types.
@@ -21,13 +21,14 @@ pub struct AosjStringModel {
     /// Stack for notes.
     pub note_stack: Vec<String>,
     /// Stack of parent elements.
-    pub parent_els: Vec<Element>
+    pub parent_els: Vec<Element>,
+    /// Open sidebars, with the index in `paras` of their first paragraph.
+    pub sidebar_stack: Vec<(String, usize)>,
 }
 
-/// # We implement all the functions of the trait for the above model
-impl AosjModel for AosjStringModel {
+impl AosjStringModel {
     /// Creates a new instance of the model.
-    fn new() -> Self {
+    pub fn new() -> Self {
         AosjStringModel {
             root_attributes: BTreeMap::new(),
             paras: Vec::new(),
@@ -35,9 +36,15 @@ impl AosjModel for AosjStringModel {
             stack_in_paras: Vec::new(),
             char_marker_stack: Vec::new(),
             note_stack: Vec::new(),
-            parent_els: Vec::new()
+            parent_els: Vec::new(),
+            sidebar_stack: Vec::new(),
         }
     }
+}
+
+/// # We implement all the functions of the trait for the above model
+impl AosjModel for AosjStringModel {
+    type Output = String;
     /// Pushes an element to the parent elements stack.
     fn push_element(&mut self, attributes: BTreeMap<String,String>, tag_name: String) {
         self.parent_els.push(Element {
@@ -48,24 +55,15 @@ impl AosjModel for AosjStringModel {
 
     /// Retrieves a formatted string of attributes.
     fn get_attributes(&self) -> String {
-        let mut attributes = Vec::new();
-        if let Some(element) = self.parent_els.last() {
-            for (key, value) in &element.attributes {
-                if key != "sid" && key != "vid" && key != "eid" {
-                    let mut good_key = key.to_string();
-                    if key == "style" {
-                        good_key = "marker".to_string();
-                    }
-                    attributes.push(format!("\"{}\": \"{}\"", good_key, value));
-                }
-            }
+        match self.parent_els.last() {
+            Some(element) => element.usj_attributes(),
+            None => String::new(),
         }
-        attributes.join(", ")
     }
 
 
     /// Adds root metadata to the model.
-    fn add_root_metadata(&mut self, version_value: &String) {
+    fn add_root_metadata(&mut self, version_value: &str) {
         self.root_attributes.insert("version".to_string(), version_value.to_string());
     }
     /// Starts a new book with given attributes.
@@ -81,7 +79,7 @@ impl AosjModel for AosjStringModel {
             last_of_last.push_str(ultimate.as_str());
         }
         last.push(last_of_last);
-        last.push(format!("] }}").to_string());
+        last.push("] }".to_string());
         self.stack_in_paras.push(last);
 
         self.paras.push(self.stack_in_paras.pop().unwrap().join(" "));
@@ -100,7 +98,7 @@ impl AosjModel for AosjStringModel {
             last_of_last.push_str(ultimate.as_str());
         }
         last.push(last_of_last);
-        last.push(format!("] }}").to_string());
+        last.push("] }".to_string());
         self.stack_in_paras.push(last);
 
         self.paras.push(self.stack_in_paras.pop().unwrap().join(" "));
@@ -108,10 +106,10 @@ impl AosjModel for AosjStringModel {
     }
     /// Adds a string to the current paragraph content
     fn add_string_to_in_para(&mut self, txt: &mut Vec<String>) {
-        if txt.len() !=0 {
+        if !txt.is_empty() {
 
             let mut last = self.stack_in_paras.pop().unwrap();
-            last.push(format!("\"{}\",", txt.join("")).to_string());
+            last.push(format!("{},", json_string(&txt.join(""))));
             // println!("{:#?}", last);
             self.stack_in_paras.push(last);
 
@@ -142,11 +140,19 @@ impl AosjModel for AosjStringModel {
         current_char.push(format!("{{ \"type\": \"char\", {}, \"content\": [", attributes).to_string());
         self.stack_in_paras.push(current_char);
     }
+    /// Adds the attributes read after the start of the current character marker.
+    fn add_char_attributes(&mut self, attributes: String) {
+        if let Some(open) = self.stack_in_paras.last_mut().and_then(|current_char| current_char.first_mut()) {
+            if let Some(content) = open.rfind("\"content\": [") {
+                open.insert_str(content, &format!("{}, ", attributes));
+            }
+        }
+    }
     /// Ends the character marker addition.
     fn end_add_char_marker(&mut self, txt: &mut Vec<String>) {
         if !txt.is_empty() {
             let mut last = self.stack_in_paras.pop().unwrap();
-            last.push(format!("\"{}\"", txt.join("")));
+            last.push(json_string(&txt.join("")));
             self.stack_in_paras.push(last);
 
             txt.clear();
@@ -159,7 +165,7 @@ impl AosjModel for AosjStringModel {
             last_of_last.push_str(ultimate.as_str());
         }
         last.push(last_of_last);
-        last.push(format!("] }},").to_string());
+        last.push("] },".to_string());
         self.stack_in_paras.push(last);
 
 
@@ -178,7 +184,7 @@ impl AosjModel for AosjStringModel {
     fn end_add_note(&mut self, txt: &mut Vec<String>) {
         if !txt.is_empty() {
             let mut last = self.stack_in_paras.pop().unwrap();
-            last.push(format!("\"{}\"", txt.join("")));
+            last.push(json_string(&txt.join("")));
             self.stack_in_paras.push(last);
 
             txt.clear();
@@ -191,7 +197,7 @@ impl AosjModel for AosjStringModel {
             last_of_last.push_str(ultimate.as_str());
         }
         last.push(last_of_last);
-        last.push(format!("] }},").to_string());
+        last.push("] },".to_string());
         self.stack_in_paras.push(last);
 
 
@@ -203,13 +209,75 @@ impl AosjModel for AosjStringModel {
         }
     }
 
+    /// Starts a figure.
+    fn add_optbreak(&mut self, _attributes: String) {
+        let mut last = self.stack_in_paras.pop().unwrap();
+        last.push("{ \"type\": \"optbreak\" },".to_string());
+        self.stack_in_paras.push(last);
+    }
+    fn start_ref(&mut self, attributes: String) {
+        self.stack_in_paras.push(vec![format!("{{ \"type\": \"ref\", {}, \"content\": [", attributes)]);
+    }
+    fn end_ref(&mut self, txt: &mut Vec<String>) {
+        self.end_add_char_marker(txt);
+    }
+    fn start_figure(&mut self, attributes: String) {
+        self.stack_in_paras.push(vec![format!("{{ \"type\": \"figure\", {}, \"content\": [", attributes)]);
+    }
+    /// Ends the current figure, which is closed like a character marker.
+    fn end_figure(&mut self, txt: &mut Vec<String>) {
+        self.end_add_char_marker(txt);
+    }
+    /// Starts a table.
+    fn start_table(&mut self, attributes: String) {
+        let separator = if attributes.is_empty() { "" } else { ", " };
+        self.stack_in_paras.push(vec![format!("{{ \"type\": \"table\"{}{}, \"content\": [", separator, attributes)]);
+    }
+    /// Ends the current table.
+    fn end_table(&mut self) {
+        self.end_new_para();
+    }
+    /// Starts a row of the current table.
+    fn start_row(&mut self, attributes: String) {
+        let tag_name = self.parent_els.last().map_or("table:row", |element| element.tag_name.as_str());
+        self.stack_in_paras.push(vec![format!("{{ \"type\": {}, {}, \"content\": [", json_string(tag_name), attributes)]);
+    }
+    /// Ends the current row.
+    fn end_row(&mut self) {
+        self.end_add_note(&mut Vec::new());
+    }
+    /// Starts a cell of the current row.
+    fn start_cell(&mut self, attributes: String) {
+        let tag_name = self.parent_els.last().map_or("table:cell", |element| element.tag_name.as_str());
+        self.stack_in_paras.push(vec![format!("{{ \"type\": {}, {}, \"content\": [", json_string(tag_name), attributes)]);
+    }
+    /// Ends the current cell.
+    fn end_cell(&mut self, txt: &mut Vec<String>) {
+        self.end_add_note(txt);
+    }
+
+    /// Starts a sidebar.
+    fn start_sidebar(&mut self, attributes: String) {
+        self.sidebar_stack.push((format!("{{ \"type\": \"sidebar\", {}, \"content\": [", attributes), self.paras.len()));
+    }
+    /// Ends the current sidebar, moving the paragraphs read since its start inside it.
+    fn end_sidebar(&mut self) {
+        if let Some((start, first_para)) = self.sidebar_stack.pop() {
+            let content = self.paras.split_off(first_para).join(",");
+            self.paras.push(format!("{}{}] }}", start, content));
+        }
+    }
+
 
     /// Assembles the model into a JSON string.
-    fn assemble_model(&self) -> String {
+    fn assemble_model(mut self) -> String {
+        while !self.sidebar_stack.is_empty() {
+            self.end_sidebar();
+        }
         let mut model = "".to_string();
         model += "{";
         let version = match self.root_attributes.get("version") {Some(v) => v.to_string(), None => {"".to_string()}};
-        model += &format!(" \"version\": \"{}\",", version);
+        model += &format!(" \"version\": {},", json_string(&version));
         model += &format!(" \"content\": [{}]", self.paras.join(","));
 
         model += "}";
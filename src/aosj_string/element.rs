@@ -12,6 +12,48 @@ pub struct Element {
     pub attributes: BTreeMap<String, String>,
 }
 
+impl Element {
+    /// Formats the attributes as the body of a USJ object, e.g. `"marker": "p"`.
+    ///
+    /// USX `style` becomes `marker`, and the `sid`, `vid` and `eid` ids are left out
+    /// since they are recomputed when the document is assembled.
+    pub fn usj_attributes(&self) -> String {
+        let mut attributes = Vec::new();
+        for (key, value) in &self.attributes {
+            if key != "sid" && key != "vid" && key != "eid" {
+                let mut good_key = key.to_string();
+                if key == "style" {
+                    good_key = "marker".to_string();
+                }
+                attributes.push(format!("{}: {}", json_string(&good_key), json_string(value)));
+            }
+        }
+        attributes.join(", ")
+    }
+
+    /// The attributes by their USJ name, left out and renamed as by `usj_attributes`.
+    pub fn usj_attribute_map(&self) -> BTreeMap<String, String> {
+        let mut attributes = BTreeMap::new();
+        for (key, value) in &self.attributes {
+            match key.as_str() {
+                "sid" | "vid" | "eid" => {}
+                "style" => {
+                    attributes.insert("marker".to_string(), value.to_string());
+                }
+                _ => {
+                    attributes.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+        attributes
+    }
+}
+
+/// Quotes and escapes a string so it can be embedded in JSON.
+pub fn json_string(value: &str) -> String {
+    serde_json::to_string(value).expect("A string is always valid JSON")
+}
+
 impl Display for Element {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "ELEMENT<{}>, {:#?}", self.tag_name, self.attributes)
@@ -0,0 +1,197 @@
+use crate::error::{HallomaiError, Warning};
+use crate::options::{ParseOptions, UsfmLayout};
+use crate::structs_model::root::Root;
+use crate::{deserialize_with_options, format_usfm, serialize};
+
+/// # A book to convert, as read from a file or from an entry of an archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookSource {
+    /// Where the book was read from, e.g. `release/USX_1/GEN.usx`.
+    pub name: String,
+    /// One of `usfm`, `usx` or `usj`.
+    pub format: String,
+    /// The text of the book.
+    pub content: String,
+}
+
+/// # The conversion of one book of a set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookResult {
+    /// The `name` of the `BookSource`.
+    pub name: String,
+    /// The code of the `\id` of the book, when it could be read.
+    pub book_code: Option<String>,
+    /// The converted book, or why it could not be converted.
+    pub output: Result<String, HallomaiError>,
+    /// What was read but looked wrong, as returned by `deserialize_with_options`.
+    pub warnings: Vec<Warning>,
+}
+
+/// Converts every book of a set, in order; a book that fails does not stop the others.
+///
+/// With the `parallel` feature, on native targets, the books are converted in parallel on the
+/// rayon thread pool, one thread per CPU unless the global pool was built otherwise.
+pub fn transform_books(books: Vec<BookSource>, output_file_format: &str, options: &ParseOptions) -> Vec<BookResult> {
+    convert_books(books, options, |root| serialize(root, output_file_format))
+}
+
+/// Converts every book of a set to canonical USFM laid out as asked by `layout`, as
+/// `transform_books` does for the other formats.
+pub fn format_usfm_books(books: Vec<BookSource>, layout: &UsfmLayout, options: &ParseOptions) -> Vec<BookResult> {
+    convert_books(books, options, |root| Ok(format_usfm(root, layout)))
+}
+
+/// Joins books converted to plain text into a single text, in their order and a blank line
+/// apart, leaving out those that failed. The other formats hold a single book.
+pub fn combine_text(results: &[BookResult]) -> String {
+    let texts: Vec<&str> = results.iter().filter_map(|result| result.output.as_deref().ok()).collect();
+    texts.join("\n")
+}
+
+fn convert_books<W>(books: Vec<BookSource>, options: &ParseOptions, write: W) -> Vec<BookResult>
+where
+    W: Fn(&Root) -> Result<String, HallomaiError> + Sync,
+{
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    {
+        use rayon::prelude::*;
+        books.into_par_iter().map(|book| convert_book(book, options, &write)).collect()
+    }
+    #[cfg(not(all(feature = "parallel", not(target_arch = "wasm32"))))]
+    {
+        books.into_iter().map(|book| convert_book(book, options, &write)).collect()
+    }
+}
+
+fn convert_book(book: BookSource, options: &ParseOptions, write: impl Fn(&Root) -> Result<String, HallomaiError>) -> BookResult {
+    match deserialize_with_options(book.content, &book.format, options) {
+        Ok((root, warnings)) => BookResult {
+            name: book.name,
+            book_code: root.book_code().map(str::to_string),
+            output: write(&root),
+            warnings,
+        },
+        Err(err) => BookResult {
+            name: book.name,
+            book_code: None,
+            output: Err(err),
+            warnings: Vec::new(),
+        },
+    }
+}
+
+/// Returns the format of a book from the extension of its file name: `.usfm` or `.sfm` for
+/// USFM, `.usx` or `.xml` for USX, `.usj` or `.json` for USJ, whatever their case.
+pub fn format_from_extension(name: &str) -> Option<&'static str> {
+    let extension = name.rsplit_once('.')?.1.to_ascii_lowercase();
+    match extension.as_str() {
+        "usfm" | "sfm" => Some("usfm"),
+        "usx" | "xml" => Some("usx"),
+        "usj" | "json" => Some("usj"),
+        _ => None,
+    }
+}
+
+/// Guesses the format of a document from its first character: `\` for USFM, `<` for USX
+/// and `{` for USJ, after any byte order mark and whitespace.
+pub fn detect_format(content: &str) -> Option<&'static str> {
+    match content.trim_start_matches('\u{feff}').trim_start().chars().next()? {
+        '\\' => Some("usfm"),
+        '<' => Some("usx"),
+        '{' => Some("usj"),
+        _ => None,
+    }
+}
+
+/// Whether a file met while listing a directory or an archive is a book: its extension is
+/// that of a book and, for the `.xml` and `.json` files that also hold metadata in bundles,
+/// its content is USX or USJ.
+fn book_format(name: &str, content: &str) -> Option<&'static str> {
+    let format = format_from_extension(name)?;
+    let extension = name.rsplit_once('.')?.1.to_ascii_lowercase();
+    let is_book = match extension.as_str() {
+        "xml" => content.contains("<usx"),
+        "json" => content.contains("\"USJ\""),
+        _ => true,
+    };
+    is_book.then_some(format)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::files::{read_book_files, read_books};
+
+#[cfg(not(target_arch = "wasm32"))]
+mod files {
+    use std::fs;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use super::{book_format, format_from_extension, BookSource};
+    use crate::error::HallomaiError;
+
+    /// Reads the books of a directory and of its subdirectories, of a `.zip` archive, or a
+    /// single book file. In a directory or an archive, files that are not books are skipped;
+    /// the books come sorted by name.
+    pub fn read_books(path: &Path) -> Result<Vec<BookSource>, HallomaiError> {
+        if path.is_dir() {
+            let mut books = Vec::new();
+            read_directory(path, path, &mut books)?;
+            books.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(books)
+        } else if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("zip")) {
+            read_archive(path)
+        } else {
+            read_book_files(&[path.to_path_buf()])
+        }
+    }
+
+    /// Reads a list of book files, in the given order. Their format comes from their extension.
+    pub fn read_book_files(paths: &[PathBuf]) -> Result<Vec<BookSource>, HallomaiError> {
+        paths.iter().map(|path| {
+            let name = path.display().to_string();
+            let format = format_from_extension(&name)
+                .ok_or_else(|| HallomaiError::io(path, "not a .usfm, .sfm, .usx, .xml, .usj or .json file"))?;
+            let content = fs::read_to_string(path).map_err(|err| HallomaiError::io(path, err))?;
+            Ok(BookSource { name, format: format.to_string(), content })
+        }).collect()
+    }
+
+    fn read_directory(root: &Path, directory: &Path, books: &mut Vec<BookSource>) -> Result<(), HallomaiError> {
+        let entries = fs::read_dir(directory).map_err(|err| HallomaiError::io(directory, err))?;
+        for entry in entries {
+            let path = entry.map_err(|err| HallomaiError::io(directory, err))?.path();
+            if path.is_dir() {
+                read_directory(root, &path, books)?;
+                continue;
+            }
+            let name = path.strip_prefix(root).unwrap_or(&path).display().to_string();
+            if format_from_extension(&name).is_none() {
+                continue;
+            }
+            let content = fs::read_to_string(&path).map_err(|err| HallomaiError::io(&path, err))?;
+            if let Some(format) = book_format(&name, &content) {
+                books.push(BookSource { name, format: format.to_string(), content });
+            }
+        }
+        Ok(())
+    }
+
+    fn read_archive(path: &Path) -> Result<Vec<BookSource>, HallomaiError> {
+        let file = fs::File::open(path).map_err(|err| HallomaiError::io(path, err))?;
+        let mut archive = zip::ZipArchive::new(file).map_err(|err| HallomaiError::io(path, err))?;
+        let mut books = Vec::new();
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).map_err(|err| HallomaiError::io(path, err))?;
+            let name = entry.name().to_string();
+            if entry.is_dir() || format_from_extension(&name).is_none() {
+                continue;
+            }
+            let mut content = String::new();
+            entry.read_to_string(&mut content).map_err(|err| HallomaiError::io(&path.join(&name), err))?;
+            if let Some(format) = book_format(&name, &content) {
+                books.push(BookSource { name, format: format.to_string(), content });
+            }
+        }
+        books.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(books)
+    }
+}
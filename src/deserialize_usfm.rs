@@ -1,14 +1,12 @@
 use std::collections::BTreeMap;
-use regex::Regex;
+use std::iter::Peekable;
+use std::vec::IntoIter;
 use crate::utils_usfm;
+use crate::lexer_usfm::Lexer;
+use crate::error::{HallomaiError, SourceLocation, Warning};
 use crate::model_traits::AosjModel;
-
-
-/// # Reads the USFM file and reconstructs it into an AosjModel.
-///
-/// This function processes a USFM file, with all the structs defined.
-/// It reconstructs the file into a model that implements the `AosjModel`
-/// trait.
+use crate::options::{ParseOptions, UnknownMarkerPolicy};
+use crate::structs_model::VerseRange;
 
 
 #[derive(Debug, PartialEq, Clone)]
@@ -24,67 +22,194 @@ enum Token {
     Break(Printable),
 }
 
-#[derive(Debug, PartialEq, Clone)]
-struct Printable {
-    subclass: String,
-    print_value: String,
+impl Token {
+    fn span(&self) -> Span {
+        match self {
+            Token::Chapter(c) => c.span,
+            Token::Verses(v) => v.span,
+            Token::Attribute(a) => a.span,
+            Token::PubChapter(cp) => cp.span,
+            Token::Milestone(m) => m.span,
+            Token::Tag(t) => t.span,
+            Token::Printable(p) | Token::Bad(p) | Token::Break(p) => p.span,
+        }
+    }
+
+    /// Whether the token is markup that a line break before it only lays out; before
+    /// markup within the text, like `\w` or `\zaln-s`, a line break is a space.
+    fn is_marker(&self) -> bool {
+        match self {
+            Token::Chapter(_) | Token::Verses(_) | Token::PubChapter(_) => true,
+            Token::Tag(t) => !matches!(t.tag_type.as_str(), "char" | "note" | "figure" | "ref"),
+            _ => false,
+        }
+    }
+}
+
+/// The bytes `start.offset..end` of the input a token was read from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Span {
+    start: SourceLocation,
+    end: usize,
 }
 
-fn make_printable(subclass: &str, matched_bits: Vec<&str>) -> Printable {
-    let mut print_value = matched_bits[0].replace("~", "\u{00a0}");
-    let values: Vec<String> = vec!["\"".to_string(), "\\".to_string()];
-    if values.contains(&print_value) {
-        let value = format!("\\{}", &print_value);
-        print_value = print_value.replace(&print_value, value.as_str());
+/// Turns byte offsets into line/column positions, scanning the input only once.
+struct LineTracker {
+    location: SourceLocation,
+}
+
+impl LineTracker {
+    fn new() -> Self {
+        LineTracker {
+            location: SourceLocation { line: 1, column: 1, offset: 0 },
+        }
     }
-    Printable {
-        subclass: subclass.to_string(),
-        print_value,
+
+    fn advance_to(&mut self, content: &str, offset: usize) -> SourceLocation {
+        for c in content[self.location.offset..offset].chars() {
+            if c == '\n' {
+                self.location.line += 1;
+                self.location.column = 1;
+            } else {
+                self.location.column += 1;
+            }
+        }
+        self.location.offset = offset;
+        self.location
+    }
+
+    fn span(&mut self, content: &str, start: usize, end: usize) -> Span {
+        Span {
+            start: self.advance_to(content, start),
+            end,
+        }
     }
 }
 
-fn make_break(subclass: &str, matched_bits: Vec<&str>) -> Printable {
-    Printable {
-        subclass: subclass.to_string(),
-        print_value: matched_bits[0].replace("~", "\u{00a0}"),
+/// # The markup kept by `ParseOptions::lossless`, until the object it belongs to is made.
+///
+/// Line breaks before a marker are layout: they close the paragraph the marker
+/// ends, or open the object it starts. Everything else is kept as read.
+struct Trivia<'a> {
+    content: &'a str,
+    lossless: bool,
+    /// Line breaks read before a marker.
+    layout: String,
+    /// The opening markup of an object made when it ends, such as `\w` or `\fig`.
+    deferred: String,
+    /// Where the attributes of the current marker start.
+    attributes_start: Option<usize>,
+}
+
+impl<'a> Trivia<'a> {
+    fn new(content: &'a str, options: &ParseOptions) -> Self {
+        Trivia {
+            content,
+            lossless: options.lossless,
+            layout: String::new(),
+            deferred: String::new(),
+            attributes_start: None,
+        }
+    }
+
+    /// The pending line breaks, followed by the input from `start` to `end`.
+    fn markup(&mut self, start: usize, end: usize) -> String {
+        self.attributes_start = None;
+        std::mem::take(&mut self.layout) + &self.content[start..end]
+    }
+
+    /// Gives the object opened now the markup from `start` to `end`.
+    fn open<T: AosjModel>(&mut self, model: &mut T, start: usize, end: usize) {
+        if self.lossless {
+            let open = self.markup(start, end);
+            model.keep_usfm_open(open);
+        }
+    }
+
+    /// Keeps the markup from `start` to `end` for an object made when it ends.
+    fn defer(&mut self, start: usize, end: usize) {
+        if self.lossless {
+            self.deferred = self.markup(start, end);
+        }
+    }
+
+    /// Gives the object made now the deferred markup, followed by `extra`.
+    fn open_deferred<T: AosjModel>(&mut self, model: &mut T, extra: &str) {
+        if self.lossless {
+            model.keep_usfm_open(std::mem::take(&mut self.deferred) + extra);
+        }
+    }
+
+    /// Gives the object closed by the marker at `span` its attributes and closing marker.
+    fn close<T: AosjModel>(&mut self, model: &mut T, span: Span) {
+        if self.lossless {
+            let start = self.attributes_start.unwrap_or(span.start.offset);
+            let close = self.markup(start, span.end);
+            model.keep_usfm_close(close);
+        }
+    }
+
+    /// Gives the paragraph closed now the line breaks before the marker closing it.
+    fn close_with_layout<T: AosjModel>(&mut self, model: &mut T) {
+        if self.lossless {
+            model.keep_usfm_close(std::mem::take(&mut self.layout));
+        }
+    }
+
+    /// Where the markup read along with a token ends: the start of the next token.
+    fn end_of(&self, tokens: &mut Peekable<IntoIter<Token>>) -> usize {
+        tokens.peek().map_or(self.content.len(), |next| next.span().start.offset)
     }
 }
 
-fn make_bad(subclass: &str, matched_bits: Vec<&str>) -> Printable {
+#[derive(Debug, PartialEq, Clone)]
+struct Printable {
+    subclass: String,
+    span: Span,
+    print_value: String,
+}
+
+/// Text written as it was read: words, spaces, punctuation, line ends, and markup that is not a marker.
+fn make_printable(subclass: &str, parts: &[&str], span: Span) -> Printable {
     Printable {
         subclass: subclass.to_string(),
-        print_value: matched_bits[0].replace("~", "\u{00a0}"),
+        span,
+        print_value: parts[0].replace("~", "\u{00a0}"),
     }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 struct Chapter {
     subclass: String,
+    span: Span,
     number_string: String,
     number: i32,
 }
 
-fn make_chapter(subclass: &str, matched_bits: Vec<&str>) -> Chapter {
-    let number_string = matched_bits[2].to_string();
+fn make_chapter(subclass: &str, parts: &[&str], span: Span) -> Chapter {
+    let number_string = parts[1].to_string();
 
     Chapter {
         subclass: subclass.to_string(),
+        span,
         number_string: number_string.clone(),
-        number: number_string.parse::<i32>().unwrap(),
+        number: number_string.parse::<i32>().unwrap_or_default(),
     }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 struct Verses {
     subclass: String,
+    span: Span,
     number_string: String,
 }
 
-fn make_verses(subclass: &str, matched_bits: Vec<&str>) -> Verses {
-    let number_string = matched_bits[2].to_string();
+fn make_verses(subclass: &str, parts: &[&str], span: Span) -> Verses {
+    let number_string = parts[1].to_string();
 
     Verses {
         subclass: subclass.to_string(),
+        span,
         number_string: number_string.clone(),
     }
 }
@@ -92,37 +217,63 @@ fn make_verses(subclass: &str, matched_bits: Vec<&str>) -> Verses {
 #[derive(Debug, PartialEq, Clone)]
 struct Attribute {
     subclass: String,
+    span: Span,
     key: String,
     value_string: String,
     values: Vec<String>,
 }
 
-fn make_attribute(subclass: &str, matched_bits: Vec<&str>) -> Attribute {
+fn make_attribute(subclass: &str, parts: &[&str], span: Span) -> Attribute {
     let (key, value_string): (String, String);
     if subclass == "defaultAttribute" {
-        (key, value_string) = ("default".to_string(), matched_bits[2].trim().replace("/", "+"));
+        (key, value_string) = ("default".to_string(), parts[1].trim().to_string());
     } else {
-        (key, value_string) = (matched_bits[2].to_string(), matched_bits[3].trim().replace("/", "+"));
+        (key, value_string) = (parts[1].to_string(), unescape_attribute(parts[2].trim()));
     }
     let values: Vec<String> = value_string.split(',').map(|v| v.trim().to_string()).collect();
     Attribute {
         subclass: subclass.to_string(),
+        span,
         key,
         value_string,
         values,
     }
 }
 
+/// Reads the `\"` and `\\` escapes of a quoted attribute value.
+fn unescape_attribute(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Gives the default attribute of `\marker text|value\marker*` the name it has for `marker`.
+fn name_default_attribute(attributes: &mut BTreeMap<String, String>, marker: &str) {
+    if let Some(name) = utils_usfm::default_attribute(marker) {
+        if let Some(value) = attributes.remove("default") {
+            attributes.insert(name.to_string(), value);
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 struct PubChapter {
     subclass: String,
+    span: Span,
     number_string: String,
 }
 
-fn make_pub_chapter(subclass: &str, matched_bits: Vec<&str>) -> PubChapter {
-    let number_string = matched_bits[2].to_string();
+fn make_pub_chapter(subclass: &str, parts: &[&str], span: Span) -> PubChapter {
+    let number_string = parts[1].trim().to_string();
     PubChapter {
         subclass: subclass.to_string(),
+        span,
         number_string,
     }
 }
@@ -130,27 +281,29 @@ fn make_pub_chapter(subclass: &str, matched_bits: Vec<&str>) -> PubChapter {
 #[derive(Debug, PartialEq, Clone)]
 struct Milestone {
     subclass: String,
+    span: Span,
     s_or_e: Option<String>,
     tag_name: Option<String>,
     attributes: Vec<(String, String)>,
 }
 
-fn make_milestone(subclass: &str, matched_bits: Vec<&str>) -> Milestone {
+fn make_milestone(subclass: &str, parts: &[&str], span: Span) -> Milestone {
     let mut ret = Milestone {
         subclass: subclass.to_string(),
+        span,
         tag_name: None,
         s_or_e: None,
         attributes: Vec::new(),
     };
 
     if subclass != "endMilestoneMarker" {
-        if matched_bits.len() > 2 {
-            ret.tag_name = Some(matched_bits[2].to_string());
+        if parts.len() > 1 {
+            ret.tag_name = Some(parts[1].to_string());
         }
 
         if subclass == "emptyMilestone" {
-            if matched_bits.len() > 1 {
-                let attributes_str = matched_bits[1];
+            if !parts.is_empty() {
+                let attributes_str = parts[0];
                 ret.attributes = attributes_str
                     .split('|')
                     .skip(1)
@@ -159,9 +312,7 @@ fn make_milestone(subclass: &str, matched_bits: Vec<&str>) -> Milestone {
                         if parts.len() == 2 {
                             let key = parts[0].to_string();
                             let value = parts[1]
-                                .replace('"', "")
-                                .replace('\\', "")
-                                .replace('*', "");
+                                .replace(['"', '\\', '*'], "");
                             Some((key, value))
                         } else {
                             None
@@ -170,8 +321,8 @@ fn make_milestone(subclass: &str, matched_bits: Vec<&str>) -> Milestone {
                     .collect();
             }
         } else {
-            if matched_bits.len() > 3 {
-                ret.s_or_e = Some(matched_bits[3].to_string());
+            if parts.len() > 2 {
+                ret.s_or_e = Some(parts[2].to_string());
             }
         }
     }
@@ -182,126 +333,165 @@ fn make_milestone(subclass: &str, matched_bits: Vec<&str>) -> Milestone {
 #[derive(Debug, PartialEq, Clone)]
 struct Tag {
     subclass: String,
+    span: Span,
     tag_name: String,
     is_nested: bool,
     tag_level: i32,
     full_tag_name: String,
     tag_type: String,
+    colspan: Option<String>,
 }
 
-fn make_tag(subclass: &str, matched_bits: Vec<&str>) -> Tag {
-    let mut tag_name = matched_bits[2].to_string();
-    let is_nested = tag_name.starts_with('+');
-    let tag_level: i32;
-    let full_tag_name: String;
-    if is_nested {
-        tag_name = tag_name[1..].to_string();
+/// Guesses the type of a marker that is not in `utils_usfm`: a marker closed by `\marker*`
+/// before the next line starting with a marker is a `char`, anything else is a `para`.
+fn guess_tag_type(subclass: &str, tag_name: &str, is_nested: bool, content: &str, span: Span) -> String {
+    if subclass == "endTag" || is_nested {
+        return "char".to_string();
     }
-    if matched_bits[3] != "" {
-        tag_level = matched_bits[3].parse::<i32>().unwrap();
+    let rest = &content[span.end..];
+    let paragraph = &rest[..rest.find("\n\\").unwrap_or(rest.len())];
+    if paragraph.contains(&format!("\\{}*", tag_name)) {
+        "char".to_string()
     } else {
-        tag_level = 1;
+        "para".to_string()
     }
-    if matched_bits[3] == "1" {
-        full_tag_name = tag_name.clone();
-    } else {
-        full_tag_name = format!("{}{}", tag_name, matched_bits[3]);
+}
+
+/// The markers of `utils_usfm` by type, gathered once for all the tags of a file.
+struct MarkerTypes {
+    char_markers: Vec<String>,
+    para_markers: Vec<String>,
+    note_markers: Vec<String>,
+    cell_markers: Vec<String>,
+}
+
+impl MarkerTypes {
+    fn new() -> Self {
+        MarkerTypes {
+            char_markers: utils_usfm::char_markers(),
+            para_markers: utils_usfm::para_markers(),
+            note_markers: utils_usfm::note_markers(),
+            cell_markers: utils_usfm::cell_markers(),
+        }
     }
-    let char_marker = utils_usfm::char_markers();
-    let para_marker = utils_usfm::para_markers();
-    let note_markers = utils_usfm::note_markers();
+}
+
+fn make_tag(subclass: &str, parts: &[&str], span: Span, content: &str, markers: &MarkerTypes, options: &ParseOptions, warnings: &mut Vec<Warning>) -> Result<Tag, HallomaiError> {
+    let mut tag_name = parts[1].to_string();
+    let is_nested = tag_name.starts_with('+');
+    if is_nested {
+        tag_name = tag_name[1..].to_string();
+    }
+    // `\tc1-2` is the cell of column 1 spanning 2 columns
+    let level = parts[2].split('-').next().unwrap_or("");
+    let tag_level: i32 = level.parse::<i32>().unwrap_or(1);
+    let full_tag_name: String = format!("{}{}", tag_name, level);
+    let colspan = Some(parts[3]).filter(|span| !span.is_empty()).map(str::to_string);
 
     let tag_type: String;
-    if char_marker.contains(&tag_name) {
+    if markers.char_markers.contains(&tag_name) {
         tag_type = "char".to_string();
-    } else if para_marker.contains(&tag_name) {
+    } else if markers.para_markers.contains(&tag_name) {
         tag_type = "para".to_string();
     } else if tag_name == "id" {
         tag_type = "book".to_string();
-    } else if note_markers.contains(&tag_name) {
+    } else if tag_name == "fig" {
+        tag_type = "figure".to_string();
+    } else if tag_name == "ref" {
+        tag_type = "ref".to_string();
+    } else if tag_name == "esb" {
+        tag_type = "sidebar".to_string();
+    } else if tag_name == "esbe" {
+        tag_type = "sidebarEnd".to_string();
+    } else if tag_name == "tr" {
+        tag_type = "row".to_string();
+    } else if markers.cell_markers.contains(&tag_name) {
+        tag_type = "cell".to_string();
+    } else if markers.note_markers.contains(&tag_name) {
         tag_type = "note".to_string();
+    } else if tag_name.starts_with('z') || options.unknown_markers == UnknownMarkerPolicy::Lenient {
+        tag_type = guess_tag_type(subclass, &tag_name, is_nested, content, span);
+        // A closing marker is reported along with its opening one
+        if subclass == "startTag" {
+            let kind = if tag_name.starts_with('z') { "User-defined" } else { "Unknown" };
+            warnings.push(Warning::new(format!("{} marker \\{} read as {}", kind, parts[1], tag_type), span.start));
+        }
     } else {
-        // TODO : make sure to handle all the tags in the future
-        // tag_type = "para".to_string();
-        panic!("Tag not in the specification : {}", tag_name);
+        return Err(HallomaiError::UnknownMarker {
+            marker: parts[1].to_string(),
+            location: span.start,
+        });
     }
 
-    Tag {
+    if !level.is_empty() && subclass == "startTag" && tag_type != "cell" {
+        match utils_usfm::max_level(&tag_name) {
+            None => warnings.push(Warning::new(format!("Marker \\{} takes no level, but was read as \\{}", tag_name, full_tag_name), span.start)),
+            Some(max) if tag_level > max as i32 => warnings.push(Warning::new(format!("Marker \\{} only goes up to level {}", full_tag_name, max), span.start)),
+            _ => {}
+        }
+    }
+
+    Ok(Tag {
         subclass: subclass.to_string(),
+        span,
         tag_name,
         is_nested,
         tag_level,
         full_tag_name,
         tag_type,
-    }
+        colspan,
+    })
 }
 
 
-pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
-
-    let mut regexes: Vec<(&str, &str, Regex)> = vec![];
-    regexes.push(("chapter", r"([\r\n]*\\c[ \t]+(\d+)[ \t\r\n]*)", Regex::new(r"([\r\n]*\\c[ \t]+(\d+)[ \t\r\n]*)").unwrap()));
-    regexes.push(("pubchapter", r"([\r\n]*\\cp[ \t]+([^\r\n]+)[ \t\r\n]*)", Regex::new(r"([\r\n]*\\cp[ \t]+([^\r\n]+)[ \t\r\n]*)").unwrap()));
-    regexes.push(("verses", r"(\\v[ \t]+([\d\-]+)[ \t\r\n]*)", Regex::new(r"(\\v[ \t]+([\d\-]+)[ \t\r\n]*)").unwrap()));
-    regexes.push(("attribute", r#"([ \t]*\|?[ \t]*([A-Za-z0-9\-]+)="([^"]*)"[ \t]?)"#, Regex::new(r#"([ \t]*\|?[ \t]*([A-Za-z0-9\-]+)="([^"]*)"[ \t]?)"#).unwrap()));
-    regexes.push(("defaultAttribute", r"([ \t]*\|[ \t]*([^|\\]*))", Regex::new(r"([ \t]*\|[ \t]*([^|\\]*))").unwrap()));
-    regexes.push(("emptyMilestone", r"(\\([a-z1-9]+)\\[*])", Regex::new(r"(\\([a-z1-9]+)\\[*])").unwrap()));
-    regexes.push(("startMilestoneTag", r"(\\([a-z1-9]+)-([se]))", Regex::new(r"(\\([a-z1-9]+)-([se]))").unwrap()));
-    regexes.push(("endMilestoneTag", r"(\\([*]))", Regex::new(r"(\\([*]))").unwrap()));
-    regexes.push(("endTag", r"(\\([+]?[a-z\-]+)([1-9]?(-([1-9]))?)[*])", Regex::new(r"(\\([+]?[a-z\-]+)([1-9]?(-([1-9]))?)[*])").unwrap()));
-    regexes.push(("startTag", r"(\\([+]?[a-z\-]+)([1-9]?(-([1-9]))?)[ \t]?)", Regex::new(r"(\\([+]?[a-z\-]+)([1-9]?(-([1-9]))?)[ \t]?)").unwrap()));
-    regexes.push(("bareSlash", r"(\\)", Regex::new(r"(\\)").unwrap()));
-    regexes.push(("quote", r#"(")"#, Regex::new(r#"(")"#).unwrap()));
-    regexes.push(("eol", r"([ \t]*[\r\n]+[ \t]*)", Regex::new(r"([ \t]*[\r\n]+[ \t]*)").unwrap()));
-    regexes.push(("noBreakSpace", r"~", Regex::new(r"~").unwrap()));
-    regexes.push(("softLinebreak", r"//", Regex::new(r"//").unwrap()));
-    regexes.push(("wordLike", r"([\p{L}\p{N}\p{M}\u2060]{1,127})", Regex::new(r"([\p{L}\p{N}\p{M}\u2060]{1,127})").unwrap()));
-    regexes.push(("lineSpace", r"([\p{Zs}\t]{1,127})", Regex::new(r"([\p{Zs}\t]{1,127})").unwrap()));
-    regexes.push(("punctuation", r"([\p{P}\p{Sm}\p{Sc}\p{Sk}\p{So}])", Regex::new(r"([\p{P}\p{Sm}\p{Sc}\p{Sk}\p{So}])").unwrap()));
-    regexes.push(("unknown", r"(.)", Regex::new(r"(.)").unwrap()));
-
-    let main_regex_str: String = regexes.iter().map(|&(_, v, _)| v).collect::<Vec<&str>>().join("|") + "/g";
-    let _text_regex = [
-        regexes.iter().find(|&&(k, _, _)| k == "wordLike").unwrap().1,
-        regexes.iter().find(|&&(k, _, _)| k == "lineSpace").unwrap().1,
-        regexes.iter().find(|&&(k, _, _)| k == "punctuation").unwrap().1,
-        regexes.iter().find(|&&(k, _, _)| k == "eol").unwrap().1,
-    ].join("|");
-    let main_regex = Regex::new(&main_regex_str).unwrap();
+/// # Reads the USFM file and reconstructs it into an AosjModel.
+///
+/// This function processes a USFM file, with all the structs defined.
+/// It reconstructs the file into a model that implements the `AosjModel`
+/// trait. Every token remembers where it was read from, so the problems found
+/// along the way are pushed to `warnings` with their location.
+/// Markers missing from `utils_usfm` are handled according to `options.unknown_markers`.
+/// With `options.lossless`, text is kept as written and every object keeps its markup
+/// as a `UsfmSource`.
+pub fn deserialize_from_file_usfm<T: AosjModel>(mut model: T, content: String, options: &ParseOptions, warnings: &mut Vec<Warning>) -> Result<T::Output, HallomaiError> {
 
     let mut tokens: Vec<Token> = vec![];
+    let mut line_tracker = LineTracker::new();
+    let markers = MarkerTypes::new();
 
-    for cap in main_regex.find_iter(&content) {
-        for (subclass, _regex_str, regex) in &regexes {
-            if let Some(matched_bits) = regex.captures(&cap.as_str()) {
-                let matched_bits: Vec<&str> = matched_bits.iter().map(|m| m.map_or("", |m| m.as_str())).collect();
-                let token = match *subclass {
-                    "chapter" => Token::Chapter(make_chapter(subclass, matched_bits)),
-                    "pubchapter" => Token::PubChapter(make_pub_chapter(subclass, matched_bits)),
-                    "verses" => Token::Verses(make_verses(subclass, matched_bits)),
-                    "attribute" | "defaultAttribute" => Token::Attribute(make_attribute(subclass, matched_bits)),
-                    "emptyMilestone" | "startMilestoneTag" | "endMilestoneTag" => Token::Milestone(make_milestone(subclass, matched_bits)),
-                    "startTag" | "endTag" => Token::Tag(make_tag(subclass, matched_bits)),
-                    "bareSlash" | "quote" | "eol" | "noBreakSpace" | "softLinebreak" | "wordLike" | "lineSpace" | "punctuation" | "unknown" => Token::Printable(make_printable(subclass, matched_bits)),
-                    _ => Token::Printable(make_bad(subclass, matched_bits)),
-                };
-
-                tokens.push(token);
-
-                break;
-            }
-        }
+    for lexeme in Lexer::new(&content) {
+        let span = line_tracker.span(&content, lexeme.start, lexeme.end);
+        let (subclass, parts) = (lexeme.subclass, lexeme.parts.as_slice());
+        let token = match subclass {
+            "chapter" => Token::Chapter(make_chapter(subclass, parts, span)),
+            "pubchapter" => Token::PubChapter(make_pub_chapter(subclass, parts, span)),
+            "verses" => Token::Verses(make_verses(subclass, parts, span)),
+            "attribute" | "defaultAttribute" => Token::Attribute(make_attribute(subclass, parts, span)),
+            "emptyMilestone" | "startMilestoneTag" | "endMilestoneTag" => Token::Milestone(make_milestone(subclass, parts, span)),
+            "startTag" | "endTag" => Token::Tag(make_tag(subclass, parts, span, &content, &markers, options, warnings)?),
+            _ => Token::Printable(make_printable(subclass, parts, span)),
+        };
+        tokens.push(token);
     }
+    let note_categories = note_categories(&tokens);
 
-    let mut model = T::new();
+    let mut trivia = Trivia::new(&content, options);
     let mut txt: Vec<String> = Vec::new();
     let mut open_para_tags: Vec<Tag> = Vec::new();
+    // Open notes are kept with the character markers, so that the markers opened
+    // inside a note are closed with it
     let mut open_char_tags: Vec<Tag> = Vec::new();
-    let mut open_note_tags: Vec<Tag> = Vec::new();
+    let mut open_sidebar_tags: Vec<Tag> = Vec::new();
+    let mut in_figure: bool = false;
+    // The `|`-separated fields of a USFM 2 figure
+    let mut figure_fields: Vec<String> = Vec::new();
+    // Where the caption field of a USFM 2 figure starts and ends
+    let mut caption_span: Option<(usize, usize)> = None;
     let mut attributes: BTreeMap<String, String> = BTreeMap::new();
     let mut in_milestone: bool = false;
 
-    for token in tokens {
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
         match token {
             Token::Tag(t) => {
 
@@ -309,21 +499,25 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
                     "startTag" => {
                         match t.tag_type.as_str() {
                             "para" => {
-                                while open_char_tags.len() > 0 {
-                                    let pop_tag = open_char_tags.pop().unwrap();
-                                    do_end_tag(&mut model, pop_tag, &mut txt);
+                                while let Some(pop_tag) = open_char_tags.pop() {
+                                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
                                 }
-                                while open_para_tags.len() > 0 {
-                                    let pop_tag = open_para_tags.pop().unwrap();
-                                    do_end_tag(&mut model, pop_tag, &mut txt);
+                                while let Some(pop_tag) = open_para_tags.pop() {
+                                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
                                 }
                                 open_para_tags.push(t.clone());
 
-                                let marker = t.full_tag_name;
-                                attributes.insert("marker".to_string(), marker);
-                                model.push_element(attributes.clone(), "para".to_string());
-                                model.start_new_para(model.get_attributes());
-                                attributes.clear();
+                                // \usfm only carries the version of the root
+                                if t.tag_name == "usfm" {
+                                    trivia.defer(t.span.start.offset, t.span.end);
+                                } else {
+                                    trivia.open(&mut model, t.span.start.offset, t.span.end);
+                                    let marker = t.full_tag_name;
+                                    attributes.insert("marker".to_string(), marker);
+                                    model.push_element(attributes.clone(), "para".to_string());
+                                    model.start_new_para(model.get_attributes());
+                                    attributes.clear();
+                                }
                             }
                             "char" => {
                                 match t.tag_name.as_str() {
@@ -331,12 +525,17 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
                                         if !txt.is_empty() {
                                             model.add_string_to_in_para(&mut txt);
                                         }
+                                        trivia.defer(t.span.start.offset, t.span.end);
+                                    }
+                                    "cat" if open_char_tags.last().is_some_and(|tag| tag.tag_type == "note") => {
+                                        // Already read as the category of the note
+                                        read_category(&mut tokens);
+                                        tokens.next_if(|next| matches!(next, Token::Printable(p) if p.subclass == "lineSpace"));
                                     }
                                     _ => {
                                         if !t.is_nested {
-                                            while open_char_tags.len() > 0 {
-                                                let pop_tag = open_char_tags.pop().unwrap();
-                                                do_end_tag(&mut model, pop_tag, &mut txt);
+                                            while let Some(pop_tag) = open_char_tags.pop_if(|tag| tag.tag_type == "char") {
+                                                do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
                                             }
                                         }
                                         open_char_tags.push(t.clone());
@@ -344,7 +543,8 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
                                         if !txt.is_empty() {
                                             model.add_string_to_in_para(&mut txt);
                                         }
-                                        let marker = t.tag_name;
+                                        trivia.open(&mut model, t.span.start.offset, t.span.end);
+                                        let marker = t.full_tag_name;
                                         attributes.insert("marker".to_string(), marker);
 
                                         model.push_element(attributes.clone(), "char".to_string());
@@ -354,16 +554,28 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
                                 }
                             }
                             "note" => {
-                                while open_char_tags.len() > 0 {
-                                    let pop_tag = open_char_tags.pop().unwrap();
-                                    do_end_tag(&mut model, pop_tag, &mut txt);
+                                if open_char_tags.iter().any(|tag| tag.tag_type == "note") {
+                                    warnings.push(Warning::new(format!("Note \\{} inside a note", t.tag_name), t.span.start));
+                                    close_note(&mut model, &mut open_char_tags, &mut txt, &mut trivia);
                                 }
-                                while open_note_tags.len() > 0 {
-                                    let pop_tag = open_note_tags.pop().unwrap();
-                                    do_end_tag(&mut model, pop_tag, &mut txt);
+                                if !txt.is_empty() {
+                                    model.add_string_to_in_para(&mut txt);
                                 }
-                                open_note_tags.push(t.clone());
+                                open_char_tags.push(t.clone());
 
+                                // The caller (`+`, `-` or a custom one) comes first
+                                if let Some(Token::Printable(caller)) = tokens.next_if(|next| matches!(next, Token::Printable(p) if p.subclass != "lineSpace" && p.subclass != "eol")) {
+                                    attributes.insert("caller".to_string(), caller.print_value);
+                                    tokens.next_if(|next| matches!(next, Token::Printable(p) if p.subclass == "lineSpace"));
+                                }
+                                if tokens.next_if(|next| matches!(next, Token::Tag(tag) if tag.tag_name == "cat" && tag.subclass == "startTag")).is_some() {
+                                    attributes.insert("category".to_string(), read_category(&mut tokens));
+                                    tokens.next_if(|next| matches!(next, Token::Printable(p) if p.subclass == "lineSpace"));
+                                } else if let Some(category) = note_categories.get(&t.span.start.offset) {
+                                    attributes.insert("category".to_string(), category.clone());
+                                }
+                                let end = trivia.end_of(&mut tokens);
+                                trivia.open(&mut model, t.span.start.offset, end);
                                 let marker = t.full_tag_name;
                                 attributes.insert("marker".to_string(), marker);
                                 model.push_element(attributes.clone(), "note".to_string());
@@ -371,7 +583,90 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
                                 attributes.clear();
                             }
                             "book" => {
+                                trivia.defer(t.span.start.offset, t.span.end);
+                                open_para_tags.push(t.clone());
+                            }
+                            "figure" => {
+                                if !txt.is_empty() {
+                                    model.add_string_to_in_para(&mut txt);
+                                }
+                                trivia.defer(t.span.start.offset, t.span.end);
+                                in_figure = true;
+                            }
+                            "ref" => {
+                                if !txt.is_empty() {
+                                    model.add_string_to_in_para(&mut txt);
+                                }
+                                trivia.defer(t.span.start.offset, t.span.end);
+                            }
+                            "sidebar" => {
+                                while let Some(pop_tag) = open_char_tags.pop() {
+                                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+                                }
+                                while let Some(pop_tag) = open_para_tags.pop() {
+                                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+                                }
+                                open_sidebar_tags.push(t.clone());
+
+                                // \esb \cat History\cat* gives the category of the sidebar
+                                if tokens.next_if(|next| matches!(next, Token::Tag(tag) if tag.tag_name == "cat" && tag.subclass == "startTag")).is_some() {
+                                    attributes.insert("category".to_string(), read_category(&mut tokens));
+                                }
+                                let end = trivia.end_of(&mut tokens);
+                                trivia.open(&mut model, t.span.start.offset, end);
+                                attributes.insert("marker".to_string(), t.full_tag_name);
+                                model.push_element(attributes.clone(), "sidebar".to_string());
+                                model.start_sidebar(model.get_attributes());
+                                attributes.clear();
+                            }
+                            "sidebarEnd" => {
+                                while let Some(pop_tag) = open_char_tags.pop() {
+                                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+                                }
+                                while let Some(pop_tag) = open_para_tags.pop() {
+                                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+                                }
+                                if let Some(pop_tag) = open_sidebar_tags.pop() {
+                                    trivia.close(&mut model, t.span);
+                                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+                                } else {
+                                    warnings.push(Warning::new("Sidebar end \\esbe without a \\esb", t.span.start));
+                                }
+                            }
+                            "row" => {
+                                while let Some(pop_tag) = open_char_tags.pop() {
+                                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+                                }
+                                start_row(&mut model, &mut open_para_tags, t, &mut txt, &mut trivia);
+                            }
+                            "cell" => {
+                                while let Some(pop_tag) = open_char_tags.pop() {
+                                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+                                }
+                                if open_para_tags.last().is_some_and(|tag| tag.tag_type == "cell") {
+                                    do_end_tag(&mut model, open_para_tags.pop().unwrap(), &mut txt, &mut trivia);
+                                }
+                                if open_para_tags.last().is_none_or(|tag| tag.tag_type != "row") {
+                                    warnings.push(Warning::new(format!("Table cell \\{} outside of a \\tr row", t.full_tag_name), t.span.start));
+                                    let mut row_tag = t.clone();
+                                    // The row has no markup of its own
+                                    row_tag.span.end = row_tag.span.start.offset;
+                                    row_tag.tag_name = "tr".to_string();
+                                    row_tag.full_tag_name = "tr".to_string();
+                                    row_tag.tag_type = "row".to_string();
+                                    start_row(&mut model, &mut open_para_tags, row_tag, &mut txt, &mut trivia);
+                                }
                                 open_para_tags.push(t.clone());
+
+                                trivia.open(&mut model, t.span.start.offset, t.span.end);
+                                attributes.insert("marker".to_string(), t.full_tag_name);
+                                attributes.insert("align".to_string(), utils_usfm::cell_align(&t.tag_name).to_string());
+                                if let Some(colspan) = t.colspan {
+                                    attributes.insert("colspan".to_string(), colspan);
+                                }
+                                model.push_element(attributes.clone(), "table:cell".to_string());
+                                model.start_cell(model.get_attributes());
+                                attributes.clear();
                             }
 
                             _ => {}
@@ -383,26 +678,94 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
                                 match t.tag_name.as_str() {
                                     "w" => {
                                         let marker = t.tag_name;
+                                        name_default_attribute(&mut attributes, &marker);
                                         attributes.insert("marker".to_string(), marker);
                                         model.push_element(attributes.clone(), "char".to_string());
+                                        trivia.open_deferred(&mut model, "");
                                         model.start_add_char_marker(model.get_attributes());
-                                        if !txt.is_empty() {
-                                            model.end_add_char_marker(&mut txt);
-                                        }
+                                        trivia.close(&mut model, t.span);
+                                        model.end_add_char_marker(&mut txt);
                                         attributes.clear();
                                     }
                                     _ => {
-                                        if !open_char_tags.is_empty() {
-                                            let pop_tag = open_char_tags.pop().unwrap();
-                                            do_end_tag(&mut model, pop_tag, &mut txt);
+                                        if let Some(pop_tag) = open_char_tags.pop_if(|tag| tag.tag_type == "char") {
+                                            if !attributes.is_empty() {
+                                                name_default_attribute(&mut attributes, &pop_tag.tag_name);
+                                                model.push_element(attributes.clone(), "char".to_string());
+                                                model.add_char_attributes(model.get_attributes());
+                                                attributes.clear();
+                                            }
+                                            trivia.close(&mut model, t.span);
+                                            do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+                                        } else {
+                                            warnings.push(Warning::new(format!("Closing marker \\{}* without an opening marker", t.tag_name), t.span.start));
                                         }
                                     }
                                 }
                             }
+                            "figure" if in_figure => {
+                                let text = txt.join("");
+                                let mut caption = if trivia.lossless { text.clone() } else { text.trim().to_string() };
+                                txt.clear();
+                                let usfm2 = !figure_fields.is_empty();
+                                if usfm2 {
+                                    // USFM 2: \fig DESC|FILE|SIZE|LOC|COPY|CAP|REF\fig*
+                                    attributes.insert("alt".to_string(), text.trim().to_string());
+                                    let mut fields = figure_fields.drain(..);
+                                    for key in ["file", "size", "loc", "copy"] {
+                                        attributes.insert(key.to_string(), fields.next().unwrap_or_default());
+                                    }
+                                    caption = fields.next().unwrap_or_default();
+                                    attributes.insert("ref".to_string(), fields.next().unwrap_or_default());
+                                } else if let Some(src) = attributes.remove("src") {
+                                    attributes.insert("file".to_string(), src);
+                                }
+                                attributes.retain(|_, value| !value.is_empty());
+                                attributes.insert("marker".to_string(), t.tag_name);
+                                model.push_element(attributes.clone(), "figure".to_string());
+                                if usfm2 && !trivia.deferred.is_empty() {
+                                    // The markup read is split around the caption, the content of the figure
+                                    let (start, end) = caption_span.take().unwrap_or((t.span.start.offset, t.span.start.offset));
+                                    let fields = &content[trivia.attributes_start.take().unwrap_or(start)..start];
+                                    trivia.open_deferred(&mut model, &(text + fields));
+                                    model.start_figure(model.get_attributes());
+                                    model.keep_usfm_close(content[end..t.span.end].to_string());
+                                } else if !trivia.deferred.is_empty() {
+                                    trivia.open_deferred(&mut model, "");
+                                    model.start_figure(model.get_attributes());
+                                    trivia.close(&mut model, t.span);
+                                } else {
+                                    model.start_figure(model.get_attributes());
+                                }
+                                model.end_figure(&mut Some(caption).filter(|c| !c.is_empty()).into_iter().collect());
+                                attributes.clear();
+                                in_figure = false;
+                            }
+                            "figure" => {
+                                warnings.push(Warning::new("Closing marker \\fig* without an opening marker", t.span.start));
+                            }
+                            "ref" => {
+                                // \ref Mark 1:1|MRK 1:1\ref*: the default attribute is the location
+                                name_default_attribute(&mut attributes, "ref");
+                                model.push_element(attributes.clone(), "ref".to_string());
+                                trivia.open_deferred(&mut model, "");
+                                model.start_ref(model.get_attributes());
+                                trivia.close(&mut model, t.span);
+                                let text = txt.join("");
+                                let mut text = vec![if trivia.lossless { text } else { text.trim().to_string() }];
+                                txt.clear();
+                                model.end_ref(&mut text);
+                                attributes.clear();
+                            }
                             "note" => {
-                                if !open_note_tags.is_empty() {
-                                    let pop_tag = open_note_tags.pop().unwrap();
-                                    do_end_tag(&mut model, pop_tag, &mut txt);
+                                if open_char_tags.iter().any(|tag| tag.tag_type == "note") {
+                                    while let Some(pop_tag) = open_char_tags.pop_if(|tag| tag.tag_type != "note") {
+                                        do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+                                    }
+                                    trivia.close(&mut model, t.span);
+                                    close_note(&mut model, &mut open_char_tags, &mut txt, &mut trivia);
+                                } else {
+                                    warnings.push(Warning::new(format!("Closing marker \\{}* without an opening marker", t.tag_name), t.span.start));
                                 }
                             }
                             _ => {}
@@ -411,29 +774,59 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
                     _ => {}
                 }
             }
-            Token::Printable(p) => {
-
-                if p.subclass != "eol" {
-                    let re = Regex::new(r"[ \t\r\n]+").unwrap();
-                    txt.push(re.replace_all(p.print_value.as_str(), " ").to_string());
+            Token::Printable(p) if p.subclass == "softLinebreak" => {
+                if !txt.is_empty() {
+                    model.add_string_to_in_para(&mut txt);
+                }
+                model.add_optbreak(String::new());
+            }
+            Token::Printable(p) if trivia.lossless => {
+                let text = &content[p.span.start.offset..p.span.end];
+                if trivia.attributes_start.is_some() {
+                    // kept with the attributes, in the markup of the closing marker
+                } else if p.subclass == "eol" && tokens.peek().is_none_or(Token::is_marker) {
+                    trivia.layout.push_str(text);
+                } else {
+                    txt.push(text.to_string());
                 }
             }
+            // a line break in text is a space, but is dropped before a paragraph or a verse
+            Token::Printable(p) if p.subclass == "eol" && !tokens.peek().is_none_or(Token::is_marker) => {
+                txt.push(" ".to_string());
+            }
+            Token::Printable(p) if p.subclass != "eol" => {
+                txt.push(collapse_whitespace(&p.print_value));
+            }
 
             Token::Chapter(c) => {
 
-                if !txt.is_empty() {
-                    model.add_string_to_in_para(&mut txt);
+                while let Some(pop_tag) = open_char_tags.pop() {
+                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
                 }
-
-                while open_para_tags.len() > 0 {
-                    let pop_tag = open_para_tags.pop().unwrap();
-                    do_end_tag(&mut model, pop_tag, &mut txt);
+                while let Some(pop_tag) = open_para_tags.pop() {
+                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+                }
+                while let Some(pop_tag) = open_sidebar_tags.pop() {
+                    warnings.push(Warning::new("Sidebar \\esb not closed by \\esbe before the chapter", pop_tag.span.start));
+                    do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
                 }
 
                 let number = c.number_string;
                 let marker = "c".to_string();
                 attributes.insert("number".to_string(), number);
                 attributes.insert("marker".to_string(), marker);
+                // \ca and \cp directly follow the chapter they number
+                loop {
+                    if let Some(altnumber) = read_number_marker(&mut tokens, "ca") {
+                        attributes.insert("altnumber".to_string(), altnumber);
+                    } else if let Some(Token::PubChapter(cp)) = tokens.next_if(|next| matches!(next, Token::PubChapter(_))) {
+                        attributes.insert("pubnumber".to_string(), cp.number_string);
+                    } else {
+                        break;
+                    }
+                }
+                let end = trivia.end_of(&mut tokens);
+                trivia.open(&mut model, c.span.start.offset, end);
                 model.push_element(attributes.clone(), "chapter".to_string());
                 model.add_chapter(model.get_attributes());
                 attributes.clear();
@@ -445,15 +838,34 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
                     model.add_string_to_in_para(&mut txt);
                 }
 
+                if let Err(message) = v.number_string.parse::<VerseRange>() {
+                    warnings.push(Warning::new(message, v.span.start));
+                }
                 let number = v.number_string;
                 let marker = "v".to_string();
                 attributes.insert("number".to_string(), number);
                 attributes.insert("marker".to_string(), marker);
+                // \va and \vp directly follow the verse they number
+                loop {
+                    if let Some(altnumber) = read_number_marker(&mut tokens, "va") {
+                        attributes.insert("altnumber".to_string(), altnumber);
+                    } else if let Some(pubnumber) = read_number_marker(&mut tokens, "vp") {
+                        attributes.insert("pubnumber".to_string(), pubnumber);
+                    } else {
+                        break;
+                    }
+                }
+                let end = trivia.end_of(&mut tokens);
+                trivia.open(&mut model, v.span.start.offset, end);
                 model.push_element(attributes.clone(), "verse".to_string());
                 model.add_verse_to_in_para(model.get_attributes());
                 attributes.clear();
             }
 
+            Token::PubChapter(cp) => {
+                warnings.push(Warning::new("Publishing chapter number \\cp not directly after a chapter", cp.span.start));
+            }
+
             Token::Milestone(m) => {
 
                 if !txt.is_empty() {
@@ -462,6 +874,7 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
 
                 match m.subclass.as_str() {
                     "emptyMilestone" => {
+                        trivia.open(&mut model, m.span.start.offset, m.span.end);
                         let marker = m.tag_name.unwrap();
                         attributes.insert("marker".to_string(), marker);
                         model.push_element(attributes.clone(), "ms".to_string());
@@ -469,6 +882,7 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
                         attributes.clear();
                     }
                     "startMilestoneTag" => {
+                        trivia.defer(m.span.start.offset, m.span.end);
                         match m.s_or_e.unwrap().as_str() {
                             "s" => {
                                 in_milestone = true;
@@ -483,60 +897,185 @@ pub fn deserialize_from_file_usfm<T: AosjModel>(content: String) -> String {
                             _ => {}
                         }
                     }
-                    "endMilestoneTag" => {
-                        if in_milestone {
-                            model.push_element(attributes.clone(), "ms".to_string());
-                            model.add_milestone(model.get_attributes());
-                            attributes.clear();
-                            in_milestone = false;
+                    "endMilestoneTag" if in_milestone => {
+                        let marker = attributes.get("marker").cloned().unwrap_or_default();
+                        name_default_attribute(&mut attributes, &marker);
+                        if trivia.lossless {
+                            let start = trivia.attributes_start.take().unwrap_or(m.span.start.offset);
+                            let open = trivia.content[start..m.span.end].to_string();
+                            trivia.open_deferred(&mut model, &open);
                         }
+                        model.push_element(attributes.clone(), "ms".to_string());
+                        model.add_milestone(model.get_attributes());
+                        attributes.clear();
+                        in_milestone = false;
+                    }
+                    "endMilestoneTag" => {
+                        warnings.push(Warning::new("Milestone end \\* without a milestone start", m.span.start));
                     }
                     _ => {}
                 }
             }
 
+            Token::Attribute(a) if in_figure && a.subclass == "defaultAttribute" => {
+                trivia.attributes_start.get_or_insert(a.span.start.offset);
+                if figure_fields.len() == 4 {
+                    let start = a.span.start.offset + content[a.span.start.offset..a.span.end].find(&a.value_string).unwrap_or_default();
+                    caption_span = Some((start, start + a.value_string.len()));
+                }
+                figure_fields.push(a.value_string);
+            }
+
             Token::Attribute(a) => {
+                trivia.attributes_start.get_or_insert(a.span.start.offset);
                 attributes.insert(a.key, a.value_string);
             }
 
             _ => {}
         }
     }
-    while open_char_tags.len() > 0 {
-        let pop_tag = open_char_tags.pop().unwrap();
-        do_end_tag(&mut model, pop_tag, &mut txt);
+    while let Some(pop_tag) = open_char_tags.pop() {
+        do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+    }
+    while let Some(pop_tag) = open_para_tags.pop() {
+        do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+    }
+    while let Some(pop_tag) = open_sidebar_tags.pop() {
+        warnings.push(Warning::new("Sidebar \\esb not closed by \\esbe", pop_tag.span.start));
+        do_end_tag(&mut model, pop_tag, &mut txt, &mut trivia);
+    }
+
+    Ok(model.assemble_model())
+}
+
+/// Closes the innermost note and the character markers opened inside it.
+fn close_note<T: AosjModel>(model: &mut T, open_char_tags: &mut Vec<Tag>, txt: &mut Vec<String>, trivia: &mut Trivia) {
+    while let Some(pop_tag) = open_char_tags.pop() {
+        let is_note = pop_tag.tag_type == "note";
+        do_end_tag(model, pop_tag, txt, trivia);
+        if is_note {
+            break;
+        }
+    }
+}
+
+/// Reads the text of a `\cat` marker up to its `\cat*`, the `\cat` itself being already read.
+fn read_category(tokens: &mut Peekable<IntoIter<Token>>) -> String {
+    let mut category = String::new();
+    while let Some(Token::Printable(p)) = tokens.next_if(|next| matches!(next, Token::Printable(_))) {
+        category.push_str(&p.print_value);
+    }
+    tokens.next_if(|next| matches!(next, Token::Tag(tag) if tag.tag_name == "cat" && tag.subclass == "endTag"));
+    category.trim().to_string()
+}
+
+/// Reads a `\marker value\marker*` number, such as `\va`, when it is the next token.
+fn read_number_marker(tokens: &mut Peekable<IntoIter<Token>>, marker: &str) -> Option<String> {
+    tokens.next_if(|next| matches!(next, Token::Tag(tag) if tag.tag_name == marker && tag.subclass == "startTag"))?;
+    let mut number = String::new();
+    while let Some(Token::Printable(p)) = tokens.next_if(|next| matches!(next, Token::Printable(_))) {
+        number.push_str(&p.print_value);
+    }
+    tokens.next_if(|next| matches!(next, Token::Tag(tag) if tag.tag_name == marker && tag.subclass == "endTag"));
+    while tokens.next_if(|next| matches!(next, Token::Printable(p) if p.subclass == "lineSpace" || p.subclass == "eol")).is_some() {}
+    Some(number.trim().to_string())
+}
+
+/// The `\cat` categories of the notes, by the offset of the note: the category may come
+/// anywhere in the note, so each note is looked through once before reading.
+fn note_categories(tokens: &[Token]) -> BTreeMap<usize, String> {
+    let mut categories = BTreeMap::new();
+    for (index, token) in tokens.iter().enumerate() {
+        let Token::Tag(note) = token else { continue };
+        if note.tag_type != "note" || note.subclass != "startTag" {
+            continue;
+        }
+        let mut rest = tokens[index + 1..].iter();
+        while let Some(token) = rest.next() {
+            if let Token::Tag(tag) = token {
+                if tag.tag_name == "cat" && tag.subclass == "startTag" {
+                    let category: String = rest.map_while(|next| match next {
+                        Token::Printable(p) => Some(p.print_value.as_str()),
+                        _ => None,
+                    }).collect();
+                    categories.insert(note.span.start.offset, category.trim().to_string());
+                    break;
+                } else if tag.tag_name == note.tag_name || tag.tag_type == "para" {
+                    break;
+                }
+            }
+        }
+    }
+    categories
+}
+
+/// Collapses each run of whitespace in a piece of text into a single space.
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if matches!(c, ' ' | '\t' | '\r' | '\n') {
+            while chars.next_if(|next| matches!(next, ' ' | '\t' | '\r' | '\n')).is_some() {}
+            collapsed.push(' ');
+        } else {
+            collapsed.push(c);
+        }
     }
-    while open_para_tags.len() > 0 {
-        let pop_tag = open_para_tags.pop().unwrap();
-        do_end_tag(&mut model, pop_tag, &mut txt);
+    collapsed
+}
+
+/// Starts a table row, closing the previous row, or starting the table if
+/// the row is its first one.
+fn start_row<T: AosjModel>(model: &mut T, open_para_tags: &mut Vec<Tag>, row_tag: Tag, txt: &mut Vec<String>, trivia: &mut Trivia) {
+    while let Some(pop_tag) = open_para_tags.pop_if(|tag| tag.tag_type != "table") {
+        do_end_tag(model, pop_tag, txt, trivia);
+    }
+    if open_para_tags.is_empty() {
+        let mut table_tag = row_tag.clone();
+        table_tag.tag_type = "table".to_string();
+        open_para_tags.push(table_tag);
+        model.push_element(BTreeMap::new(), "table".to_string());
+        model.start_table(model.get_attributes());
     }
 
-    model.assemble_model()
+    trivia.open(model, row_tag.span.start.offset, row_tag.span.end);
+    let mut attributes: BTreeMap<String, String> = BTreeMap::new();
+    attributes.insert("marker".to_string(), row_tag.full_tag_name.clone());
+    model.push_element(attributes, "table:row".to_string());
+    model.start_row(model.get_attributes());
+    open_para_tags.push(row_tag);
 }
 
-fn do_end_tag<T: AosjModel>(model: &mut T, token: Tag, txt: &mut Vec<String>) {
+fn do_end_tag<T: AosjModel>(model: &mut T, token: Tag, txt: &mut Vec<String>, trivia: &mut Trivia) {
     match token.tag_name.as_str() {
         "usfm" => {
+            trivia.open_deferred(model, "");
+            trivia.close_with_layout(model);
             model.add_root_metadata(&txt.join(""));
             txt.clear();
         }
         _ => {
             match token.tag_type.as_str() {
                 "book" => {
-                    let code = txt.clone().join("").chars().take(3).collect::<String>();
+                    let id_line = txt.join("");
+                    let code = id_line.chars().take(3).collect::<String>();
                     let marker = token.tag_name;
                     let mut attributes: BTreeMap<String, String> = BTreeMap::new();
 
                     attributes.insert("code".to_string(), code);
                     attributes.insert("marker".to_string(), marker);
                     model.push_element(attributes, "book".to_string());
+                    // The code is part of the markup
+                    trivia.open_deferred(model, id_line.get(..4).unwrap_or(&id_line));
                     model.start_book(model.get_attributes());
-                    model.add_string_to_in_para(&mut vec![txt.clone().join("")[4..].to_string()]);
+                    trivia.close_with_layout(model);
+                    model.add_string_to_in_para(&mut vec![id_line.get(4..).unwrap_or("").to_string()]);
                     model.end_book();
                     txt.clear();
                 }
 
                 "para" => {
+                    trivia.close_with_layout(model);
                     model.add_string_to_in_para(txt);
                     model.end_new_para();
                 }
@@ -548,6 +1087,23 @@ fn do_end_tag<T: AosjModel>(model: &mut T, token: Tag, txt: &mut Vec<String>) {
                 "note" => {
                     model.end_add_note(txt);
                 }
+
+                "table" => {
+                    model.end_table();
+                }
+
+                "row" => {
+                    trivia.close_with_layout(model);
+                    model.end_row();
+                }
+
+                "cell" => {
+                    model.end_cell(txt);
+                }
+
+                "sidebar" => {
+                    model.end_sidebar();
+                }
                 _ => {}
             }
         }
@@ -1,124 +1,238 @@
 #![allow(dead_code)]
 
 use std::collections::BTreeMap;
-use serde_json::Value;
-use regex::Regex;
+use serde_json::{Map, Value};
+use crate::error::{HallomaiError, SourceLocation, Warning};
 use crate::model_traits::AosjModel;
 
-/// # Reads the USJ file and reconstructs it into an AosjModel.
-///
-/// This function processes a USJ file, parsing its content and reconstructing
-/// it into a model that implements the `AosjModel` trait. It handles different
-/// types of elements such as books, paragraphs, chapters, verses, characters, and notes.
+/// A warning about the value at the JSON pointer `path`; USJ warnings have no line and column.
+fn warn(warnings: &mut Vec<Warning>, message: String, path: &str) {
+    warnings.push(Warning::new(format!("{} at '{}'", message, path), SourceLocation::default()));
+}
+
+/// Reads the properties of an object as attributes. Numbers and booleans are
+/// written as text, e.g. a chapter `"number": 1` as `1`, and `null` is left out.
+/// Nested arrays and objects cannot be attributes: they are left out with a warning.
+fn read_attributes(obj: &Map<String, Value>, path: &str, warnings: &mut Vec<Warning>) -> Result<BTreeMap<String, String>, HallomaiError> {
+    let mut attributes: BTreeMap<String, String> = BTreeMap::new();
+    for (key, value) in obj.iter() {
+        if key == "content" || key == "type" {
+            continue;
+        }
+        if value.is_array() || value.is_object() {
+            warn(warnings, format!("Property '{}' is not a string, number or boolean and is left out", key), &format!("{}/{}", path, key));
+        } else if let Some(value) = scalar_string(value) {
+            attributes.insert(key.to_string(), value);
+        }
+    }
+    if obj.get("content").is_some_and(|content| !content.is_array() && !content.is_null()) {
+        return Err(HallomaiError::schema("'content' must be an array", format!("{}/content", path)));
+    }
+    Ok(attributes)
+}
 
-fn read_content<T:AosjModel>(model: &mut T, object: &Value) {
+fn scalar_string(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(text) => Some(text.to_string()),
+        _ => Some(value.to_string()),
+    }
+}
+
+fn read_type<'a>(obj: &'a Map<String, Value>, path: &str) -> Result<&'a str, HallomaiError> {
+    obj.get("type")
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| HallomaiError::schema("missing 'type'", format!("{}/type", path)))
+}
+
+fn read_children<T:AosjModel>(model: &mut T, obj: &Map<String, Value>, path: &str, warnings: &mut Vec<Warning>) -> Result<(), HallomaiError> {
+    if let Some(contents) = obj.get("content").and_then(|c| c.as_array()) {
+        for (i, object) in contents.iter().enumerate() {
+            read_content(model, object, &format!("{}/content/{}", path, i), warnings)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the rows of a table and their cells; both the `table:row`/`table:cell`
+/// and the older `row`/`cell` types are accepted.
+fn read_table<T:AosjModel>(model: &mut T, table: &Map<String, Value>, path: &str, warnings: &mut Vec<Warning>) -> Result<(), HallomaiError> {
+    for (i, row) in table.get("content").and_then(|c| c.as_array()).into_iter().flatten().enumerate() {
+        let row_path = format!("{}/content/{}", path, i);
+        let row = row.as_object()
+            .ok_or_else(|| HallomaiError::schema("a table row must be an object", row_path.as_str()))?;
+        let row_type = read_type(row, &row_path)?;
+        if row_type != "table:row" && row_type != "row" {
+            return Err(HallomaiError::schema(format!("unexpected '{}' in a table", row_type), format!("{}/type", row_path)));
+        }
+        model.push_element(read_attributes(row, &row_path, warnings)?, row_type.to_string());
+        model.start_row(model.get_attributes());
+
+        for (j, cell) in row.get("content").and_then(|c| c.as_array()).into_iter().flatten().enumerate() {
+            let cell_path = format!("{}/content/{}", row_path, j);
+            let cell = cell.as_object()
+                .ok_or_else(|| HallomaiError::schema("a table cell must be an object", cell_path.as_str()))?;
+            let cell_type = read_type(cell, &cell_path)?;
+            if cell_type != "table:cell" && cell_type != "cell" {
+                return Err(HallomaiError::schema(format!("unexpected '{}' in a table row", cell_type), format!("{}/type", cell_path)));
+            }
+            model.push_element(read_attributes(cell, &cell_path, warnings)?, cell_type.to_string());
+            model.start_cell(model.get_attributes());
+            read_children(model, cell, &cell_path, warnings)?;
+            model.parent_els().pop();
+            model.end_cell(&mut Vec::new());
+        }
+
+        model.parent_els().pop();
+        model.end_row();
+    }
+    Ok(())
+}
+
+fn read_content<T:AosjModel>(model: &mut T, object: &Value, path: &str, warnings: &mut Vec<Warning>) -> Result<(), HallomaiError> {
     let mut txt: Vec<String> = Vec::new();
-    let values = Regex::new(r#"(["\\])"#).unwrap();
     match object {
         Value::String(text) => {
-            let new_value = values.replace_all(text.as_str(), "\\$1");
-            txt.push(new_value.to_string());
+            txt.push(text.to_string());
         }
         Value::Object(obj) => {
-            let mut attributes: BTreeMap<String, String> = BTreeMap::new();
+            let attributes = read_attributes(obj, path, warnings)?;
+            let tag_name = read_type(obj, path)?;
+            model.push_element(attributes, tag_name.to_string());
 
-            for (key, value) in obj.iter() {
-                if key != "content" && key != "type" {
-                    attributes.insert(key.to_string(), value.as_str().unwrap().parse().unwrap());
-                }
-            }
-
-            let tag_name = obj.get("type").unwrap().to_string();
-            model.push_element(attributes.clone(), tag_name);
-
-            match obj.get("type").and_then(|t| t.as_str()) {
-                Some("verse") => {
+            match tag_name {
+                "verse" => {
                     model.add_verse_to_in_para(model.get_attributes());
                     model.parent_els().pop();
                 }
-                Some("ms") => {
+                "ms" => {
                     model.add_milestone(model.get_attributes());
                     model.parent_els().pop();
                 }
-                Some("char") => {
+                "char" => {
                     model.start_add_char_marker(model.get_attributes());
-                    if let Some(contents) = obj.get("content").and_then(|c| c.as_array()) {
-                        for object in contents {
-                            read_content(model, object);
-                        }
-                    }
+                    read_children(model, obj, path, warnings)?;
                     model.parent_els().pop();
                     model.end_add_char_marker(&mut txt);
                 }
-                Some("note") => {
+                "note" => {
                     model.start_add_note(model.get_attributes());
-                    if let Some(contents) = obj.get("content").and_then(|c| c.as_array()) {
-                        for object in contents {
-                            read_content(model, object);
-                        }
-                    }
+                    read_children(model, obj, path, warnings)?;
                     model.parent_els().pop();
                     model.end_add_note(&mut txt);
                 }
-                _=> {}
+                "figure" => {
+                    model.start_figure(model.get_attributes());
+                    read_children(model, obj, path, warnings)?;
+                    model.parent_els().pop();
+                    model.end_figure(&mut txt);
+                }
+                "ref" => {
+                    model.start_ref(model.get_attributes());
+                    read_children(model, obj, path, warnings)?;
+                    model.parent_els().pop();
+                    model.end_ref(&mut txt);
+                }
+                // USJ 3 `optbreak`, `whitespace` with the `optbreak` marker before it
+                "optbreak" | "whitespace" => {
+                    model.add_optbreak(model.get_attributes());
+                    model.parent_els().pop();
+                }
+                _ => {
+                    warn(warnings, format!("Unknown type '{}', only its content is kept", tag_name), path);
+                    model.parent_els().pop();
+                    read_children(model, obj, path, warnings)?;
+                }
             }
         }
-        _=> {}
+        _ => warn(warnings, format!("Expected a string or an object, found {}; it is left out", object), path),
     }
     model.add_string_to_in_para(&mut txt);
+    Ok(())
 }
 
 
-pub fn deserialize_from_file_usj<T:AosjModel>(json: Value) -> String {
-
-    let mut model = T::new();
+/// # Reads the USJ file and reconstructs it into an AosjModel.
+///
+/// This function processes a USJ file, parsing its content and reconstructing
+/// it into a model that implements the `AosjModel` trait. It handles different
+/// types of elements such as books, paragraphs, chapters, verses, characters, and notes.
+/// What cannot be read as USJ without stopping, e.g. an unknown type, is reported in `warnings`.
+pub fn deserialize_from_file_usj<T:AosjModel>(mut model: T, json: Value, warnings: &mut Vec<Warning>) -> Result<T::Output, HallomaiError> {
 
-    let version = json.get("version").expect("Missing version").as_str().expect("Version should be a string");
-    model.add_root_metadata(&version.to_string());
+    let version = json.get("version")
+        .and_then(scalar_string)
+        .ok_or_else(|| HallomaiError::schema("missing 'version'", "/version"))?;
+    model.add_root_metadata(&version);
 
     if let Some(content) = json.get("content").and_then(|c| c.as_array()) {
-        for element in content {
-            if let Some(obj) = element.as_object() {
+        for (i, element) in content.iter().enumerate() {
+            read_block(&mut model, element, &format!("/content/{}", i), warnings)?;
+        }
+    }
+    Ok(model.assemble_model())
+}
 
-                let mut attributes: BTreeMap<String, String> = BTreeMap::new();
+/// Reads an object of the root content, or of a sidebar. Text and inline objects such as
+/// verses cannot be read outside of a paragraph: they are left out with a warning.
+fn read_block<T:AosjModel>(model: &mut T, element: &Value, path: &str, warnings: &mut Vec<Warning>) -> Result<(), HallomaiError> {
+    let Some(obj) = element.as_object() else {
+        warn(warnings, format!("Expected an object, found {}; it is left out", element), path);
+        return Ok(());
+    };
+    let attributes = read_attributes(obj, path, warnings)?;
+    let tag_name = read_type(obj, path)?;
+    model.push_element(attributes, tag_name.to_string());
 
-                for (key, value) in obj.iter() {
-                    if key != "content" && key != "type" {
-                        attributes.insert(key.to_string(), value.as_str().unwrap().parse().unwrap());
-                    }
+    match tag_name {
+        "book" => {
+            model.start_book(model.get_attributes());
+            if let Some(contents) = obj.get("content").and_then(|c| c.as_array()) {
+                for (j, object) in contents.iter().enumerate() {
+                    read_content(model, object, &format!("{}/content/{}", path, j), warnings)?;
+                    model.parent_els().pop();
                 }
-                let tag_name = obj.get("type").unwrap().to_string();
-                model.push_element(attributes, tag_name);
-
-                match obj.get("type").and_then(|t| t.as_str()) {
-                    Some("book") => {
-                        model.start_book(model.get_attributes());
-                        if let Some(contents) = obj.get("content").and_then(|c| c.as_array()) {
-                            for object in contents {
-                                read_content(&mut model, object);
-                                model.parent_els().pop();
-                            }
-                        }
-                        model.end_book();
-                    }
-                    Some("chapter") => {
-                        model.add_chapter(model.get_attributes());
-                    }
-                    Some("para") => {
-                        model.start_new_para(model.get_attributes());
-                        if let Some(contents) = obj.get("content").and_then(|c| c.as_array()) {
-                            for object in contents {
-                                read_content(&mut model, object);
-                            }
-                        }
-                        model.parent_els().pop();
-                        model.end_new_para();
-                    }
-                    _ => {}
+            }
+            model.end_book();
+        }
+        "chapter" => {
+            model.add_chapter(model.get_attributes());
+        }
+        "para" => {
+            model.start_new_para(model.get_attributes());
+            read_children(model, obj, path, warnings)?;
+            model.parent_els().pop();
+            model.end_new_para();
+        }
+        "table" => {
+            model.start_table(model.get_attributes());
+            read_table(model, obj, path, warnings)?;
+            model.parent_els().pop();
+            model.end_table();
+        }
+        "sidebar" => {
+            model.start_sidebar(model.get_attributes());
+            if let Some(contents) = obj.get("content").and_then(|c| c.as_array()) {
+                for (j, object) in contents.iter().enumerate() {
+                    read_block(model, object, &format!("{}/content/{}", path, j), warnings)?;
+                }
+            }
+            model.parent_els().pop();
+            model.end_sidebar();
+        }
+        "verse" | "ms" | "char" | "note" | "figure" | "ref" | "optbreak" | "whitespace" => {
+            warn(warnings, format!("'{}' outside of a paragraph is left out", tag_name), path);
+            model.parent_els().pop();
+        }
+        _ => {
+            warn(warnings, format!("Unknown type '{}', only its content is kept", tag_name), path);
+            model.parent_els().pop();
+            if let Some(contents) = obj.get("content").and_then(|c| c.as_array()) {
+                for (j, object) in contents.iter().enumerate() {
+                    read_block(model, object, &format!("{}/content/{}", path, j), warnings)?;
                 }
             }
-
         }
     }
-    model.assemble_model()
+    Ok(())
 }
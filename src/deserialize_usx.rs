@@ -2,9 +2,9 @@
 
 use std::collections::BTreeMap;
 use std::io::{BufReader, Cursor};
-use quick_xml::events::Event;
+use quick_xml::events::{BytesStart, Event};
 use quick_xml::Reader;
-use regex::Regex;
+use crate::error::{HallomaiError, SourceLocation, Warning};
 use crate::model_traits::AosjModel;
 
 
@@ -14,7 +14,10 @@ use crate::model_traits::AosjModel;
 /// content and reconstructing it into a model that implements the `AosjModel`
 /// trait. It handles different types of XML events such as start tags, end tags,
 /// empty elements, and text nodes.
-pub fn deserialize_from_file_usx<T:AosjModel>(input_string: String) -> String {
+///
+/// The USX 3 end milestones `<verse eid="..."/>` and `<chapter eid="..."/>` are
+/// checked against the open verse and chapter; a mismatch is reported in `warnings`.
+pub fn deserialize_from_file_usx<T:AosjModel>(mut model: T, input_string: String, warnings: &mut Vec<Warning>) -> Result<T::Output, HallomaiError> {
 
     let input_bytes = input_string.as_bytes();
     let cursor = Cursor::new(input_bytes);
@@ -26,8 +29,9 @@ pub fn deserialize_from_file_usx<T:AosjModel>(input_string: String) -> String {
     let mut buf = Vec::new();
     let mut txt = Vec::new();
 
-    let values = Regex::new(r#"(["\\])"#).unwrap();
-    let mut model = T::new();
+    // The sid of the open chapter and verse, to be matched by their eid
+    let mut open_chapter: Option<String> = None;
+    let mut open_verse: Option<String> = None;
 
     loop {
         match reader.read_event_into(&mut buf) {
@@ -35,16 +39,9 @@ pub fn deserialize_from_file_usx<T:AosjModel>(input_string: String) -> String {
                 model.add_string_to_in_para(
                     &mut txt
                 );
-                let mut attributes: BTreeMap<String, String> = BTreeMap::new();
-
-                for att in el.attributes() {
-                    attributes.insert(
-                        String::from_utf8(att.clone().unwrap().key.local_name().as_ref().to_vec()).unwrap(),
-                        String::from_utf8(att.clone().unwrap().value.as_ref().to_vec()).unwrap()
-                    );
-                }
-
-                let tag_name = String::from_utf8(el.name().as_ref().to_vec()).unwrap();
+                let attributes = read_attributes(&el)
+                    .map_err(|message| xml_error(&input_string, reader.buffer_position(), message))?;
+                let tag_name = usj_type(String::from_utf8_lossy(el.name().as_ref()).to_string());
 
                 model.push_element(attributes, tag_name.clone());
 
@@ -52,7 +49,7 @@ pub fn deserialize_from_file_usx<T:AosjModel>(input_string: String) -> String {
 
                 if tag_name == "usx" {
                     model.add_root_metadata(
-                        current_parent.last().unwrap().attributes.get("version").unwrap(),
+                        current_parent.last().unwrap().attributes.get("version").map_or("", |v| v.as_str()),
                     );
                 } else if tag_name == "para" {
                     model.start_new_para(
@@ -71,6 +68,30 @@ pub fn deserialize_from_file_usx<T:AosjModel>(input_string: String) -> String {
                     model.start_add_note(
                         model.get_attributes()
                     )
+                } else if tag_name == "table" {
+                    model.start_table(
+                        model.get_attributes()
+                    )
+                } else if tag_name == "table:row" {
+                    model.start_row(
+                        model.get_attributes()
+                    )
+                } else if tag_name == "table:cell" {
+                    model.start_cell(
+                        model.get_attributes()
+                    )
+                } else if tag_name == "figure" {
+                    model.start_figure(
+                        model.get_attributes()
+                    )
+                } else if tag_name == "ref" {
+                    model.start_ref(
+                        model.get_attributes()
+                    )
+                } else if tag_name == "sidebar" {
+                    model.start_sidebar(
+                        model.get_attributes()
+                    )
                 }
             }
 
@@ -78,45 +99,65 @@ pub fn deserialize_from_file_usx<T:AosjModel>(input_string: String) -> String {
                 clean_whitespace(&mut txt);
 
                 model.add_string_to_in_para(&mut txt);
-                let mut attributes: BTreeMap<String, String> = BTreeMap::new();
-                for att in el.attributes() {
-                    attributes.insert(
-                        String::from_utf8(att.clone().unwrap().key.local_name().as_ref().to_vec()).unwrap(),
-                        String::from_utf8(att.clone().unwrap().value.as_ref().to_vec()).unwrap()
-                    );
-                }
-                let tag_name = String::from_utf8(el.name().as_ref().to_vec()).unwrap();
+                let attributes = read_attributes(&el)
+                    .map_err(|message| xml_error(&input_string, reader.buffer_position(), message))?;
+                let tag_name = String::from_utf8_lossy(el.name().as_ref()).to_string();
 
+                let eid = attributes.get("eid").cloned();
+                let sid = attributes.get("sid").cloned();
                 model.push_element(attributes, tag_name.clone());
 
-                if tag_name == "verse" && !model.parent_els().last().unwrap().attributes.contains_key("eid") {
+                if let Some(eid) = eid.filter(|_| tag_name == "verse" || tag_name == "chapter") {
+                    let open = if tag_name == "verse" { open_verse.take() } else { open_chapter.take() };
+                    if open.as_ref() != Some(&eid) {
+                        let message = match open {
+                            Some(open) => format!("End milestone {} eid=\"{}\" does not match the open {} {}", tag_name, eid, tag_name, open),
+                            None => format!("End milestone {} eid=\"{}\" without an open {}", tag_name, eid, tag_name),
+                        };
+                        let position = reader.buffer_position() as usize;
+                        warnings.push(Warning::new(message, SourceLocation::from_offset(&input_string, position)));
+                    }
+                } else if tag_name == "verse" {
+                    open_verse = sid;
                     model.add_verse_to_in_para(
                         model.get_attributes()
                     );
-                } else if tag_name == "chapter" && !model.parent_els().last().unwrap().attributes.contains_key("eid") {
+                } else if tag_name == "chapter" {
+                    open_verse = None;
+                    open_chapter = sid;
                     model.add_chapter(
                         model.get_attributes()
                     );
-                } else if tag_name == "ms" && !model.parent_els().last().unwrap().attributes.contains_key("eid") {
+                } else if tag_name == "ms" {
 
                     model.add_milestone(
                         model.get_attributes()
                     )
-                } else if tag_name == "ms" && model.parent_els().last().unwrap().attributes.contains_key("eid") {
-
-                    model.add_milestone(
+                } else if tag_name == "optbreak" {
+                    model.add_optbreak(
                         model.get_attributes()
                     )
+                } else if tag_name == "book" {
+                    model.start_book(
+                        model.get_attributes()
+                    );
+                    model.end_book()
+                } else if tag_name == "para" {
+                    model.start_new_para(
+                        model.get_attributes()
+                    );
+                    model.end_new_para()
                 }
 
                 model.parent_els().pop();
             }
 
             Ok(Event::Text(el)) => {
-                let value = el.unescape().unwrap().into_owned();
-                let new_value = values.replace_all(value.as_str(), "\\$1");
+                let value = el.unescape()
+                    .map_err(|err| xml_error(&input_string, reader.buffer_position(), err.to_string()))?
+                    .into_owned();
                 if model.parent_els().len()>1 {
-                    txt.push(new_value.to_string());
+                    txt.push(value);
                 }
             }
 
@@ -144,15 +185,33 @@ pub fn deserialize_from_file_usx<T:AosjModel>(input_string: String) -> String {
                     model.end_add_note(
                         &mut txt
                     )
+                } else if tag_name == "table" {
+                    model.end_table()
+                } else if tag_name == "table:row" {
+                    model.end_row()
+                } else if tag_name == "table:cell" {
+                    model.end_cell(
+                        &mut txt
+                    )
+                } else if tag_name == "figure" {
+                    model.end_figure(
+                        &mut txt
+                    )
+                } else if tag_name == "ref" {
+                    model.end_ref(
+                        &mut txt
+                    )
+                } else if tag_name == "sidebar" {
+                    model.end_sidebar()
                 }
                 model.parent_els().pop();
             }
 
             Ok(Event::Eof) => {
-                return model.assemble_model();
+                return Ok(model.assemble_model());
             }
             Err(err) => {
-                panic!("Error reading XML: {}", err);
+                return Err(xml_error(&input_string, reader.error_position(), err.to_string()));
             }
             _ => {}
         }
@@ -161,9 +220,37 @@ pub fn deserialize_from_file_usx<T:AosjModel>(input_string: String) -> String {
 }
 
 
-fn clean_whitespace(txt: &mut Vec<String>) {
+/// USX `<row>` and `<cell>` are `table:row` and `table:cell` in USJ.
+fn usj_type(tag_name: String) -> String {
+    match tag_name.as_str() {
+        "row" | "cell" => format!("table:{}", tag_name),
+        _ => tag_name,
+    }
+}
+
+fn clean_whitespace(txt: &mut [String]) {
     for i in txt.iter_mut() {
         *i = i.replace("\n", " ").replace("\t", " ").trim().to_string();
         *i = i.split_whitespace().collect::<Vec<&str>>().join(" ");
     }
 }
+
+fn read_attributes(el: &BytesStart) -> Result<BTreeMap<String, String>, String> {
+    let mut attributes: BTreeMap<String, String> = BTreeMap::new();
+    for att in el.attributes() {
+        let att = att.map_err(|err| err.to_string())?;
+        let value = att.unescape_value().map_err(|err| err.to_string())?;
+        attributes.insert(
+            String::from_utf8_lossy(att.key.local_name().as_ref()).to_string(),
+            value.into_owned(),
+        );
+    }
+    Ok(attributes)
+}
+
+fn xml_error(input: &str, position: u64, message: String) -> HallomaiError {
+    HallomaiError::Xml {
+        message,
+        location: SourceLocation::from_offset(input, position as usize),
+    }
+}
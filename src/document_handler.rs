@@ -0,0 +1,380 @@
+#![deny(missing_docs)]
+
+use std::collections::BTreeMap;
+use crate::aosj_string::element::Element;
+use crate::model_traits::AosjModel;
+use crate::options::MarkerLevels;
+use crate::structs_model::VerseRange;
+use crate::utils_usfm;
+
+/// # Receives a document as a stream of events, while it is being read.
+///
+/// `read_with_handler` calls these methods in document order for USFM, USX and USJ alike,
+/// without building the document tree, so a document can be indexed, counted or converted
+/// to a custom format without holding its model. The input is still kept whole, with the
+/// objects open at each event, and USFM is split into all its tokens before the first
+/// event. Every method does nothing by default; implement those you need.
+///
+/// Markers and attributes use their USJ names: `marker` for the USX `style`, and the
+/// `attributes` of an event are those not passed as their own parameter. The events
+/// follow what the typed model keeps: objects that cannot appear where they are read,
+/// like a verse directly in a table row, are left out with their content.
+pub trait DocumentHandler {
+    /// The USJ/USX version stated by the document, from `\usfm` in USFM.
+    fn version(&mut self, _version: &str) {}
+    /// The start of the `\id` line, whose text comes next.
+    fn start_book(&mut self, _code: &str, _attributes: &BTreeMap<String, String>) {}
+    /// The end of the `\id` line.
+    fn end_book(&mut self) {}
+    /// A chapter, with its `sid` such as `GEN 1`.
+    fn chapter(&mut self, _number: &str, _sid: &str, _attributes: &BTreeMap<String, String>) {}
+    /// The start of a paragraph, e.g. `p` or `q1`.
+    fn start_para(&mut self, _marker: &str, _attributes: &BTreeMap<String, String>) {}
+    /// The end of the current paragraph.
+    fn end_para(&mut self) {}
+    /// A verse, with its `sid` such as `GEN 1:3-4`.
+    fn verse(&mut self, _number: &str, _sid: &str, _attributes: &BTreeMap<String, String>) {}
+    /// Text of the current object.
+    fn text(&mut self, _text: &str) {}
+    /// The start of a character marker, e.g. `w` or `+nd`.
+    fn start_char(&mut self, _marker: &str, _attributes: &BTreeMap<String, String>) {}
+    /// The attributes of the current character marker that come after its text, as in
+    /// USFM `\w word|lemma="x"\w*`. USX and USJ give them to `start_char` instead.
+    fn char_attributes(&mut self, _attributes: &BTreeMap<String, String>) {}
+    /// The end of the current character marker.
+    fn end_char(&mut self) {}
+    /// The start of a note, e.g. `f` with the caller `+`.
+    fn start_note(&mut self, _marker: &str, _caller: Option<&str>, _attributes: &BTreeMap<String, String>) {}
+    /// The end of the current note.
+    fn end_note(&mut self) {}
+    /// A milestone, e.g. `qt-s` or `zaln-e`.
+    fn milestone(&mut self, _marker: &str, _attributes: &BTreeMap<String, String>) {}
+    /// An optional line break, `//` in USFM.
+    fn optbreak(&mut self) {}
+    /// The start of a scripture reference, whose text comes next.
+    fn start_ref(&mut self, _attributes: &BTreeMap<String, String>) {}
+    /// The end of the current scripture reference.
+    fn end_ref(&mut self) {}
+    /// The start of a figure, whose caption comes next.
+    fn start_figure(&mut self, _marker: &str, _attributes: &BTreeMap<String, String>) {}
+    /// The end of the current figure.
+    fn end_figure(&mut self) {}
+    /// The start of a table.
+    fn start_table(&mut self, _attributes: &BTreeMap<String, String>) {}
+    /// The end of the current table.
+    fn end_table(&mut self) {}
+    /// The start of a row of the current table, `tr` in USFM.
+    fn start_row(&mut self, _marker: &str, _attributes: &BTreeMap<String, String>) {}
+    /// The end of the current row.
+    fn end_row(&mut self) {}
+    /// The start of a cell of the current row, e.g. `th1` or `tcr2`.
+    fn start_cell(&mut self, _marker: &str, _attributes: &BTreeMap<String, String>) {}
+    /// The end of the current cell.
+    fn end_cell(&mut self) {}
+    /// The start of a sidebar, whose paragraphs and tables come next.
+    fn start_sidebar(&mut self, _marker: &str, _attributes: &BTreeMap<String, String>) {}
+    /// The end of the current sidebar.
+    fn end_sidebar(&mut self) {}
+}
+
+/// The kinds of objects that stay open while their content is read.
+#[derive(Clone, Copy, PartialEq)]
+enum OpenKind {
+    Book,
+    Para,
+    Char,
+    Note,
+    Figure,
+    Ref,
+    Table,
+    Row,
+    Cell,
+    Sidebar,
+}
+
+/// An open object, and whether the typed model would keep it.
+struct Open {
+    kind: OpenKind,
+    kept: bool,
+}
+
+/// # Turns the callbacks of the readers into `DocumentHandler` events.
+///
+/// The open objects are tracked like the `stack` of the typed model, so that the
+/// events describe the same document as the `Root` read from the same input.
+pub(crate) struct HandlerModel<'a, H: DocumentHandler> {
+    handler: &'a mut H,
+    parent_els: Vec<Element>,
+    stack: Vec<Open>,
+    marker_levels: MarkerLevels,
+    book_code: String,
+    chapter_number: String,
+}
+
+impl<'a, H: DocumentHandler> HandlerModel<'a, H> {
+    pub fn new(handler: &'a mut H, marker_levels: MarkerLevels) -> Self {
+        HandlerModel {
+            handler,
+            parent_els: Vec::new(),
+            stack: Vec::new(),
+            marker_levels,
+            book_code: String::new(),
+            chapter_number: String::new(),
+        }
+    }
+
+    /// Returns the attributes of the current element by their USJ name, and its marker.
+    fn current_attributes(&self) -> (String, BTreeMap<String, String>) {
+        let mut attributes = self.parent_els.last().map(Element::usj_attribute_map).unwrap_or_default();
+        let marker = attributes.remove("marker").unwrap_or_default();
+        (marker, attributes)
+    }
+
+    /// Whether the innermost open object keeps an object of `kind`, or text when `kind` is `None`.
+    fn accepts(&self, kind: Option<OpenKind>) -> bool {
+        let parent = match self.stack.last() {
+            Some(parent) if parent.kept => parent.kind,
+            _ => return false,
+        };
+        match kind {
+            None => !matches!(parent, OpenKind::Table | OpenKind::Row | OpenKind::Sidebar),
+            Some(OpenKind::Row) => parent == OpenKind::Table,
+            Some(OpenKind::Cell) => parent == OpenKind::Row,
+            Some(_) => matches!(parent, OpenKind::Para | OpenKind::Char | OpenKind::Note | OpenKind::Cell),
+        }
+    }
+
+    /// Opens an object, returning whether its start event is to be sent.
+    fn open(&mut self, kind: OpenKind) -> bool {
+        let kept = match kind {
+            OpenKind::Book | OpenKind::Para | OpenKind::Table | OpenKind::Sidebar => true,
+            _ => self.accepts(Some(kind)),
+        };
+        self.stack.push(Open { kind, kept });
+        kept
+    }
+
+    /// Sends the text read so far, if the current object keeps text.
+    fn flush_text(&mut self, txt: &mut Vec<String>) {
+        if !txt.is_empty() {
+            if self.accepts(None) {
+                self.handler.text(&txt.join(""));
+            }
+            txt.clear();
+        }
+    }
+
+    /// Closes the current block-level object and everything opened inside it,
+    /// leaving the sidebars it belongs to open.
+    fn close_block(&mut self) {
+        while self.stack.last().is_some_and(|object| object.kind != OpenKind::Sidebar) {
+            self.close_last();
+        }
+    }
+
+    /// Closes the innermost open object.
+    fn close_last(&mut self) {
+        let Some(object) = self.stack.pop() else {
+            return;
+        };
+        if !object.kept {
+            return;
+        }
+        match object.kind {
+            OpenKind::Book => self.handler.end_book(),
+            OpenKind::Para => self.handler.end_para(),
+            OpenKind::Char => self.handler.end_char(),
+            OpenKind::Note => self.handler.end_note(),
+            OpenKind::Figure => self.handler.end_figure(),
+            OpenKind::Ref => self.handler.end_ref(),
+            OpenKind::Table => self.handler.end_table(),
+            OpenKind::Row => self.handler.end_row(),
+            OpenKind::Cell => self.handler.end_cell(),
+            OpenKind::Sidebar => self.handler.end_sidebar(),
+        }
+    }
+}
+
+impl<H: DocumentHandler> AosjModel for HandlerModel<'_, H> {
+    type Output = ();
+
+    fn push_element(&mut self, attributes: BTreeMap<String, String>, tag_name: String) {
+        self.parent_els.push(Element {
+            tag_name,
+            attributes,
+        });
+    }
+
+    fn get_attributes(&self) -> String {
+        String::new()
+    }
+
+    fn add_root_metadata(&mut self, version_value: &str) {
+        self.handler.version(version_value);
+    }
+
+    fn start_book(&mut self, _attributes: String) {
+        let (_, mut attributes) = self.current_attributes();
+        self.book_code = attributes.remove("code").unwrap_or_default();
+        self.open(OpenKind::Book);
+        self.handler.start_book(&self.book_code, &attributes);
+    }
+
+    fn end_book(&mut self) {
+        self.close_block();
+    }
+
+    fn start_new_para(&mut self, _attributes: String) {
+        let (marker, attributes) = self.current_attributes();
+        self.open(OpenKind::Para);
+        self.handler.start_para(&utils_usfm::normalize_level(&marker, self.marker_levels), &attributes);
+    }
+
+    fn end_new_para(&mut self) {
+        self.close_block();
+    }
+
+    fn add_string_to_in_para(&mut self, txt: &mut Vec<String>) {
+        self.flush_text(txt);
+    }
+
+    fn add_chapter(&mut self, _attributes: String) {
+        let (_, mut attributes) = self.current_attributes();
+        self.chapter_number = attributes.remove("number").unwrap_or_default();
+        let sid = format!("{} {}", self.book_code, self.chapter_number);
+        self.handler.chapter(&self.chapter_number, &sid, &attributes);
+    }
+
+    fn add_verse_to_in_para(&mut self, _attributes: String) {
+        let (_, mut attributes) = self.current_attributes();
+        if self.accepts(Some(OpenKind::Para)) {
+            let number = attributes.remove("number").unwrap_or_default();
+            let range = number.parse::<VerseRange>().map_or_else(|_| number.clone(), |range| range.sid_number());
+            let sid = format!("{} {}:{}", self.book_code, self.chapter_number, range);
+            self.handler.verse(&number, &sid, &attributes);
+        }
+    }
+
+    fn add_milestone(&mut self, _attributes: String) {
+        let (marker, attributes) = self.current_attributes();
+        if self.accepts(Some(OpenKind::Para)) {
+            self.handler.milestone(&marker, &attributes);
+        }
+    }
+
+    fn start_add_char_marker(&mut self, _attributes: String) {
+        let (marker, attributes) = self.current_attributes();
+        if self.open(OpenKind::Char) {
+            self.handler.start_char(&utils_usfm::normalize_level(&marker, self.marker_levels), &attributes);
+        }
+    }
+
+    fn add_char_attributes(&mut self, _attributes: String) {
+        let (_, attributes) = self.current_attributes();
+        if self.stack.last().is_some_and(|object| object.kind == OpenKind::Char && object.kept) {
+            self.handler.char_attributes(&attributes);
+        }
+    }
+
+    fn end_add_char_marker(&mut self, txt: &mut Vec<String>) {
+        self.flush_text(txt);
+        self.close_last();
+    }
+
+    fn start_add_note(&mut self, _attributes: String) {
+        let (marker, mut attributes) = self.current_attributes();
+        let caller = attributes.remove("caller");
+        if self.open(OpenKind::Note) {
+            self.handler.start_note(&marker, caller.as_deref(), &attributes);
+        }
+    }
+
+    fn end_add_note(&mut self, txt: &mut Vec<String>) {
+        self.flush_text(txt);
+        self.close_last();
+    }
+
+    fn add_optbreak(&mut self, _attributes: String) {
+        if self.accepts(Some(OpenKind::Para)) {
+            self.handler.optbreak();
+        }
+    }
+
+    fn start_ref(&mut self, _attributes: String) {
+        let (_, attributes) = self.current_attributes();
+        if self.open(OpenKind::Ref) {
+            self.handler.start_ref(&attributes);
+        }
+    }
+
+    fn end_ref(&mut self, txt: &mut Vec<String>) {
+        self.flush_text(txt);
+        self.close_last();
+    }
+
+    fn start_figure(&mut self, _attributes: String) {
+        let (marker, attributes) = self.current_attributes();
+        if self.open(OpenKind::Figure) {
+            self.handler.start_figure(&marker, &attributes);
+        }
+    }
+
+    fn end_figure(&mut self, txt: &mut Vec<String>) {
+        self.flush_text(txt);
+        self.close_last();
+    }
+
+    fn start_table(&mut self, _attributes: String) {
+        let (_, attributes) = self.current_attributes();
+        self.open(OpenKind::Table);
+        self.handler.start_table(&attributes);
+    }
+
+    fn end_table(&mut self) {
+        self.close_block();
+    }
+
+    fn start_row(&mut self, _attributes: String) {
+        let (marker, attributes) = self.current_attributes();
+        if self.open(OpenKind::Row) {
+            self.handler.start_row(&marker, &attributes);
+        }
+    }
+
+    fn end_row(&mut self) {
+        self.close_last();
+    }
+
+    fn start_cell(&mut self, _attributes: String) {
+        let (marker, attributes) = self.current_attributes();
+        if self.open(OpenKind::Cell) {
+            self.handler.start_cell(&marker, &attributes);
+        }
+    }
+
+    fn end_cell(&mut self, txt: &mut Vec<String>) {
+        self.flush_text(txt);
+        self.close_last();
+    }
+
+    fn start_sidebar(&mut self, _attributes: String) {
+        let (marker, attributes) = self.current_attributes();
+        self.open(OpenKind::Sidebar);
+        self.handler.start_sidebar(&marker, &attributes);
+    }
+
+    fn end_sidebar(&mut self) {
+        self.close_block();
+        self.close_last();
+    }
+
+    fn assemble_model(mut self) {
+        while !self.stack.is_empty() {
+            self.close_last();
+        }
+    }
+
+    fn parent_els(&mut self) -> &mut Vec<Element> {
+        &mut self.parent_els
+    }
+}
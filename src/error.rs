@@ -0,0 +1,157 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+/// # A position in the input document.
+///
+/// `line` and `column` start at 1, `column` counts characters and `offset`
+/// is the byte offset from the start of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SourceLocation {
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+}
+
+impl SourceLocation {
+    /// Computes the line and column of a byte offset in `text`.
+    pub fn from_offset(text: &str, offset: usize) -> Self {
+        let offset = offset.min(text.len());
+        let before = &text[..floor_char_boundary(text, offset)];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        SourceLocation {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            offset,
+        }
+    }
+
+    /// Computes the byte offset of a line and column in `text`.
+    pub fn from_line_column(text: &str, line: usize, column: usize) -> Self {
+        let line_start: usize = text.split_inclusive('\n').take(line.saturating_sub(1)).map(str::len).sum();
+        let column_offset: usize = text[line_start..].chars().take(column.saturating_sub(1)).map(char::len_utf8).sum();
+        SourceLocation {
+            line,
+            column,
+            offset: line_start + column_offset,
+        }
+    }
+}
+
+fn floor_char_boundary(text: &str, mut offset: usize) -> usize {
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} col {}", self.line, self.column)
+    }
+}
+
+/// # Everything that can go wrong while converting a document.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HallomaiError {
+    /// A USFM marker that is not in the specification.
+    UnknownMarker { marker: String, location: SourceLocation },
+    /// Malformed USX.
+    Xml { message: String, location: SourceLocation },
+    /// Malformed JSON.
+    Json { message: String, location: SourceLocation },
+    /// Well-formed JSON that is not valid USJ; `path` is a JSON pointer.
+    Schema { message: String, path: String },
+    /// The input format is not one of `usfm`, `usx`, `usj` or `json`.
+    UnsupportedInputFormat(String),
+    /// The output format is not one of `usfm`, `usx`, `usj`, `json` or `txt`.
+    UnsupportedOutputFormat(String),
+    /// A file, directory or archive of books that could not be read.
+    Io { path: String, message: String },
+}
+
+impl HallomaiError {
+    pub(crate) fn json(text: &str, err: serde_json::Error) -> Self {
+        HallomaiError::Json {
+            message: err.to_string(),
+            location: SourceLocation::from_line_column(text, err.line(), err.column()),
+        }
+    }
+
+    pub(crate) fn io(path: &std::path::Path, err: impl Display) -> Self {
+        HallomaiError::Io {
+            path: path.display().to_string(),
+            message: err.to_string(),
+        }
+    }
+
+    pub(crate) fn schema(message: impl Into<String>, path: impl Into<String>) -> Self {
+        HallomaiError::Schema {
+            message: message.into(),
+            path: path.into(),
+        }
+    }
+}
+
+impl Display for HallomaiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HallomaiError::UnknownMarker { marker, location } => {
+                write!(f, "Unknown marker \\{} at {}", marker, location)
+            }
+            HallomaiError::Xml { message, location } => {
+                write!(f, "XML error at {}: {}", location, message)
+            }
+            HallomaiError::Json { message, location } => {
+                write!(f, "JSON error at {}: {}", location, message)
+            }
+            HallomaiError::Schema { message, path } => {
+                write!(f, "Invalid USJ at '{}': {}", path, message)
+            }
+            HallomaiError::UnsupportedInputFormat(format) => {
+                write!(f, "Unsupported input file format '{}'. Only 'usfm', 'usx', 'usj' and 'json' are supported.", format)
+            }
+            HallomaiError::UnsupportedOutputFormat(format) => {
+                write!(f, "Unsupported output file format '{}'. Only 'usfm', 'usx', 'usj', 'json' and 'txt' are supported.", format)
+            }
+            HallomaiError::Io { path, message } => {
+                write!(f, "Cannot read '{}': {}", path, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HallomaiError {}
+
+/// # A problem that did not stop the conversion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub message: String,
+    /// Where the problem is; USJ has no lines, so its warnings have the default location and
+    /// name the JSON pointer of the value in `message`.
+    pub location: SourceLocation,
+}
+
+impl Warning {
+    pub(crate) fn new(message: impl Into<String>, location: SourceLocation) -> Self {
+        Warning {
+            message: message.into(),
+            location,
+        }
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.location == SourceLocation::default() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{} at {}", self.message, self.location)
+        }
+    }
+}
+
+impl From<HallomaiError> for wasm_bindgen::JsValue {
+    fn from(err: HallomaiError) -> Self {
+        wasm_bindgen::JsValue::from_str(&err.to_string())
+    }
+}
@@ -0,0 +1,286 @@
+use unicode_general_category::{get_general_category, GeneralCategory};
+
+/// # One token of USFM, as read by `Lexer`.
+///
+/// `parts` holds the text the token was read from, then the parts of it the token is made of:
+/// the number of `\c 1`, the key and value of `key="value"`, or the name, level and column span
+/// of `\tc1-2`. Parts that are not there are empty.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Lexeme<'a> {
+    pub subclass: &'static str,
+    pub parts: Vec<&'a str>,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// # Splits USFM into tokens in a single pass.
+///
+/// At each position, the kinds of token are tried in the order below, and the first one found
+/// is the token; anything else is a single character of `punctuation` or `unknown` text, so
+/// every byte of the input belongs to exactly one token.
+///
+/// `chapter`, `pubchapter`, `verses`, `attribute`, `defaultAttribute`, `emptyMilestone`,
+/// `startMilestoneTag`, `endMilestoneTag`, `endTag`, `startTag`, `bareSlash`, `quote`, `eol`,
+/// `noBreakSpace`, `softLinebreak`, `wordLike`, `lineSpace`, `punctuation` and `unknown`.
+pub struct Lexer<'a> {
+    content: &'a str,
+    position: usize,
+}
+
+/// The longest run of characters read as a single `wordLike` or `lineSpace` token.
+const MAX_RUN: usize = 127;
+
+impl<'a> Lexer<'a> {
+    pub fn new(content: &'a str) -> Self {
+        Lexer { content, position: 0 }
+    }
+
+    fn lexeme(&self, subclass: &'static str, end: usize, parts: &[&'a str]) -> Lexeme<'a> {
+        let mut all_parts = Vec::with_capacity(parts.len() + 1);
+        all_parts.push(&self.content[self.position..end]);
+        all_parts.extend_from_slice(parts);
+        Lexeme { subclass, parts: all_parts, start: self.position, end }
+    }
+
+    /// The byte offset after the characters matching `f` from `from`, up to `max` of them.
+    fn skip_while(&self, from: usize, max: usize, f: impl Fn(char) -> bool) -> usize {
+        let rest = &self.content[from..];
+        let length = rest.char_indices()
+            .take(max)
+            .find(|&(_, c)| !f(c))
+            .map_or_else(|| rest.chars().take(max).map(char::len_utf8).sum(), |(i, _)| i);
+        from + length
+    }
+
+    /// The byte offset after the ASCII byte `b` at `at`, if it is there.
+    fn expect(&self, at: usize, b: u8) -> Option<usize> {
+        (self.content.as_bytes().get(at) == Some(&b)).then_some(at + 1)
+    }
+
+    fn next_lexeme(&self) -> Lexeme<'a> {
+        let bytes = self.content.as_bytes();
+        let start = self.position;
+        match bytes[start] {
+            b'\r' | b'\n' => self.chapter()
+                .or_else(|| self.pub_chapter())
+                .unwrap_or_else(|| self.eol().unwrap()),
+            b'\\' => self.chapter()
+                .or_else(|| self.pub_chapter())
+                .or_else(|| self.verses())
+                .or_else(|| self.milestone())
+                .or_else(|| self.tag())
+                .unwrap_or_else(|| self.lexeme("bareSlash", start + 1, &[])),
+            b'"' => self.lexeme("quote", start + 1, &[]),
+            b' ' | b'\t' | b'|' => self.attribute()
+                .or_else(|| self.default_attribute())
+                .or_else(|| self.eol())
+                .unwrap_or_else(|| self.text()),
+            b'~' => self.lexeme("noBreakSpace", start + 1, &[]),
+            b'/' if bytes.get(start + 1) == Some(&b'/') => self.lexeme("softLinebreak", start + 2, &[]),
+            b if b.is_ascii_alphanumeric() || b == b'-' => self.attribute().unwrap_or_else(|| self.text()),
+            _ => self.text(),
+        }
+    }
+
+    /// `\c 1`, along with the line breaks before it and the whitespace after it.
+    fn chapter(&self) -> Option<Lexeme<'a>> {
+        let marker = self.skip_while(self.position, usize::MAX, |c| c == '\r' || c == '\n');
+        let after_marker = self.expect(marker, b'\\').and_then(|at| self.expect(at, b'c'))?;
+        let number_start = self.skip_while(after_marker, usize::MAX, is_line_space);
+        let number_end = self.skip_while(number_start, usize::MAX, |c| c.is_ascii_digit());
+        if number_start == after_marker || number_end == number_start {
+            return None;
+        }
+        let end = self.skip_while(number_end, usize::MAX, is_space_or_eol);
+        Some(self.lexeme("chapter", end, &[&self.content[number_start..number_end]]))
+    }
+
+    /// `\cp A`, up to the end of the line.
+    fn pub_chapter(&self) -> Option<Lexeme<'a>> {
+        let marker = self.skip_while(self.position, usize::MAX, |c| c == '\r' || c == '\n');
+        let after_marker = self.expect(marker, b'\\')
+            .and_then(|at| self.expect(at, b'c'))
+            .and_then(|at| self.expect(at, b'p'))?;
+        let spaces_end = self.skip_while(after_marker, usize::MAX, is_line_space);
+        let line_end = self.skip_while(spaces_end, usize::MAX, |c| c != '\r' && c != '\n');
+        // With nothing else on the line, the last space is the publishing number
+        let number_start = match (spaces_end - after_marker, line_end > spaces_end) {
+            (0, _) => return None,
+            (_, true) => spaces_end,
+            (1, false) => return None,
+            (_, false) => spaces_end - 1,
+        };
+        let end = self.skip_while(line_end, usize::MAX, is_space_or_eol);
+        Some(self.lexeme("pubchapter", end, &[&self.content[number_start..line_end]]))
+    }
+
+    /// `\v 1` or `\v 1-2`, along with the whitespace after it.
+    fn verses(&self) -> Option<Lexeme<'a>> {
+        let after_marker = self.expect(self.position, b'\\').and_then(|at| self.expect(at, b'v'))?;
+        let number_start = self.skip_while(after_marker, usize::MAX, is_line_space);
+        let number_end = self.skip_while(number_start, usize::MAX, |c| c.is_alphanumeric() || is_combining_mark(c) || c == '_' || c == '-');
+        if number_start == after_marker || number_end == number_start {
+            return None;
+        }
+        let end = self.skip_while(number_end, usize::MAX, is_space_or_eol);
+        Some(self.lexeme("verses", end, &[&self.content[number_start..number_end]]))
+    }
+
+    /// `\ts\*`, `\qt-s`, `\qt-e` or the `\*` ending the attributes of a milestone.
+    fn milestone(&self) -> Option<Lexeme<'a>> {
+        let name_start = self.position + 1;
+        let name_end = self.skip_while(name_start, usize::MAX, |c| matches!(c, 'a'..='z' | '1'..='9'));
+        if name_end == name_start {
+            return self.expect(name_start, b'*').map(|end| self.lexeme("endMilestoneTag", end, &[]));
+        }
+        let name = &self.content[name_start..name_end];
+        if let Some(end) = self.expect(name_end, b'\\').and_then(|at| self.expect(at, b'*')) {
+            return Some(self.lexeme("emptyMilestone", end, &[name]));
+        }
+        let after_dash = self.expect(name_end, b'-')?;
+        let s_or_e = self.expect(after_dash, b's').or_else(|| self.expect(after_dash, b'e'))?;
+        Some(self.lexeme("startMilestoneTag", s_or_e, &[name, &self.content[after_dash..s_or_e]]))
+    }
+
+    /// `\p`, `\q1`, `\tc1-2` or `\+w*`: the name of the marker, its level and column span,
+    /// then `*` for an `endTag` or an optional space for a `startTag`.
+    fn tag(&self) -> Option<Lexeme<'a>> {
+        let name_start = self.position + 1;
+        let letters_start = self.expect(name_start, b'+').unwrap_or(name_start);
+        let longest_name = self.skip_while(letters_start, usize::MAX, |c| matches!(c, 'a'..='z' | '-'));
+        if longest_name == letters_start {
+            return None;
+        }
+        // An end tag may need a shorter name, as in `\tc-2*`, so each name is tried from the longest
+        for name_end in (letters_start + 1..=longest_name).rev() {
+            for (level_end, colspan) in self.level_and_colspan(name_end) {
+                if let Some(end) = self.expect(colspan.map_or(level_end, |(_, end)| end), b'*') {
+                    return Some(self.tag_lexeme("endTag", end, name_start..name_end, level_end, colspan));
+                }
+            }
+        }
+        let (level_end, colspan) = self.level_and_colspan(longest_name)[0];
+        let markup_end = colspan.map_or(level_end, |(_, end)| end);
+        let end = self.skip_while(markup_end, 1, is_line_space);
+        Some(self.tag_lexeme("startTag", end, name_start..longest_name, level_end, colspan))
+    }
+
+    /// The ways the level of a marker and its column span can be read after `at`, longest first:
+    /// the end of the level, and the start and end of the column span.
+    fn level_and_colspan(&self, at: usize) -> Vec<(usize, Option<(usize, usize)>)> {
+        let is_level = |i: usize| matches!(self.content.as_bytes().get(i), Some(b'1'..=b'9'));
+        let colspan_after = |i: usize| (self.expect(i, b'-').is_some() && is_level(i + 1)).then_some((i, i + 2));
+        let mut ways = Vec::with_capacity(4);
+        if is_level(at) {
+            ways.extend(colspan_after(at + 1).map(|colspan| (at + 1, Some(colspan))));
+            ways.push((at + 1, None));
+        }
+        ways.extend(colspan_after(at).map(|colspan| (at, Some(colspan))));
+        ways.push((at, None));
+        ways
+    }
+
+    fn tag_lexeme(&self, subclass: &'static str, end: usize, name: std::ops::Range<usize>, level_end: usize, colspan: Option<(usize, usize)>) -> Lexeme<'a> {
+        let level = &self.content[name.end..colspan.map_or(level_end, |(_, end)| end)];
+        let colspan = colspan.map_or("", |(start, end)| &self.content[start + 1..end]);
+        self.lexeme(subclass, end, &[&self.content[name], level, colspan])
+    }
+
+    /// `key="value"`, with the `|` and spaces before it and a space after it.
+    fn attribute(&self) -> Option<Lexeme<'a>> {
+        let bytes = self.content.as_bytes();
+        let mut key_start = self.skip_while(self.position, usize::MAX, is_line_space);
+        if let Some(after_bar) = self.expect(key_start, b'|') {
+            key_start = self.skip_while(after_bar, usize::MAX, is_line_space);
+        }
+        let key_end = self.skip_while(key_start, usize::MAX, |c| c.is_ascii_alphanumeric() || c == '-');
+        if key_end == key_start {
+            return None;
+        }
+        let value_start = self.expect(key_end, b'=').and_then(|at| self.expect(at, b'"'))?;
+        let mut value_end = value_start;
+        loop {
+            match bytes.get(value_end)? {
+                b'"' => break,
+                b'\\' if !matches!(bytes.get(value_end + 1), None | Some(b'\n')) => {
+                    value_end += 1 + self.content[value_end + 1..].chars().next().map_or(0, char::len_utf8);
+                }
+                b'\\' => return None,
+                _ => value_end += 1,
+            }
+        }
+        let end = self.skip_while(value_end + 1, 1, |c| c == ' ' || c == '\t');
+        Some(self.lexeme("attribute", end, &[&self.content[key_start..key_end], &self.content[value_start..value_end]]))
+    }
+
+    /// `|value`, up to the next `|` or marker.
+    fn default_attribute(&self) -> Option<Lexeme<'a>> {
+        let bar = self.skip_while(self.position, usize::MAX, is_line_space);
+        let value_start = self.skip_while(self.expect(bar, b'|')?, usize::MAX, is_line_space);
+        let end = self.skip_while(value_start, usize::MAX, |c| c != '|' && c != '\\');
+        Some(self.lexeme("defaultAttribute", end, &[&self.content[value_start..end]]))
+    }
+
+    /// Line breaks, with the spaces around them.
+    fn eol(&self) -> Option<Lexeme<'a>> {
+        let breaks_start = self.skip_while(self.position, usize::MAX, is_line_space);
+        let breaks_end = self.skip_while(breaks_start, usize::MAX, |c| c == '\r' || c == '\n');
+        if breaks_end == breaks_start {
+            return None;
+        }
+        let end = self.skip_while(breaks_end, usize::MAX, is_line_space);
+        Some(self.lexeme("eol", end, &[]))
+    }
+
+    /// A word, a run of spaces or a single other character.
+    fn text(&self) -> Lexeme<'a> {
+        let c = self.content[self.position..].chars().next().unwrap();
+        if is_word_char(c) {
+            self.lexeme("wordLike", self.skip_while(self.position, MAX_RUN, is_word_char), &[])
+        } else if is_space_separator(c) || c == '\t' {
+            self.lexeme("lineSpace", self.skip_while(self.position, MAX_RUN, |c| is_space_separator(c) || c == '\t'), &[])
+        } else if c.is_control() || c.is_whitespace() || matches!(c, '\u{200b}'..='\u{200f}' | '\u{feff}') {
+            self.lexeme("unknown", self.position + c.len_utf8(), &[])
+        } else {
+            self.lexeme("punctuation", self.position + c.len_utf8(), &[])
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Lexeme<'a>;
+
+    fn next(&mut self) -> Option<Lexeme<'a>> {
+        if self.position >= self.content.len() {
+            return None;
+        }
+        let lexeme = self.next_lexeme();
+        self.position = lexeme.end;
+        Some(lexeme)
+    }
+}
+
+fn is_line_space(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+fn is_space_or_eol(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\r' | '\n')
+}
+
+/// The characters of the Unicode `Zs` category.
+fn is_space_separator(c: char) -> bool {
+    get_general_category(c) == GeneralCategory::SpaceSeparator
+}
+
+/// Letters, numbers, word joiners and combining marks.
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '\u{2060}' || is_combining_mark(c)
+}
+
+/// Combining marks, such as Hebrew points and cantillation marks, which are not alphabetic
+/// on their own.
+fn is_combining_mark(c: char) -> bool {
+    matches!(get_general_category(c), GeneralCategory::NonspacingMark | GeneralCategory::SpacingMark | GeneralCategory::EnclosingMark)
+}
+
@@ -9,7 +9,16 @@
 //! - `element`: Defines the `Element` struct for representing XML elements.
 //! - `aosj_string_model`: Contains the main model `AosjStringModel` for processing USX documents.
 //! - `model_traits`: Defines the `AosjModel` trait for model operations.
+//! - `aosj_enum_model`: Implements `AosjModel` for `structs_model::model::Model`, which builds the typed tree.
 //! - `deserialize_usx`: Provides functions for reading and parsing USX files using SAX.
+//! - `lexer_usfm`: Splits USFM into the tokens read by `deserialize_usfm`, in a single pass.
+//! - `options`: `ParseOptions`, which tune how documents are read.
+//! - `validate_usj`: Checks USJ documents against the bundled USJ schema.
+//! - `document_handler`: The `DocumentHandler` trait, which receives a document as events while it is read.
+//! - `books`: Converts a set of books, read from a directory, a list of files or a zip archive.
+//! - `alignment`: Extracts, strips and applies the `\zaln-s` word alignments of aligned USFM.
+//! - `structs_model`: The typed document tree (`Root`, `Content`, `InParaObject`, ...) that
+//!   every reader produces and every writer consumes.
 //!
 
 #![allow(dead_code)]
@@ -17,29 +26,236 @@
 use wasm_bindgen::prelude::*;
 mod model_traits;
 mod aosj_string;
-mod structs_model;
-use aosj_string::aosj_string_model::AosjStringModel;
+pub mod structs_model;
+pub mod error;
+pub mod options;
 
 mod deserialize_usx;
 mod deserialize_usj;
 mod deserialize_usfm;
+mod lexer_usfm;
 mod reg_ex_tests;
 mod utils_usfm;
 mod aosj_enum_model;
 mod serialize_to_usj;
 mod serialize_to_usx;
 mod serialize_to_usfm;
+mod serialize_to_text;
+mod validate_usj;
+mod alignment;
+mod document_handler;
+mod books;
 
 use crate::deserialize_usfm::deserialize_from_file_usfm;
 use crate::deserialize_usj::deserialize_from_file_usj;
 use crate::deserialize_usx::deserialize_from_file_usx;
-// use crate::model_traits::AosjModel;
+use crate::document_handler::HandlerModel;
+use crate::model_traits::AosjModel;
+use crate::structs_model::model::Model;
+use crate::structs_model::root::Root;
+pub use crate::error::{HallomaiError, SourceLocation, Warning};
+pub use crate::options::{LineEnding, MarkerLevels, ParseOptions, UnknownMarkerPolicy, UsfmLayout};
+pub use crate::validate_usj::{validate_usj, ValidationIssue};
+pub use crate::document_handler::DocumentHandler;
+pub use crate::books::{combine_text, detect_format, format_from_extension, format_usfm_books, transform_books, BookResult, BookSource};
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::books::{read_book_files, read_books};
+pub use crate::alignment::{apply_alignments, extract_alignments, strip_alignments, Alignment, SourceWord, TargetWord, VerseAlignments};
 
 // include!("../tests/code/test_deserialize_usj.rs");
 // include!("../tests/code/test_deserialize_usx.rs");
 // include!("../tests/code/test_deserialize_usfm.rs");
 
 
+/// Reads a document into the typed model.
+///
+/// # Parameters
+/// - `input_file_content`: The content of the file.
+/// - `input_file_format`: One of `usfm`, `usx`, `json` or `usj`.
+///
+/// # Returns
+/// The `Root` of the document. Chapters and verses get their `sid` computed from the book code.
+///
+/// # Errors
+/// A `HallomaiError` if the format is unsupported or the content cannot be read.
+///
+/// # Example
+/// ```
+/// use hallomai::deserialize;
+/// use hallomai::structs_model::Content;
+///
+/// let root = deserialize("\\id TIT Titus\n\\h Titus\n\\c 1\n\\p\n\\v 1 Paul".to_string(), "usfm").unwrap();
+/// assert!(matches!(root.content[0], Content::Book(_)));
+/// ```
+pub fn deserialize(input_file_content: String, input_file_format: &str) -> Result<Root, HallomaiError> {
+    deserialize_with_warnings(input_file_content, input_file_format).map(|(root, _)| root)
+}
+
+/// Reads a document into the typed model, also returning what was read but looked wrong.
+///
+/// Each `Warning` carries the line and column it was found at, e.g. a USFM closing
+/// marker `\bd*` without its opening `\bd`.
+///
+/// # Example
+/// ```
+/// use hallomai::deserialize_with_warnings;
+///
+/// let (_, warnings) = deserialize_with_warnings("\\id TIT Titus\n\\p\n\\v 1 Paul\\bd*".to_string(), "usfm").unwrap();
+/// assert_eq!(warnings[0].to_string(), "Closing marker \\bd* without an opening marker at line 3 col 10");
+/// ```
+pub fn deserialize_with_warnings(input_file_content: String, input_file_format: &str) -> Result<(Root, Vec<Warning>), HallomaiError> {
+    deserialize_with_options(input_file_content, input_file_format, &ParseOptions::default())
+}
+
+/// Reads a document into the typed model with the given `ParseOptions`.
+///
+/// # Example
+/// ```
+/// use hallomai::{deserialize_with_options, ParseOptions};
+///
+/// let usfm = "\\id TIT Titus\n\\p\n\\v 1 Paul \\zsig an apostle\\zsig*";
+/// let (_, warnings) = deserialize_with_options(usfm.to_string(), "usfm", &ParseOptions::lenient()).unwrap();
+/// assert_eq!(warnings[0].message, "User-defined marker \\zsig read as char");
+/// ```
+pub fn deserialize_with_options(input_file_content: String, input_file_format: &str, options: &ParseOptions) -> Result<(Root, Vec<Warning>), HallomaiError> {
+    let mut warnings = Vec::new();
+    let mut root = read_into(Model::new(), input_file_content, input_file_format, options, &mut warnings)?;
+    if options.lossless && input_file_format == "usfm" {
+        serialize_to_usfm::keep_usfm_markup(&mut root);
+    }
+    root.normalize_marker_levels(options.marker_levels);
+    serialize_to_usj::add_sid_to_usj(&mut root);
+    Ok((root, warnings))
+}
+
+/// Reads a document, sending each of its objects to `handler` as it is read instead of
+/// building the typed model.
+///
+/// The events are the same whichever the input format, except for the USFM attributes
+/// written after the text of a character marker, which come in `DocumentHandler::char_attributes`.
+///
+/// # Returns
+/// What was read but looked wrong, as returned by `deserialize_with_options`.
+///
+/// # Example
+/// ```
+/// use hallomai::{read_with_handler, DocumentHandler, ParseOptions};
+/// use std::collections::BTreeMap;
+///
+/// #[derive(Default)]
+/// struct VerseCounter {
+///     verses: Vec<String>,
+/// }
+///
+/// impl DocumentHandler for VerseCounter {
+///     fn verse(&mut self, _number: &str, sid: &str, _attributes: &BTreeMap<String, String>) {
+///         self.verses.push(sid.to_string());
+///     }
+/// }
+///
+/// let mut counter = VerseCounter::default();
+/// let usfm = "\\id TIT Titus\n\\c 1\n\\p\n\\v 1 Paul\n\\v 2-3 in hope";
+/// read_with_handler(usfm.to_string(), "usfm", &ParseOptions::default(), &mut counter).unwrap();
+/// assert_eq!(counter.verses, ["TIT 1:1", "TIT 1:2-3"]);
+/// ```
+pub fn read_with_handler<H: DocumentHandler>(input_file_content: String, input_file_format: &str, options: &ParseOptions, handler: &mut H) -> Result<Vec<Warning>, HallomaiError> {
+    let mut warnings = Vec::new();
+    read_into(HandlerModel::new(handler, options.marker_levels), input_file_content, input_file_format, options, &mut warnings)?;
+    Ok(warnings)
+}
+
+/// Reads a document with the reader of its format into `model`.
+fn read_into<T: AosjModel>(model: T, input_file_content: String, input_file_format: &str, options: &ParseOptions, warnings: &mut Vec<Warning>) -> Result<T::Output, HallomaiError> {
+    match input_file_format {
+        "usx" => deserialize_from_file_usx(model, input_file_content, warnings),
+        "usfm" => deserialize_from_file_usfm(model, input_file_content, options, warnings),
+        "json" | "usj" => {
+            let json = serde_json::from_str(&input_file_content)
+                .map_err(|err| HallomaiError::json(&input_file_content, err))?;
+            if options.validate_usj {
+                if let Some(issue) = validate_usj(&json).into_iter().next() {
+                    return Err(HallomaiError::schema(issue.message, issue.path));
+                }
+            }
+            deserialize_from_file_usj(model, json, warnings)
+        }
+        _ => Err(HallomaiError::UnsupportedInputFormat(input_file_format.to_string())),
+    }
+}
+
+/// Writes the typed model in the given format.
+///
+/// # Parameters
+/// - `root`: The document to write.
+/// - `output_file_format`: One of `usfm`, `usx`, `json` or `usj`, or `txt` for plain text,
+///   which cannot be read back.
+///
+/// # Returns
+/// The serialized document, or `HallomaiError::UnsupportedOutputFormat`.
+pub fn serialize(root: &Root, output_file_format: &str) -> Result<String, HallomaiError> {
+    match output_file_format {
+        "json" | "usj" => Ok(serialize_to_usj::serialize_to_usj(root)),
+        "usfm" => Ok(serialize_to_usfm::serialize_to_usfm(root)),
+        "usx" => Ok(serialize_to_usx::serialize_to_usx(root)),
+        "txt" => Ok(serialize_to_text::serialize_to_text(root)),
+        _ => Err(HallomaiError::UnsupportedOutputFormat(output_file_format.to_string())),
+    }
+}
+
+/// Writes the typed model as canonical USFM: one paragraph marker per line, single spaces,
+/// quoted attributes and no trailing whitespace, laid out as asked by `layout`.
+///
+/// The markup kept by `ParseOptions::lossless` is ignored, so that two documents that only
+/// differ in their layout are formatted the same way.
+///
+/// # Example
+/// ```
+/// use hallomai::{deserialize, format_usfm, UsfmLayout};
+///
+/// let root = deserialize("\\id GEN  \n\\c 1\n\\p \\v 1 In   the\nbeginning \\v 2 God".to_string(), "usfm").unwrap();
+/// let layout = UsfmLayout { verse_on_new_line: false, ..UsfmLayout::default() };
+/// assert_eq!(format_usfm(&root, &layout), "\\id GEN\n\\c 1\n\\p \\v 1 In the beginning \\v 2 God\n");
+/// ```
+pub fn format_usfm(root: &Root, layout: &UsfmLayout) -> String {
+    serialize_to_usfm::format_usfm(root, layout)
+}
+
+/// Transforms a file from one format to another, reporting failures as a `HallomaiError`.
+///
+/// This is the fallible counterpart of `transform`: unknown markers, malformed XML or JSON,
+/// invalid USJ and unsupported formats are returned as errors carrying their location
+/// instead of panicking or being written into the output string.
+///
+/// # Example
+/// ```
+/// use hallomai::{try_transform, HallomaiError};
+///
+/// let err = try_transform("\\id TIT\n\\xyz oops".to_string(), "usfm".to_string(), "usx".to_string()).unwrap_err();
+/// assert!(matches!(err, HallomaiError::UnknownMarker { ref marker, .. } if marker == "xyz"));
+/// assert_eq!(err.to_string(), "Unknown marker \\xyz at line 2 col 1");
+/// ```
+#[wasm_bindgen(js_name = tryTransform)]
+pub fn try_transform(input_file_content: String, input_file_format: String, output_file_format: String) -> Result<String, HallomaiError> {
+    let model = deserialize(input_file_content, input_file_format.as_str())?;
+    serialize(&model, output_file_format.as_str())
+}
+
+/// Transforms a file from one format to another, reading it with the given `ParseOptions`.
+///
+/// # Example
+/// ```
+/// use hallomai::{try_transform_with_options, HallomaiError, ParseOptions};
+///
+/// let usj = r#"{"type": "USJ", "version": "3.0", "content": [{"type": "chapter", "marker": "c", "number": "one"}]}"#;
+/// let options = ParseOptions { validate_usj: true, ..ParseOptions::default() };
+/// let err = try_transform_with_options(usj.to_string(), "usj", "usx", &options).unwrap_err();
+/// assert_eq!(err.to_string(), "Invalid USJ at '/content/0/number': 'one' does not match the pattern ^[1-9][0-9]{0,2}(-[1-9][0-9]{0,2})?$");
+/// ```
+pub fn try_transform_with_options(input_file_content: String, input_file_format: &str, output_file_format: &str, options: &ParseOptions) -> Result<String, HallomaiError> {
+    let (model, _) = deserialize_with_options(input_file_content, input_file_format, options)?;
+    serialize(&model, output_file_format)
+}
+
 /// Transforms a USFM file into a different format (`.usfm`, `.usx`, or `.json`).
 ///
 /// # Parameters
@@ -50,11 +266,11 @@ use crate::deserialize_usx::deserialize_from_file_usx;
 /// A `String` of the desired output format. If the output format is unsupported,
 /// it returns an error message indicating that the output file format is not supported.
 ///
-/// # Panics
-/// The function may panic if there are issues during file serialization or deserialization processes.
+/// Any other failure is returned as the message of its `HallomaiError`; use `try_transform`
+/// to get the error itself.
 ///
 /// # Example
-/// ```
+/// ```js
 /// let result = transform_file_to("{YOUR FILE CONTENT}", "usx");
 /// console.log(result);
 /// ```
@@ -68,81 +284,807 @@ use crate::deserialize_usx::deserialize_from_file_usx;
 /// If an unsupported output file format is provided, the function will return an error message.
 #[wasm_bindgen]
 pub fn transform(input_file_content: String, input_file_format: String, output_file_format: String) -> String {
-    let model = match input_file_format.as_str() {
-        "usx" => deserialize_from_file_usx::<AosjStringModel>(input_file_content),
-        "usfm" => deserialize_from_file_usfm::<AosjStringModel>(input_file_content),
-        "json" | "usj" => deserialize_from_file_usj::<AosjStringModel>(serde_json::from_str(&input_file_content).unwrap()),
-        _ => return "Unsupported input file format. Only 'usfm', 'usx', and 'json' are supported.".to_string(),
-    };
-
-    match output_file_format.as_str() {
-        "json" | "usj" => serialize_to_usj::serialize_to_usj(model).to_string(),
-        "usfm" => {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            serialize_to_usfm::serialize_to_usfm(usj)
-        }
-        "usx" => {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            serialize_to_usx::serialize_to_usx(usj)
-        }
-        _ => "Unsupported output file format. Only 'usfm', 'usx', and 'json' are supported.".to_string(),
-    }
+    try_transform(input_file_content, input_file_format, output_file_format).unwrap_or_else(|err| err.to_string())
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::aosj_string::aosj_string_model::AosjStringModel;
+    use crate::structs_model::{Content, FigureObject, InParaObject, NoteObject, VerseObject, VerseRange};
+    use std::collections::BTreeMap;
 
-    const USFM_CONTENT: &str = r#"\\id PSA unfoldingWord Literal Text
-\\usfm 3.0
-\\ide UTF-8
-\\sts 2
-\\h Psalms
-\\toc1 The Book of Psalms
-\\toc2 Psalms
-\\toc3 Psa
-\\mt Psalms
-\\c 1
-\\s Here comes a psalm
-\\s2 See also all the other psalms
-\\q
-\\v 1 Blessed is the \\w man|Man\\w* who \\bd \\+it does not\\+it* walk\\bd* in the advice of the wicked,
-\\q or stand in the pathway with sinners,
-\\q or sit in the assembly of mockers.\\qs Selah\\qs* Amen
-\\ts\\*
-\\v 2 Beginning \\zaln-s |x-strong="G5043" x-lemma="τέκνον" x-morph="Gr,N,,,,,NNP," x-occurrence="1" x-occurrences="1" x-content="τέκνα"\\*\\w milestone |x-occurrence="1" x-occurrences="1"\\w*\\zaln-e\\*
+    const USFM_CONTENT: &str = r#"\id PSA unfoldingWord Literal Text
+\usfm 3.0
+\ide UTF-8
+\sts 2
+\h Psalms
+\toc1 The Book of Psalms
+\toc2 Psalms
+\toc3 Psa
+\mt Psalms
+\c 1
+\s Here comes a psalm
+\s2 See also all the other psalms
+\q
+\v 1 Blessed is the \w man|Man\w* who \bd \+it does not\+it* walk\bd* in the advice of the wicked,
+\q or stand in the pathway with sinners,
+\q or sit in the assembly of mockers.\qs Selah\qs* Amen
+\ts\*
+\v 2 Beginning \zaln-s |x-strong="G5043" x-lemma="τέκνον" x-morph="Gr,N,,,,,NNP," x-occurrence="1" x-occurrences="1" x-content="τέκνα"\*\w milestone |x-occurrence="1" x-occurrences="1"\w*\zaln-e\*
 "#;
 
     #[test]
     fn test_transform_usfm_to_json() {
         let output = transform(USFM_CONTENT.to_string(), "usfm".to_string(), "json".to_string());
-        assert!(output.contains("\"usfm\":"));
-        assert!(output.contains("\"toc1\": \"The Book of Psalms\""));
+        assert!(output.contains("\"version\":\"3.0\""));
+        assert!(output.contains("\"content\":[\"The Book of Psalms\"]"));
     }
 
     #[test]
     fn test_transform_usfm_to_usx() {
         let output = transform(USFM_CONTENT.to_string(), "usfm".to_string(), "usx".to_string());
-        assert!(output.contains("<usx>"));
-        assert!(output.contains("<toc1>The Book of Psalms</toc1>"));
+        assert!(output.contains("<usx version=\"3.0\">"));
+        assert!(output.contains(">The Book of Psalms</para>"));
     }
 
     #[test]
     fn test_transform_usfm_to_usfm() {
-        let output = transform(USFM_CONTENT.to_string(), "usfm".to_string(), "usfm".to_string());
+        let options = ParseOptions { lossless: true, ..ParseOptions::default() };
+        let output = try_transform_with_options(USFM_CONTENT.to_string(), "usfm", "usfm", &options).unwrap();
         assert_eq!(output, USFM_CONTENT);
+
+        for usfm in [
+            "\\id GEN\n\\c 1\n\\p\n\\v 1 \\w In|lemma=\"x\"  \\w* the\n",
+            "\\id GEN\n\\c 1\n\\p\n\\v 1 A \\fig Caption|a.jpg|col|||\\fig* b \\fig Desc |b.jpg|span||| The cap |1.2\\fig*\n",
+        ] {
+            let output = try_transform_with_options(usfm.to_string(), "usfm", "usfm", &options).unwrap();
+            assert_eq!(output, usfm);
+        }
+    }
+
+    #[test]
+    fn test_lossless_usfm_writes_changed_objects_the_usual_way() {
+        let usfm = "\\id GEN\n\\c 1\n\\p\n\\v 1  In  the beginning\n\\v 2 God\\f + \\ft created\\f*\n\\q2 the earth\n";
+        let options = ParseOptions { lossless: true, ..ParseOptions::default() };
+        let (mut root, _) = deserialize_with_options(usfm.to_string(), "usfm", &options).unwrap();
+        let Content::Para(para) = &mut root.content[2] else { panic!("expected a para") };
+        let Some(InParaObject::Verse(verse)) = para.content.as_mut().and_then(|c| c.get_mut(2)) else { panic!("expected a verse") };
+        verse.number = "3".to_string();
+        let Content::Para(para) = &mut root.content[3] else { panic!("expected a para") };
+        para.marker = "q1".to_string();
+        assert_eq!(serialize(&root, "usfm").unwrap(), "\\id GEN\n\\c 1\n\\p\n\\v 1  In  the beginning\n\\v 3 God\\f + \\ft created\\f*\n\\q1 the earth\n");
+
+        let (root, _) = deserialize_with_options(usfm.to_string(), "usfm", &ParseOptions::default()).unwrap();
+        assert_eq!(serialize(&root, "usfm").unwrap(), "\\id GEN\n\\c 1\n\\p\n\\v 1 In the beginning\n\\v 2 God\\f + \\ft created\\f*\n\\q2 the earth\n");
+    }
+
+    #[test]
+    fn test_format_usfm_layouts() {
+        let usfm = "\\id GEN \n\\c 1\n\\p \\v 1   In the\n beginning \\v 2 God \\w created|strong=\"H1254\"\\w*  the heavens\\qt-s |who=\"Me\"\\*\n\\tr \\tc1 A\\tc2 B\n";
+        let options = ParseOptions { lossless: true, ..ParseOptions::default() };
+        let (root, _) = deserialize_with_options(usfm.to_string(), "usfm", &options).unwrap();
+
+        assert_eq!(
+            format_usfm(&root, &UsfmLayout::default()),
+            "\\id GEN\n\\c 1\n\\p\n\\v 1 In the beginning\n\\v 2 God \\w created|strong=\"H1254\"\\w* the heavens\\qt-s |who=\"Me\"\\*\n\\tr \\tc1 A \\tc2 B\n"
+        );
+        let layout = UsfmLayout { verse_on_new_line: false, max_line_width: Some(20), line_ending: LineEnding::CrLf };
+        assert_eq!(
+            format_usfm(&root, &layout),
+            "\\id GEN\r\n\\c 1\r\n\\p \\v 1 In the\r\nbeginning \\v 2 God\r\n\\w created|strong=\"H1254\"\\w*\r\nthe\r\nheavens\\qt-s |who=\"Me\"\\*\r\n\\tr \\tc1 A \\tc2 B\r\n"
+        );
+    }
+
+    #[test]
+    fn test_usfm_attributes_round_trip() {
+        let usfm = "\\id GEN\n\\c 1\n\\p\n\\v 1 \\qt-s |Paul\\*\\w In|lemma=\"in\" x-occurrence=\"1\"\\w* \\rb 天|tiān\\rb* \\jmp the beginning|link-href=\"#GEN 1:2\" link-title=\"say \\\"hi\\\"\"\\jmp*\\qt-e\\*\n";
+        let root = deserialize(usfm.to_string(), "usfm").unwrap();
+        let Content::Para(para) = &root.content[2] else { panic!("expected a para") };
+        let content = para.content.as_ref().unwrap();
+        let Some(InParaObject::Milestone(qt)) = content.get(1) else { panic!("expected a milestone") };
+        assert_eq!(qt.who.as_deref(), Some("Paul"));
+        let Some(InParaObject::Char(rb)) = content.get(4) else { panic!("expected \\rb") };
+        assert_eq!(rb.additional_properties.get("gloss"), Some(&"tiān".to_string()));
+        let Some(InParaObject::Char(jmp)) = content.get(6) else { panic!("expected \\jmp") };
+        assert_eq!(jmp.link_href.as_deref(), Some("#GEN 1:2"));
+        assert_eq!(jmp.additional_properties.get("link-title"), Some(&"say \"hi\"".to_string()));
+
+        let written = serialize(&root, "usfm").unwrap();
+        assert_eq!(
+            written,
+            "\\id GEN\n\\c 1\n\\p\n\\v 1 \\qt-s |who=\"Paul\"\\*\\w In|lemma=\"in\" x-occurrence=\"1\"\\w* \\rb 天|tiān\\rb* \\jmp the beginning|link-href=\"#GEN 1:2\" link-title=\"say \\\"hi\\\"\"\\jmp*\\qt-e\\*\n"
+        );
+        assert_eq!(deserialize(written, "usfm").unwrap(), root);
+    }
+
+    #[test]
+    fn test_strip_and_reapply_alignments() {
+        let usfm = concat!(
+            "\\id GEN\n\\c 1\n\\p\n\\v 1 ",
+            r#"\zaln-s |x-content="בְּרֵאשִׁית" x-lemma="רֵאשִׁית" x-occurrence="1" x-occurrences="1" x-strong="H7225"\*\w In|x-occurrence="1" x-occurrences="1"\w* "#,
+            r#"\w the|x-occurrence="1" x-occurrences="2"\w* \w beginning|x-occurrence="1" x-occurrences="1"\w*\zaln-e\*, "#,
+            r#"\zaln-s |x-content="אֱלֹהִים" x-lemma="אֱלֹהִים" x-occurrence="1" x-occurrences="1" x-strong="H0430"\*"#,
+            r#"\zaln-s |x-content="בָּרָא" x-lemma="בָּרָא" x-occurrence="1" x-occurrences="1" x-strong="H1254a"\*"#,
+            r#"\w God|x-occurrence="1" x-occurrences="1"\w* \w created|x-occurrence="1" x-occurrences="1"\w*\zaln-e\*\zaln-e\* "#,
+            r#"\w the|x-occurrence="2" x-occurrences="2"\w* \w earth|x-occurrence="1" x-occurrences="1"\w*."#,
+            "\n",
+        );
+        let aligned = deserialize(usfm.to_string(), "usfm").unwrap();
+        let alignments = extract_alignments(&aligned);
+        let verse = &alignments[0].alignments;
+        assert_eq!(verse.len(), 2);
+        assert_eq!(verse[0].targets.iter().map(|word| (word.text.as_str(), word.occurrence)).collect::<Vec<_>>(), [("In", 1), ("the", 1), ("beginning", 1)]);
+        assert_eq!(verse[1].sources.iter().map(|word| word.strong.as_deref().unwrap()).collect::<Vec<_>>(), ["H0430", "H1254a"]);
+
+        let mut root = aligned.clone();
+        strip_alignments(&mut root);
+        assert_eq!(serialize(&root, "usfm").unwrap(), "\\id GEN\n\\c 1\n\\p\n\\v 1 In the beginning, God created the earth.\n");
+
+        apply_alignments(&mut root, &alignments);
+        assert_eq!(serialize(&root, "usfm").unwrap(), usfm);
+    }
+
+    #[test]
+    fn test_edit_model_between_parse_and_write() {
+        let mut root = deserialize(USFM_CONTENT.to_string(), "usfm").unwrap();
+        assert_eq!(root.book_code(), Some("PSA"));
+
+        for content in root.content.iter_mut() {
+            if let Content::Para(para) = content {
+                for in_para in para.content.iter_mut().flatten() {
+                    if let InParaObject::String(text) = in_para {
+                        *text = text.replace("Psalms", "Songs");
+                    }
+                }
+            }
+        }
+
+        let usj = serialize(&root, "usj").unwrap();
+        assert!(usj.contains("\"content\":[\"The Book of Songs\"]"));
+        let reparsed = deserialize(usj, "usj").unwrap();
+        assert_eq!(reparsed, root);
+    }
+
+    #[test]
+    fn test_string_and_typed_models_agree() {
+        let usx = std::fs::read_to_string("tests/datas/usx/good/web_psa150.usx").unwrap();
+        let usj = std::fs::read_to_string("tests/datas/usj/good/small.json").unwrap();
+        let table = std::fs::read_to_string("assets/data/good/table.json").unwrap();
+
+        let from_strings = [
+            deserialize_from_file_usfm(AosjStringModel::new(), USFM_CONTENT.to_string(), &ParseOptions::default(), &mut Vec::new()).unwrap(),
+            deserialize_from_file_usx(AosjStringModel::new(), usx.clone(), &mut Vec::new()).unwrap(),
+            deserialize_from_file_usj(AosjStringModel::new(), serde_json::from_str(&usj).unwrap(), &mut Vec::new()).unwrap(),
+            deserialize_from_file_usj(AosjStringModel::new(), serde_json::from_str(&table).unwrap(), &mut Vec::new()).unwrap(),
+        ];
+        let from_structs = [
+            deserialize(USFM_CONTENT.to_string(), "usfm").unwrap(),
+            deserialize(usx, "usx").unwrap(),
+            deserialize(usj, "usj").unwrap(),
+            deserialize(table, "usj").unwrap(),
+        ];
+        for (string_model, root) in from_strings.iter().zip(from_structs) {
+            assert_eq!(serialize_to_usj::usj_to_root(string_model), root);
+        }
+    }
+
+    #[derive(Default)]
+    struct EventRecorder {
+        events: Vec<String>,
+    }
+
+    impl DocumentHandler for EventRecorder {
+        fn start_book(&mut self, code: &str, _attributes: &BTreeMap<String, String>) {
+            self.events.push(format!("book {}", code));
+        }
+        fn chapter(&mut self, _number: &str, sid: &str, _attributes: &BTreeMap<String, String>) {
+            self.events.push(format!("chapter {}", sid));
+        }
+        fn start_para(&mut self, marker: &str, _attributes: &BTreeMap<String, String>) {
+            self.events.push(format!("para {}", marker));
+        }
+        fn end_para(&mut self) {
+            self.events.push("/para".to_string());
+        }
+        fn verse(&mut self, number: &str, sid: &str, _attributes: &BTreeMap<String, String>) {
+            self.events.push(format!("verse {} {}", number, sid));
+        }
+        fn text(&mut self, text: &str) {
+            // the indented USX has no space at the ends of text
+            self.events.push(format!("'{}'", text.trim()));
+        }
+        fn start_char(&mut self, marker: &str, attributes: &BTreeMap<String, String>) {
+            self.events.push(format!("char {} {:?}", marker, attributes));
+        }
+        fn end_char(&mut self) {
+            self.events.push("/char".to_string());
+        }
+        fn start_note(&mut self, marker: &str, caller: Option<&str>, _attributes: &BTreeMap<String, String>) {
+            self.events.push(format!("note {} {:?}", marker, caller));
+        }
+        fn end_note(&mut self) {
+            self.events.push("/note".to_string());
+        }
+        fn start_cell(&mut self, marker: &str, _attributes: &BTreeMap<String, String>) {
+            self.events.push(format!("cell {}", marker));
+        }
+        fn end_table(&mut self) {
+            self.events.push("/table".to_string());
+        }
+    }
+
+    #[test]
+    fn test_document_handler_events_agree_across_formats() {
+        let usfm = "\\id MRK Mark\n\\c 1\n\\s1 Heading\n\\p\n\\v 1 Beginning\\f + \\fr 1:1 \\ft Or \\fq good news\\f* of the \\nd Lord\\nd*\n\\v 2-3 As written\n\\tr \\th1 A \\tc2 B\n\\p more\n";
+        let root = deserialize(usfm.to_string(), "usfm").unwrap();
+        let mut recorded = Vec::new();
+        for (content, format) in [
+            (usfm.to_string(), "usfm"),
+            (serialize(&root, "usx").unwrap(), "usx"),
+            (serialize(&root, "usj").unwrap(), "usj"),
+        ] {
+            let mut recorder = EventRecorder::default();
+            let options = ParseOptions { marker_levels: MarkerLevels::Implicit, ..ParseOptions::default() };
+            read_with_handler(content, format, &options, &mut recorder).unwrap();
+            recorded.push(recorder.events);
+        }
+        assert_eq!(recorded[0][..8], [
+            "book MRK", "'Mark'", "chapter MRK 1", "para s", "'Heading'", "/para", "para p", "verse 1 MRK 1:1",
+        ]);
+        assert!(recorded[0].contains(&"note f Some(\"+\")".to_string()));
+        assert!(recorded[0].contains(&"verse 2-3 MRK 1:2-3".to_string()));
+        assert!(recorded[0].contains(&"cell tc2".to_string()));
+        assert_eq!(recorded[0], recorded[1]);
+        assert_eq!(recorded[0], recorded[2]);
+    }
+
+    #[test]
+    fn test_try_transform_reports_errors() {
+        let err = try_transform("<usx version=\"3.0\">\n  <para style=\"p\">text</usx>".to_string(), "usx".to_string(), "usj".to_string()).unwrap_err();
+        assert!(matches!(err, HallomaiError::Xml { location: SourceLocation { line: 2, .. }, .. }));
+
+        let err = try_transform("{\"version\": \"3.0\",\n\"content\": [}".to_string(), "usj".to_string(), "usx".to_string()).unwrap_err();
+        assert!(matches!(err, HallomaiError::Json { location: SourceLocation { line: 2, column: 13, .. }, .. }));
+
+        let usj = r#"{"version": "3.0", "content": [{"type": "para", "marker": "p", "content": "text"}]}"#;
+        let err = try_transform(usj.to_string(), "usj".to_string(), "usx".to_string()).unwrap_err();
+        assert_eq!(err, HallomaiError::schema("'content' must be an array", "/content/0/content"));
+
+        let err = try_transform(USFM_CONTENT.to_string(), "usfm".to_string(), "pdf".to_string()).unwrap_err();
+        assert_eq!(err, HallomaiError::UnsupportedOutputFormat("pdf".to_string()));
+    }
+
+    #[test]
+    fn test_usfm_diagnostics_have_positions() {
+        let usfm = "\\id PSA\n\\c 1\n\\p\n\\v 1 Blessed \\xyz is the man";
+        let err = deserialize(usfm.to_string(), "usfm").unwrap_err();
+        assert_eq!(err, HallomaiError::UnknownMarker {
+            marker: "xyz".to_string(),
+            location: SourceLocation { line: 4, column: 14, offset: 29 },
+        });
+
+        let usfm = "\\id PSA\n\\c 1\n\\p\n\\v 1 Blessed\\nd* is\n\\ts\\*\\*";
+        let (_, warnings) = deserialize_with_warnings(usfm.to_string(), "usfm").unwrap();
+        let locations: Vec<(usize, usize)> = warnings.iter().map(|w| (w.location.line, w.location.column)).collect();
+        assert_eq!(locations, vec![(4, 13), (5, 6)]);
+    }
+
+    #[test]
+    fn test_lenient_unknown_markers() {
+        let usfm = "\\id PSA\n\\c 1\n\\qx\n\\v 1 Blessed \\nx is\\nx* the \\zfoo man\\zfoo*";
+        assert!(matches!(deserialize(usfm.to_string(), "usfm"), Err(HallomaiError::UnknownMarker { .. })));
+
+        let (root, warnings) = deserialize_with_options(usfm.to_string(), "usfm", &ParseOptions::lenient()).unwrap();
+        let messages: Vec<&str> = warnings.iter().map(|w| w.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Unknown marker \\qx read as para",
+            "Unknown marker \\nx read as char",
+            "User-defined marker \\zfoo read as char",
+        ]);
+        let usx = serialize(&root, "usx").unwrap();
+        assert!(usx.contains("<para style=\"qx\">"));
+        assert!(usx.contains("<char style=\"nx\">is</char>"));
+        assert!(usx.contains("<char style=\"zfoo\">man</char>"));
+    }
+
+    #[test]
+    fn test_tables() {
+        let usfm = "\\id NUM\n\\c 7\n\\p\n\\v 12 In this order:\n\\tr \\th1 Day \\th2 Tribe \\thr3 Leader\n\\tr \\tcr1 1st \\tc2-3 Judah\n\\p Amen";
+        let root = deserialize(usfm.to_string(), "usfm").unwrap();
+        let Content::Table(table) = &root.content[3] else { panic!("expected a table, got {:?}", root.content[3]) };
+        let rows = table.content.as_ref().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].r#type, "table:row");
+        let cell = &rows[1].content.as_ref().unwrap()[1];
+        assert_eq!((cell.marker.as_str(), cell.align.as_deref(), cell.colspan.as_deref()), ("tc2", Some("start"), Some("3")));
+        assert!(matches!(root.content[4], Content::Para(_)));
+
+        let usx = serialize(&root, "usx").unwrap();
+        assert!(usx.contains("<cell align=\"end\" style=\"thr3\">Leader</cell>"));
+        assert!(usx.contains("<cell align=\"start\" colspan=\"3\" style=\"tc2\">Judah</cell>"));
+        let cells = |root: &Root| -> Vec<(String, Option<String>)> {
+            root.content.iter()
+                .filter_map(|content| if let Content::Table(table) = content { table.content.clone() } else { None })
+                .flatten()
+                .flat_map(|row| row.content.unwrap_or_default())
+                .map(|cell| (cell.marker, cell.colspan))
+                .collect()
+        };
+        assert_eq!(cells(&deserialize(usx, "usx").unwrap()), cells(&root));
+        assert_eq!(deserialize(serialize(&root, "usfm").unwrap(), "usfm").unwrap().content, root.content);
+
+        let usj = std::fs::read_to_string("assets/data/good/table.json").unwrap();
+        let root = deserialize(usj, "usj").unwrap();
+        assert_eq!(deserialize(serialize(&root, "usj").unwrap(), "usj").unwrap(), root);
+    }
+
+    #[test]
+    fn test_sidebars() {
+        let usfm = "\\id MRK\n\\c 1\n\\p\n\\v 1 The beginning\n\\esb \\cat History\\cat*\n\\ms Fishing\n\\p Boats were small.\n\\esbe\n\\p\n\\v 2 As it is written";
+        let root = deserialize(usfm.to_string(), "usfm").unwrap();
+        let Content::Sidebar(sidebar) = &root.content[3] else { panic!("expected a sidebar, got {:?}", root.content[3]) };
+        assert_eq!((sidebar.marker.as_str(), sidebar.category.as_deref()), ("esb", Some("History")));
+        assert_eq!(sidebar.content.as_ref().unwrap().len(), 2);
+        assert!(matches!(root.content[4], Content::Para(_)));
+
+        let string_model = deserialize_from_file_usfm(AosjStringModel::new(), usfm.to_string(), &ParseOptions::default(), &mut Vec::new()).unwrap();
+        assert_eq!(serialize_to_usj::usj_to_root(&string_model), root);
+
+        let usx = serialize(&root, "usx").unwrap();
+        assert!(usx.contains("<sidebar category=\"History\" style=\"esb\">"));
+        for format in ["usx", "usfm", "usj"] {
+            let reparsed = deserialize(serialize(&root, format).unwrap(), format).unwrap();
+            assert_eq!(reparsed.content[3], root.content[3], "{}", format);
+        }
+    }
+
+    #[test]
+    fn test_figures() {
+        let figure = |usfm: &str| -> FigureObject {
+            let root = deserialize(format!("\\id MRK\n\\c 1\n\\p\n\\v 18 Fishing nets {}", usfm), "usfm").unwrap();
+            let Content::Para(para) = &root.content[2] else { panic!("expected a para") };
+            match para.content.as_ref().unwrap().last() {
+                Some(InParaObject::Figure(figure)) => figure.clone(),
+                other => panic!("expected a figure, got {:?}", other),
+            }
+        };
+        let usfm3 = figure("\\fig Fishermen|src=\"art/fish.jpg\" size=\"col\" ref=\"1:18\"\\fig*");
+        let usfm2 = figure("\\fig A boat|art/fish.jpg|col|||Fishermen|1:18\\fig*");
+        assert_eq!(usfm3.content, Some(vec!["Fishermen".to_string()]));
+        assert_eq!((usfm3.file.as_deref(), usfm3.size.as_deref(), usfm3.r#ref.as_deref()), (Some("art/fish.jpg"), Some("col"), Some("1:18")));
+        assert_eq!(usfm2, FigureObject { alt: Some("A boat".to_string()), ..usfm3 });
+
+        let root = deserialize("\\id MRK\n\\p \\fig A boat|art/fish.jpg|col|||Fishermen|1:18\\fig*".to_string(), "usfm").unwrap();
+        let usx = serialize(&root, "usx").unwrap();
+        assert!(usx.contains("<figure alt=\"A boat\" file=\"art/fish.jpg\" style=\"fig\" ref=\"1:18\" size=\"col\">Fishermen</figure>"));
+        for format in ["usx", "usfm", "usj"] {
+            assert_eq!(deserialize(serialize(&root, format).unwrap(), format).unwrap().content[1], root.content[1], "{}", format);
+        }
+    }
+
+    #[test]
+    fn test_notes() {
+        let usfm = "\\id MRK\n\\c 1\n\\p\n\\v 1 \\wj Text\\f - \\cat Textual\\cat* \\fr 1:1 \\ft Note \\+bd text\\+bd*.\\f* said\\wj* and\\x a \\xo 1:1 \\xt Gen 1:1\\x*.\n\\p End\\fe + \\ft endnote\\fe*";
+        let root = deserialize(usfm.to_string(), "usfm").unwrap();
+        let notes = find_notes(&serde_json::to_value(&root).unwrap());
+        let summary = |notes: &[NoteObject]| -> Vec<(String, Option<String>, Option<String>)> {
+            notes.iter().map(|note| (note.marker.clone(), note.caller.clone(), note.category.clone())).collect()
+        };
+        assert_eq!(summary(&notes), vec![
+            ("f".to_string(), Some("-".to_string()), Some("Textual".to_string())),
+            ("x".to_string(), Some("a".to_string()), None),
+            ("fe".to_string(), Some("+".to_string()), None),
+        ]);
+        let Some(InParaObject::Char(ft)) = notes[0].content.as_ref().unwrap().get(1) else { panic!("expected the \\ft of the note") };
+        assert_eq!(ft.content.as_ref().unwrap().len(), 3);
+
+        let written = serialize(&root, "usfm").unwrap();
+        assert!(written.contains("\\f - \\cat Textual\\cat* \\fr 1:1 \\ft Note \\+bd text\\+bd*.\\f*"), "{}", written);
+        assert!(written.contains("\\x a \\xo 1:1 \\xt Gen 1:1\\x*"), "{}", written);
+        let root_with_link = deserialize("\\id MRK\n\\p\\x - \\xt Gen 1:1|GEN 1:1\\xt*\\x*".to_string(), "usfm").unwrap();
+        assert!(serialize(&root_with_link, "usfm").unwrap().contains("\\xt Gen 1:1|GEN 1:1\\xt*\\x*"));
+
+        let usx = serialize(&root, "usx").unwrap();
+        assert_eq!(summary(&find_notes(&serde_json::to_value(deserialize(usx, "usx").unwrap()).unwrap())), summary(&notes));
+        for format in ["usfm", "usj"] {
+            assert_eq!(deserialize(serialize(&root, format).unwrap(), format).unwrap().content[2..], root.content[2..], "{}", format);
+        }
+    }
+
+    #[test]
+    fn test_usfm_tokens() {
+        let usfm = "\\id PSA\n\\c 1\n\\cp A\n\\q1\n\\v 1-2 \u{5d0}\u{5b8}\u{591}\u{5d3}\u{5b8}\u{5dd}\t  and~\\+w man|lemma=\"a \\\"b\\\"\"\\+w*//\n\\f + \\fr 1:1\\fr* \\cat Textual\\cat* \\ft Note\\f*";
+        let root = deserialize(usfm.to_string(), "usfm").unwrap();
+        let notes = find_notes(&serde_json::to_value(&root).unwrap());
+        assert_eq!(notes[0].category.as_deref(), Some("Textual"));
+        assert_eq!(
+            serialize(&root, "usfm").unwrap(),
+            "\\id PSA\n\\c 1\n\\cp A\n\\q1\n\\v 1-2 \u{5d0}\u{5b8}\u{591}\u{5d3}\u{5b8}\u{5dd} and\u{a0}\\w man|lemma=\"a \\\"b\\\"\"\\w*// \\f + \\cat Textual\\cat* \\fr 1:1\\fr* \\ft Note\\f*\n"
+        );
+
+        // Devanagari vowel signs are spacing marks, and only ASCII digits number a chapter
+        let lexemes: Vec<_> = lexer_usfm::Lexer::new("\\c 1\n\\p \u{915}\u{93f}\u{951}\u{924}\u{93e}\u{92c}\n\\c \u{662}\n")
+            .map(|lexeme| (lexeme.subclass, lexeme.parts[0])).collect();
+        assert_eq!(lexemes[0], ("chapter", "\\c 1\n"));
+        assert_eq!(lexemes[2], ("wordLike", "\u{915}\u{93f}\u{951}\u{924}\u{93e}\u{92c}"));
+        assert_ne!(lexemes[4].0, "chapter");
+    }
+
+    fn find_notes(value: &serde_json::Value) -> Vec<NoteObject> {
+        let mut notes = Vec::new();
+        if value.get("type").and_then(|t| t.as_str()) == Some("note") {
+            notes.push(serde_json::from_value(value.clone()).unwrap());
+        }
+        for child in value.get("content").and_then(|c| c.as_array()).into_iter().flatten() {
+            notes.extend(find_notes(child));
+        }
+        notes
+    }
+
+    #[test]
+    fn test_marker_levels() {
+        let usfm = "\\id PSA\n\\c 1\n\\q1 Blessed\n\\q is the man\n\\q2 who \\liv1 walks\\liv1*\n\\q7 not\n\\p2 in";
+        let markers = |levels: MarkerLevels| -> (Vec<String>, Vec<String>) {
+            let options = ParseOptions { marker_levels: levels, ..ParseOptions::default() };
+            let (root, warnings) = deserialize_with_options(usfm.to_string(), "usfm", &options).unwrap();
+            let usx = serialize(&root, "usx").unwrap();
+            let markers = usx.split("style=\"").skip(3).map(|s| s[..s.find('"').unwrap()].to_string()).collect();
+            (markers, warnings.into_iter().map(|w| w.to_string()).collect())
+        };
+
+        let (preserved, warnings) = markers(MarkerLevels::Preserve);
+        assert_eq!(preserved, vec!["q1", "q", "q2", "liv1", "q7", "p2"]);
+        assert_eq!(warnings, vec![
+            "Marker \\q7 only goes up to level 4 at line 6 col 1",
+            "Marker \\p takes no level, but was read as \\p2 at line 7 col 1",
+        ]);
+        assert_eq!(markers(MarkerLevels::Implicit).0, vec!["q", "q", "q2", "liv", "q7", "p2"]);
+        assert_eq!(markers(MarkerLevels::Explicit).0, vec!["q1", "q1", "q2", "liv1", "q7", "p2"]);
+
+        // \toc is not a marker: the number of \toc1 and \toca1 is always written
+        let usfm = "\\id PSA\n\\toc1 Psalms\n\\toca1 Ps\n\\toc2 Psalms\n";
+        let options = ParseOptions { marker_levels: MarkerLevels::Implicit, ..ParseOptions::default() };
+        let (root, _) = deserialize_with_options(usfm.to_string(), "usfm", &options).unwrap();
+        assert_eq!(serialize(&root, "usfm").unwrap(), usfm);
+    }
+
+    #[test]
+    fn test_alternate_and_publishing_numbers() {
+        let usfm = "\\id PSA\n\\c 51\n\\ca 53\\ca*\n\\cp LI\n\\d For the director\n\\q1\n\\v 1 \\va 3\\va* \\vp 1a\\vp* Have mercy on me";
+        let numbers = |root: &Root| -> Vec<(String, Option<String>, Option<String>)> {
+            let mut numbers = Vec::new();
+            for content in &root.content {
+                match content {
+                    Content::Chapter(c) => numbers.push((c.number.clone(), c.altnumber.clone(), c.pubnumber.clone())),
+                    Content::Para(p) => {
+                        for object in p.content.iter().flatten() {
+                            if let InParaObject::Verse(v) = object {
+                                numbers.push((v.number.clone(), v.altnumber.clone(), v.pubnumber.clone()));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            numbers
+        };
+
+        let (root, warnings) = deserialize_with_warnings(usfm.to_string(), "usfm").unwrap();
+        let expected = vec![
+            ("51".to_string(), Some("53".to_string()), Some("LI".to_string())),
+            ("1".to_string(), Some("3".to_string()), Some("1a".to_string())),
+        ];
+        assert!(warnings.is_empty());
+        assert_eq!(numbers(&root), expected);
+        let Content::Para(q) = &root.content[3] else { panic!("expected the \\q1 paragraph") };
+        assert_eq!(q.content.as_ref().unwrap().last(), Some(&InParaObject::String("Have mercy on me".to_string())));
+
+        for format in ["usx", "usj", "usfm"] {
+            let output = serialize(&root, format).unwrap();
+            assert_eq!(numbers(&deserialize(output, format).unwrap()), expected, "round trip through {}", format);
+        }
+
+        let (_, warnings) = deserialize_with_warnings("\\id PSA\n\\p\n\\cp A\n".to_string(), "usfm").unwrap();
+        assert_eq!(warnings[0].to_string(), "Publishing chapter number \\cp not directly after a chapter at line 2 col 3");
+    }
+
+    #[test]
+    fn test_verse_segments_and_ranges() {
+        let usfm = "\\id GEN\n\\c 1\n\\p\n\\v 1a In the beginning\n\\v 1b God\n\\v 12b-13 created\n\\v 4-3 the\n\\v 5x7 heavens\n\\v 0 and";
+        let (root, warnings) = deserialize_with_warnings(usfm.to_string(), "usfm").unwrap();
+        let Content::Para(p) = &root.content[2] else { panic!("expected the \\p paragraph") };
+        let verses: Vec<&VerseObject> = p.content.iter().flatten().filter_map(|object| match object {
+            InParaObject::Verse(verse) => Some(verse),
+            _ => None,
+        }).collect();
+
+        assert_eq!(verses.iter().map(|v| v.number.as_str()).collect::<Vec<_>>(), vec!["1a", "1b", "12b-13", "4-3", "5x7", "0"]);
+        assert_eq!(verses[0].sid.as_deref(), Some("GEN 1:1"));
+        assert_eq!(verses[2].sid.as_deref(), Some("GEN 1:12-13"));
+        assert_eq!(verses[2].range(), Some(VerseRange { start: 12, start_segment: Some("b".to_string()), end: Some(13), end_segment: None }));
+        assert!(verses[2].range().unwrap().contains(13));
+        assert_eq!(verses[3].range(), None);
+        assert_eq!(p.content.as_ref().unwrap()[1], InParaObject::String("In the beginning".to_string()));
+        assert_eq!(warnings.iter().map(|w| w.to_string()).collect::<Vec<_>>(), vec![
+            "Verse range '4-3' ends before it starts at line 7 col 1",
+            "Invalid verse number '5x7', expected e.g. 1, 1a, 3-4 or 5a-6b at line 8 col 1",
+            "Invalid verse number '0', verses start at 1 at line 9 col 1",
+        ]);
+        assert_eq!("5a-6b".parse::<VerseRange>().unwrap().to_string(), "5a-6b");
+    }
+
+    #[test]
+    fn test_verse_and_chapter_milestones() {
+        let usfm = "\\id GEN\n\\c 1\n\\p\n\\v 1 In the beginning\n\\q1 God created\n\\v 2 The earth\n\\s Heading\n\\tr \\tc1 \\v 3 Light\n\\tc2 Dark\n\\c 2\n\\p\n\\v 1 Thus";
+        let root = deserialize(usfm.to_string(), "usfm").unwrap();
+        let Content::Table(table) = &root.content[5] else { panic!("expected the table") };
+        let cell = &table.content.as_ref().unwrap()[0].content.as_ref().unwrap()[0];
+        let Some(InParaObject::Verse(verse)) = cell.content.as_ref().unwrap().first() else { panic!("expected verse 3") };
+        assert_eq!(verse.sid.as_deref(), Some("GEN 1:3"));
+
+        let usx = serialize(&root, "usx").unwrap();
+        let milestones = |usx: &str| -> Vec<String> {
+            usx.match_indices(r#"id=""#).map(|(i, _)| usx[i - 1..i + usx[i..].find("\"/>").unwrap()].to_string()).collect()
+        };
+        assert_eq!(milestones(&usx), vec![
+            r#"sid="GEN 1"#, r#"sid="GEN 1:1"#, r#"eid="GEN 1:1"#, r#"sid="GEN 1:2"#, r#"eid="GEN 1:2"#,
+            r#"sid="GEN 1:3"#, r#"eid="GEN 1:3"#, r#"eid="GEN 1"#, r#"sid="GEN 2"#, r#"sid="GEN 2:1"#, r#"eid="GEN 2:1"#, r#"eid="GEN 2"#,
+        ]);
+        assert!(usx.contains("<para style=\"q1\">God created<verse eid=\"GEN 1:1\"/>"));
+
+        let (reread, warnings) = deserialize_with_warnings(usx.clone(), "usx").unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(milestones(&serialize(&reread, "usx").unwrap()), milestones(&usx));
+
+        let (_, warnings) = deserialize_with_warnings(usx.replace(r#"eid="GEN 1:2""#, r#"eid="GEN 1:5""#), "usx").unwrap();
+        assert_eq!(warnings[0].message, "End milestone verse eid=\"GEN 1:5\" does not match the open verse GEN 1:2");
+    }
+
+    #[test]
+    fn test_default_version() {
+        let usfm = "\\id 3JN\n\\c 1\n\\p\n\\v 1 The elder\n";
+        let root = deserialize(usfm.to_string(), "usfm").unwrap();
+        assert!(serialize(&root, "usx").unwrap().starts_with("<usx version=\"3.0\">"));
+        assert!(serialize(&root, "usj").unwrap().contains("\"version\":\"3.0\""));
+        assert_eq!(serialize(&root, "usfm").unwrap(), usfm);
+    }
+
+    #[test]
+    fn test_usx_end_milestones_placement() {
+        let usfm = "\\id GEN\n\\c 1\n\\p\n\\v 1 In the beginning\\f + \\ft note\\f*\n\\q1 God created\n\\v 2 The earth\n\\s Heading\n\\p\n\\v 3 Light\n\\c 2\n\\p\n\\v 1 Thus\n";
+        let usx = serialize(&deserialize(usfm.to_string(), "usfm").unwrap(), "usx").unwrap();
+        assert_eq!(usx, r#"<usx version="3.0">
+    <book code="GEN" style="id"></book>
+    <chapter style="c" number="1" sid="GEN 1"/>
+    <para style="p">
+        <verse style="v" number="1" sid="GEN 1:1"/>In the beginning<note caller="+" style="f">
+            <char style="ft">note</char>
+        </note>
+    </para>
+    <para style="q1">God created<verse eid="GEN 1:1"/>
+        <verse style="v" number="2" sid="GEN 1:2"/>The earth<verse eid="GEN 1:2"/>
+    </para>
+    <para style="s">Heading</para>
+    <para style="p">
+        <verse style="v" number="3" sid="GEN 1:3"/>Light<verse eid="GEN 1:3"/>
+    </para>
+    <chapter eid="GEN 1"/>
+    <chapter style="c" number="2" sid="GEN 2"/>
+    <para style="p">
+        <verse style="v" number="1" sid="GEN 2:1"/>Thus<verse eid="GEN 2:1"/>
+    </para>
+    <chapter eid="GEN 2"/>
+</usx>"#);
+    }
+
+    #[test]
+    fn test_validate_usj() {
+        for entry in std::fs::read_dir("assets/data/good").unwrap() {
+            let path = entry.unwrap().path();
+            let usj: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            assert_eq!(validate_usj(&usj), vec![], "{}", path.display());
+        }
+
+        let bad_sid = serde_json::from_str(&std::fs::read_to_string("assets/data/bad/bad_sid.json").unwrap()).unwrap();
+        let issues: Vec<String> = validate_usj(&bad_sid).iter().map(|issue| issue.to_string()).collect();
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].starts_with("'GEN X' does not match the pattern"));
+        assert!(issues[0].ends_with(" at '/content/1/sid'"));
+
+        let extra_property = std::fs::read_to_string("assets/data/bad/extra_property.json").unwrap();
+        assert_eq!(validate_usj(&serde_json::from_str(&extra_property).unwrap()), vec![ValidationIssue {
+            path: "/content/0/banana".to_string(),
+            message: "unexpected property 'banana'".to_string(),
+        }]);
+        let options = ParseOptions { validate_usj: true, ..ParseOptions::default() };
+        let err = try_transform_with_options(extra_property.clone(), "usj", "usfm", &options).unwrap_err();
+        assert_eq!(err, HallomaiError::schema("unexpected property 'banana'", "/content/0/banana"));
+        assert!(try_transform_with_options(extra_property, "usj", "usfm", &ParseOptions::default()).is_ok());
+
+        let usj = serde_json::json!({"type": "USJ", "version": "3.0", "content": [
+            {"type": "para", "marker": "p", "content": [{"type": "verse", "marker": "v"}, 3, {"type": "chr", "marker": "bd"}]},
+        ]});
+        assert_eq!(validate_usj(&usj).into_iter().map(|issue| (issue.path, issue.message)).collect::<Vec<_>>(), vec![
+            ("/content/0/content/0/number".to_string(), "missing required property 'number'".to_string()),
+            ("/content/0/content/1".to_string(), "expected string or object, found number".to_string()),
+            ("/content/0/content/2/type".to_string(), "unexpected type \"chr\"".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_own_usj_output_is_valid() {
+        for (path, format) in [("assets/usfm/22-SNG.usfm", "usfm"), ("assets/usx/MAT.usx", "usx"), ("assets/usx/web_psa.usx", "usx")] {
+            let root = deserialize(std::fs::read_to_string(path).unwrap(), format).unwrap();
+            let usj: serde_json::Value = serde_json::from_str(&serialize(&root, "usj").unwrap()).unwrap();
+            assert_eq!(validate_usj(&usj), vec![], "{}", path);
+        }
+
+        let root = deserialize("\\id GEN\n\\c 1\n\\p\n\\v 1a In the beginning\n\\v 1b-2 God\n".to_string(), "usfm").unwrap();
+        let usj: serde_json::Value = serde_json::from_str(&serialize(&root, "usj").unwrap()).unwrap();
+        assert_eq!(validate_usj(&usj), vec![]);
+
+        // Only the known deviations from the schema are accepted
+        let usj = serde_json::json!({"type": "USJ", "version": "3.0", "content": [
+            {"type": "para", "marker": "p", "x-rank": "2", "content": [
+                {"type": "verse", "marker": "v", "number": "1A"},
+                {"type": "char", "marker": "w", "closed": "maybe", "lemma": "a", "x-occurrence": "1"},
+            ]},
+        ]});
+        assert_eq!(validate_usj(&usj).into_iter().map(|issue| issue.path).collect::<Vec<_>>(), vec![
+            "/content/0/content/0/number", "/content/0/content/1/closed", "/content/0/x-rank",
+        ]);
+    }
+
+    #[test]
+    fn test_usj_with_loose_values_and_every_node_type() {
+        let usj = r#"{"type": "USJ", "version": 3.0, "content": [
+            {"type": "book", "marker": "id", "code": "GEN", "content": ["Genesis"]},
+            {"type": "chapter", "marker": "c", "number": 1, "sid": "GEN 1"},
+            {"type": "para", "marker": "p", "x-rank": 2, "content": [
+                {"type": "verse", "marker": "v", "number": 1, "sid": "GEN 1:1", "x-checked": true, "x-source": {"id": 7}},
+                "In the beginning", {"type": "optbreak"}, "God created",
+                {"type": "ref", "loc": "JHN 1:1", "content": ["John 1:1"]},
+                {"type": "glossary", "content": ["kept"]}
+            ]}
+        ]}"#.to_string();
+        let (root, warnings) = deserialize_with_warnings(usj.clone(), "usj").unwrap();
+        assert_eq!(warnings.iter().map(Warning::to_string).collect::<Vec<_>>(), vec![
+            "Property 'x-source' is not a string, number or boolean and is left out at '/content/2/content/0/x-source'",
+            "Unknown type 'glossary', only its content is kept at '/content/2/content/5'",
+        ]);
+        let Content::Para(para) = &root.content[2] else { panic!("expected a para") };
+        assert_eq!(para.additional_properties.get("x-rank"), Some(&"2".to_string()));
+        let Some(InParaObject::Verse(verse)) = para.content.as_ref().and_then(|c| c.first()) else { panic!("expected a verse") };
+        assert_eq!(verse.number, "1");
+        assert_eq!(verse.additional_properties.get("x-checked"), Some(&"true".to_string()));
+        assert_eq!(verse.additional_properties.get("x-source"), None);
+
+        let usfm = transform(usj.clone(), "usj".to_string(), "usfm".to_string());
+        assert!(usfm.contains(r#"In the beginning//God created\ref John 1:1|JHN 1:1\ref*kept"#), "{}", usfm);
+        let usx = transform(usj, "usj".to_string(), "usx".to_string());
+        assert!(usx.contains(r#"In the beginning<optbreak/>God created<ref loc="JHN 1:1">John 1:1</ref>kept"#), "{}", usx);
+
+        for (content, format) in [(usfm, "usfm"), (usx, "usx")] {
+            let json: serde_json::Value = serde_json::from_str(&transform(content, format.to_string(), "usj".to_string())).unwrap();
+            assert_eq!(json["content"][2]["content"][2], serde_json::json!({"type": "optbreak"}), "{}", format);
+            assert_eq!(json["content"][2]["content"][4], serde_json::json!({"type": "ref", "loc": "JHN 1:1", "content": ["John 1:1"]}), "{}", format);
+        }
+
+        let usj = r#"{"type": "USJ", "version": "3.0", "content": [
+            {"type": "book", "marker": "id", "code": "GEN"},
+            "stray", {"type": "verse", "marker": "v", "number": "1"},
+            {"type": "division", "content": [{"type": "para", "marker": "p", "content": ["kept"]}]}
+        ]}"#.to_string();
+        let (root, warnings) = deserialize_with_warnings(usj, "usj").unwrap();
+        assert_eq!(warnings.iter().map(Warning::to_string).collect::<Vec<_>>(), vec![
+            "Expected an object, found \"stray\"; it is left out at '/content/1'",
+            "'verse' outside of a paragraph is left out at '/content/2'",
+            "Unknown type 'division', only its content is kept at '/content/3'",
+        ]);
+        assert_eq!(serialize(&root, "usfm").unwrap(), "\\id GEN\n\\usfm 3.0\n\\p kept\n");
+    }
+
+    #[test]
+    fn test_transform_books() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("release")).unwrap();
+        std::fs::copy("assets/usx/TIT.usx", dir.path().join("release/TIT.usx")).unwrap();
+        std::fs::copy("assets/usfm/65-3JN.usfm", dir.path().join("65-3JN.SFM")).unwrap();
+        std::fs::write(dir.path().join("01-GEN.usfm"), "\\id GEN\n\\xyz oops").unwrap();
+        std::fs::write(dir.path().join("metadata.xml"), "<DBLMetadata/>").unwrap();
+
+        let books = read_books(dir.path()).unwrap();
+        let names: Vec<_> = books.iter().map(|book| (book.name.as_str(), book.format.as_str())).collect();
+        assert_eq!(names, [("01-GEN.usfm", "usfm"), ("65-3JN.SFM", "usfm"), (&*format!("release{}TIT.usx", std::path::MAIN_SEPARATOR), "usx")]);
+
+        let results = transform_books(books, "usj", &ParseOptions::default());
+        assert!(matches!(results[0].output, Err(HallomaiError::UnknownMarker { .. })));
+        assert_eq!(results[1].book_code.as_deref(), Some("3JN"));
+        assert_eq!(results[2].book_code.as_deref(), Some("TIT"));
+        assert!(results[1..].iter().all(|result| result.output.is_ok()));
+
+        let books = read_books(dir.path()).unwrap();
+        let layout = UsfmLayout { verse_on_new_line: false, ..UsfmLayout::default() };
+        let formatted = format_usfm_books(books.clone(), &layout, &ParseOptions::default());
+        assert_eq!(formatted[2].output.as_ref().unwrap(), &format_usfm(&deserialize(books[2].content.clone(), "usx").unwrap(), &layout));
+        let texts = transform_books(books, "txt", &ParseOptions::default());
+        let combined = combine_text(&texts);
+        assert!(combined.starts_with("TROISIÈME ÉPÎTRE DE JEAN\n1\n1 L’ancien"), "{}", combined);
+        assert!(combined.contains("Les amis te saluent. Salue les amis, chacun en particulier.\n\nE Titosko lil\n"), "{}", combined);
+
+        let archive_path = dir.path().join("bundle.zip");
+        let mut archive = zip::ZipWriter::new(std::fs::File::create(&archive_path).unwrap());
+        archive.start_file("USX_1/TIT.usx", zip::write::SimpleFileOptions::default()).unwrap();
+        archive.write_all(std::fs::read("assets/usx/TIT.usx").unwrap().as_slice()).unwrap();
+        archive.start_file("metadata.xml", zip::write::SimpleFileOptions::default()).unwrap();
+        archive.write_all(b"<DBLMetadata/>").unwrap();
+        archive.finish().unwrap();
+        let books = read_books(&archive_path).unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].name, "USX_1/TIT.usx");
+    }
+
+    #[test]
+    fn test_transform_books_keeps_the_order_of_the_books() {
+        let books: Vec<BookSource> = (1..=40).map(|chapter| {
+            let content = if chapter % 7 == 0 {
+                format!("\\id GEN\n\\c {}\n\\xyz oops", chapter)
+            } else {
+                format!("\\id GEN\n\\c {}\n\\p\n\\v 1 In the beginning \\bd God\\bd*", chapter)
+            };
+            BookSource { name: format!("book{}.usfm", chapter), format: "usfm".to_string(), content }
+        }).collect();
+        let options = ParseOptions::default();
+        let sequential: Vec<BookResult> = books.iter().map(|book| {
+            match deserialize_with_options(book.content.clone(), "usfm", &options) {
+                Ok((root, warnings)) => BookResult {
+                    name: book.name.clone(),
+                    book_code: root.book_code().map(str::to_string),
+                    output: serialize(&root, "usx"),
+                    warnings,
+                },
+                Err(err) => BookResult { name: book.name.clone(), book_code: None, output: Err(err), warnings: Vec::new() },
+            }
+        }).collect();
+
+        let results = transform_books(books, "usx", &options);
+        assert_eq!(results, sequential);
+        assert!(matches!(results[6].output, Err(HallomaiError::UnknownMarker { .. })));
+        assert!(results[7].output.as_ref().unwrap().contains(r#"number="8""#));
+    }
+
+    #[test]
+    fn test_plain_text() {
+        let usfm = "\\id GEN\n\\h Genesis\n\\toc1 Genesis\n\\mt The Book of Genesis\n\\c 1\n\\s The Creation\n\\p\n\\v 1 In the \\w beginning|lemma=\"a\"\\w*\\f + \\ft Or when\\f*\n\\v 2 God created\n\\tr \\th1 Day \\th2 Work\n";
+        let text = serialize(&deserialize(usfm.to_string(), "usfm").unwrap(), "txt").unwrap();
+        assert_eq!(text, "The Book of Genesis\n1\nThe Creation\n1 In the beginning 2 God created\nDay\tWork\n");
+    }
+
+    #[test]
+    fn test_format_detection() {
+        assert_eq!(format_from_extension("41MATGNT92.SFM"), Some("usfm"));
+        assert_eq!(format_from_extension("release/USX_1/MAT.xml"), Some("usx"));
+        assert_eq!(format_from_extension("mat.usj"), Some("usj"));
+        assert_eq!(format_from_extension("README.md"), None);
+        assert_eq!(detect_format("\u{feff}\\id MAT"), Some("usfm"));
+        assert_eq!(detect_format("  <?xml version=\"1.0\"?><usx version=\"3.0\"/>"), Some("usx"));
+        assert_eq!(detect_format("\n{\"type\": \"USJ\"}"), Some("usj"));
+        assert_eq!(detect_format("MAT"), None);
     }
 
     #[test]
     fn test_invalid_input_format() {
         let output = transform(USFM_CONTENT.to_string(), "invalid_format".to_string(), "json".to_string());
-        assert_eq!(output, "Unsupported input file format. Only 'usfm', 'usx', and 'json' are supported.".to_string());
+        assert_eq!(output, "Unsupported input file format 'invalid_format'. Only 'usfm', 'usx', 'usj' and 'json' are supported.".to_string());
     }
 
     #[test]
     fn test_invalid_output_format() {
         let output = transform(USFM_CONTENT.to_string(), "usfm".to_string(), "invalid_format".to_string());
-        assert_eq!(output, "Unsupported output file format. Only 'usfm', 'usx', and 'json' are supported.".to_string());
+        assert_eq!(output, "Unsupported output file format 'invalid_format'. Only 'usfm', 'usx', 'usj', 'json' and 'txt' are supported.".to_string());
     }
 }
@@ -1,161 +1,423 @@
-//! This crate is a tool for reading and processing USX files.
-//! It uses custom modules and the SAX approach to parse the files
-//! and convert the data into structured models.
+//! The `hallomai` command line: converts and checks USFM, USX and USJ books.
 //!
-//! ### Organisation
-//!
-//! The crate is organized into several modules:
-//!
-//! - `element`: Defines the `Element` struct for representing XML elements.
-//! - `aosj_string_model`: Contains the main model `AosjStringModel` for processing USX documents.
-//! - `model_traits`: Defines the `AosjModel` trait for model operations.
-//! - `deserialize_usx`: Provides functions for reading and parsing USX files using SAX.
+//! - `hallomai convert GEN.usfm --to usx -o GEN.usx` converts a book; `-` reads standard
+//!   input or writes standard output, the default.
+//! - `hallomai convert bible/ --to usj --out-dir out/` converts every book of a directory,
+//!   a `.zip` archive or a list of files; `--to txt --combine` writes them as a single text.
+//! - `hallomai validate bible/` reads books and reports their errors and warnings.
 //!
+//! The exit code is 0 when everything went well, 1 when a book could not be read or written
+//! and 2 when the command line itself is wrong.
 
-#![allow(dead_code)]
-mod model_traits;
-mod aosj_string;
-mod structs_model;
-
-use std::fs::File;
-use std::io::Read;
-use aosj_string::aosj_string_model::AosjStringModel;
-
-mod deserialize_usx;
-mod deserialize_usj;
-mod deserialize_usfm;
-mod reg_ex_tests;
-mod utils_usfm;
-mod aosj_enum_model;
-mod serialize_to_usj;
-mod serialize_to_usx;
-mod serialize_to_usfm;
-use serde_json::to_value;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
 use structopt::StructOpt;
-use crate::deserialize_usfm::deserialize_from_file_usfm;
-use crate::deserialize_usj::deserialize_from_file_usj;
-use crate::deserialize_usx::deserialize_from_file_usx;
+use hallomai::{
+    combine_text, deserialize_with_options, detect_format, format_from_extension, format_usfm, format_usfm_books,
+    read_books, serialize, transform_books, validate_usj, BookResult, BookSource, HallomaiError, LineEnding, MarkerLevels,
+    ParseOptions, UsfmLayout,
+};
 
+#[derive(StructOpt, Debug)]
+#[structopt(name = "hallomai", about = "Converts Scripture between USFM, USX and USJ")]
+enum Command {
+    /// Converts books from one format to another
+    Convert(ConvertOpt),
+    /// Reads books and reports their errors and warnings, without converting them
+    Validate(ValidateOpt),
+}
 
-// include!("../tests/code/test_deserialize_usj.rs");
-// include!("../tests/code/test_deserialize_usx.rs");
-// include!("../tests/code/test_deserialize_usfm.rs");
+/// How the inputs are read, for every command.
+#[derive(StructOpt, Debug)]
+struct ReadOpt {
+    /// Input format: usfm, usx, usj or json; guessed from the extension or the content otherwise
+    #[structopt(long)]
+    from: Option<String>,
 
-// extern crate rust_usfm_parser;
+    /// Read unknown USFM markers as para/char instead of failing
+    #[structopt(long)]
+    lenient: bool,
 
-// use regex::Regex;
-// use std::collections::HashMap;
-// use regex::{Captures, Regex};
-// use tree_sitter::Node;
-// use rust_usfm_parser::USFMParser;
-// use crate::aosj_string::aosj_string_model::AosjStringModel;
+    /// How to write level 1 of numbered markers: preserve, implicit (\q) or explicit (\q1)
+    #[structopt(long, default_value = "preserve")]
+    marker_levels: MarkerLevels,
+}
+
+impl ReadOpt {
+    fn check_from(&self) -> Result<(), Failure> {
+        match self.from.as_deref().filter(|from| !FORMATS.contains(from)) {
+            Some(from) => Err(Failure::Usage(format!("unsupported input format '{}', expected one of {}", from, FORMATS.join(", ")))),
+            None => Ok(()),
+        }
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        let mut options = if self.lenient { ParseOptions::lenient() } else { ParseOptions::default() };
+        options.marker_levels = self.marker_levels;
+        options
+    }
+}
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "usx_tool")]
-struct Opt {
-    /// Input file path
+struct ConvertOpt {
+    /// Input files, `-` for standard input; a directory, a .zip archive or several inputs convert every book they hold
+    #[structopt(required = true)]
+    inputs: Vec<String>,
+
+    #[structopt(flatten)]
+    read: ReadOpt,
+
+    /// Output format: usfm, usx, usj, json or txt; guessed from the extension of --output otherwise
+    #[structopt(long)]
+    to: Option<String>,
+
+    /// Output file, `-` for standard output (the default)
     #[structopt(short, long)]
-    input: String,
+    output: Option<String>,
+
+    /// Directory to write the books to, when converting several books
+    #[structopt(long)]
+    out_dir: Option<String>,
+
+    /// Write every book to the single --output, standard output by default; only for --to txt
+    #[structopt(long)]
+    combine: bool,
 
-    /// Output file path
+    /// How many books to convert at the same time, one per CPU by default
     #[structopt(short, long)]
-    output: String,
+    jobs: Option<usize>,
+
+    /// Check USJ input against the USJ schema before converting it
+    #[structopt(long)]
+    validate: bool,
+
+    /// Keep the layout of USFM input, so that writing it back to USFM changes nothing
+    #[structopt(long)]
+    lossless: bool,
+
+    /// Write USFM output in canonical form
+    #[structopt(long)]
+    canonical: bool,
+
+    /// Keep verses in the line of their paragraph in canonical USFM
+    #[structopt(long)]
+    inline_verses: bool,
+
+    /// Wrap canonical USFM at this many columns
+    #[structopt(long)]
+    wrap: Option<usize>,
+
+    /// End the lines of canonical USFM with CRLF
+    #[structopt(long)]
+    crlf: bool,
 }
 
-/// This function initializes the deserialization process for a USX file and
-/// processes it using the `AosjStringModel`.
-fn main() {
+impl ConvertOpt {
+    /// The layout asked for canonical USFM, if any.
+    fn layout(&self) -> Option<UsfmLayout> {
+        (self.canonical || self.inline_verses || self.wrap.is_some() || self.crlf).then_some(UsfmLayout {
+            verse_on_new_line: !self.inline_verses,
+            max_line_width: self.wrap,
+            line_ending: if self.crlf { LineEnding::CrLf } else { LineEnding::Lf },
+        })
+    }
+}
 
-    let opt = Opt::from_args();
+#[derive(StructOpt, Debug)]
+struct ValidateOpt {
+    /// Input files, `-` for standard input, directories or .zip archives
+    #[structopt(required = true)]
+    inputs: Vec<String>,
 
-    let mut input_file_path = opt.input;
-    let output = opt.output;
+    #[structopt(flatten)]
+    read: ReadOpt,
 
-    let mut file = File::open(input_file_path.clone()).unwrap();
-    let mut content = String::new();
-    file.read_to_string(&mut content).unwrap();
-
-    // use std::time::Instant;
-    // let now = Instant::now();
-
-    if input_file_path.ends_with("usx") {
-        let model = deserialize_from_file_usx::<AosjStringModel>(content);
-        // println!("{}", model);
-        if output == "json" || output == "usj" {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            println!("{}", usj);
-        } else if output == "usfm" {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            let usfm = serialize_to_usfm::serialize_to_usfm(usj);
-            println!("{}", usfm);
-        } else if output == "usx" {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            let usx = serialize_to_usx::serialize_to_usx(usj);
-            println!("{}", usx);
-        } else {
-            eprintln!("Unsupported output file format for .usx input.");
+    /// Also fail when a book has warnings
+    #[structopt(long)]
+    strict: bool,
+}
+
+/// The formats read, by their `--from` names.
+const FORMATS: [&str; 4] = ["usfm", "usx", "usj", "json"];
+
+/// The formats written, by their `--to` names; plain text cannot be read back.
+const OUTPUT_FORMATS: [&str; 5] = ["usfm", "usx", "usj", "json", "txt"];
+
+/// What stopped a command, and the exit code it gives.
+enum Failure {
+    /// Some books could not be read or written; they have been reported.
+    Books,
+    /// The command line cannot be run as it is.
+    Usage(String),
+}
+
+fn main() -> ExitCode {
+    // structopt would exit with 1 on a bad command line, the code of books that failed
+    let command = match Command::from_iter_safe(std::env::args_os()) {
+        Ok(command) => command,
+        Err(err) if err.use_stderr() => {
+            eprintln!("{}", err.message);
+            return ExitCode::from(2);
         }
-    } else if input_file_path.ends_with("json") {
-        let model = deserialize_from_file_usj::<AosjStringModel>(serde_json::from_str(content.as_str()).unwrap());
-        if output == "usx" {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            let usx = serialize_to_usx::serialize_to_usx(usj);
-            println!("{}", usx);
-        } else if output == "usfm" {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            let usfm = serialize_to_usfm::serialize_to_usfm(usj);
-            println!("{}", usfm);
-        } else if output == "json" || output == "usj" {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            println!("{}", usj);
-        } else {
-            eprintln!("Unsupported output file format for .usj input.");
+        Err(err) => {
+            println!("{}", err.message);
+            return ExitCode::SUCCESS;
         }
-    } else if input_file_path.ends_with("usfm") {
-        let model = deserialize_from_file_usfm::<AosjStringModel>(content);
-        if output == "json" || output == "usj" {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            println!("{}", usj);
-        } else if output == "usx" {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            let usx = serialize_to_usx::serialize_to_usx(usj);
-            println!("{}", usx);
-        } else if output == "usfm" {
-            let usj = serialize_to_usj::serialize_to_usj(model);
-            let usfm = serialize_to_usfm::serialize_to_usfm(usj);
-            println!("{}", usfm);
-        } else {
-            eprintln!("Unsupported output file format for .usfm input.");
+    };
+    let result = match command {
+        Command::Convert(opt) => convert(&opt),
+        Command::Validate(opt) => validate(&opt),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::Books) => ExitCode::from(1),
+        Err(Failure::Usage(message)) => {
+            eprintln!("error: {}", message);
+            ExitCode::from(2)
         }
-    } else {
-        eprintln!("Unsupported input file format.");
     }
+}
 
-    // let elapsed = now.elapsed();
-    // println!("Elapsed: {:.2?}", elapsed);
+fn convert(opt: &ConvertOpt) -> Result<(), Failure> {
+    let mut options = opt.read.parse_options();
+    options.validate_usj = opt.validate;
+    options.lossless = opt.lossless;
 
+    let to = match (&opt.to, opt.output.as_deref().filter(|output| *output != "-")) {
+        (Some(to), _) => to.clone(),
+        (None, Some(output)) => format_from_extension(output)
+            .or_else(|| output.to_ascii_lowercase().ends_with(".txt").then_some("txt"))
+            .ok_or_else(|| Failure::Usage(format!("cannot tell the output format of '{}', pass --to", output)))?
+            .to_string(),
+        (None, None) => return Err(Failure::Usage("pass --to, or --output with the extension of the format".to_string())),
+    };
+    if !OUTPUT_FORMATS.contains(&to.as_str()) {
+        return Err(Failure::Usage(format!("unsupported output format '{}', expected one of {}", to, OUTPUT_FORMATS.join(", "))));
+    }
+    if opt.combine && to != "txt" {
+        return Err(Failure::Usage("--combine writes plain text only, pass --to txt".to_string()));
+    }
+    if opt.layout().is_some() && to != "usfm" {
+        return Err(Failure::Usage("--canonical, --inline-verses, --wrap and --crlf lay out USFM only, pass --to usfm".to_string()));
+    }
+    opt.read.check_from()?;
 
-    // pour run le code : cargo run -- --input chemin_vers_le_fichier_d_entrée --output extension_fichier_de_sortie>nom_du_fichier_de_sortie
+    if is_book_set(&opt.inputs) {
+        return convert_books(opt, &to, &options);
+    }
+    if opt.out_dir.is_some() {
+        return Err(Failure::Usage("--out-dir is for several books, use --output for one".to_string()));
+    }
+    let book = read_input(&opt.inputs[0], opt.read.from.as_deref())?;
+    let (root, warnings) = deserialize_with_options(book.content, &book.format, &options)
+        .map_err(|err| report(&book.name, err))?;
+    for warning in warnings {
+        eprintln!("{}: warning: {}", book.name, warning);
+    }
+    let output = match opt.layout() {
+        Some(layout) => format_usfm(&root, &layout),
+        _ => serialize(&root, &to).map_err(|err| report(&book.name, err))?,
+    };
+    write_output(opt.output.as_deref().unwrap_or("-"), &output)
+}
 
+/// Converts every book of the inputs into `--out-dir`, one file per book named after it, or
+/// into a single `--output` with `--combine`.
+fn convert_books(opt: &ConvertOpt, to: &str, options: &ParseOptions) -> Result<(), Failure> {
+    if opt.combine {
+        if opt.out_dir.is_some() {
+            return Err(Failure::Usage("--combine writes a single --output, not --out-dir".to_string()));
+        }
+        let results = convert_book_set(opt, to, options)?;
+        let failed = report_books(&results);
+        write_output(opt.output.as_deref().unwrap_or("-"), &combine_text(&results))?;
+        eprintln!("Converted {} of {} books.", results.len() - failed, results.len());
+        return if failed == 0 { Ok(()) } else { Err(Failure::Books) };
+    }
 
-    // // let input_file_path = "PSA.usx";
-    // // let input_file_path = "./assets/usj/small.json";
-    // let input_file_path = "./assets/usfm/65-3JN.usfm";
-    //
-    // if input_file_path.ends_with(".usx") {
-    //     let a = deserialize_from_file_path_usx::<AosjStringModel>(input_file_path);
-    //     // println!("{}", a);
-    //     let d = serialize_to_usj::serialize_to_usj(a, "output_usx_to_usj.json");
-    //     serialize_to_usx::serialize_to_usx(d, "output_usj_to_usx.usx");
-    // } else if input_file_path.ends_with(".json") {
-    //     let b = deserialize_from_file_path_usj::<AosjStringModel>(input_file_path);
-    //     serialize_to_usj::serialize_to_usj(b, "output_usj.json");
-    // } else if input_file_path.ends_with(".usfm") {
-    //     let c = deserialize_from_file_path_usfm::<AosjStringModel>(input_file_path);
-    //     // println!("{}", c);
-    //     let f = serialize_to_usj::serialize_to_usj(c, "output_usfm_to_usj.json");
-    //     serialize_to_usfm::serialize_to_usfm(f.clone(), "output_usj_to_usfm.usfm");
-    //     // serialize_to_usx::serialize_to_usx(f, "output_usfm_to_usx.usx")
-    // }
+    let out_dir = opt.out_dir.as_ref()
+        .ok_or_else(|| Failure::Usage("converting several books needs --out-dir or --combine".to_string()))?;
+    if opt.output.is_some() {
+        return Err(Failure::Usage("--output is for one book, use --out-dir for several".to_string()));
+    }
+    std::fs::create_dir_all(out_dir)
+        .map_err(|err| Failure::Usage(format!("cannot create '{}': {}", out_dir, err)))?;
+
+    let results = convert_book_set(opt, to, options)?;
+    let mut failed = report_books(&results);
+    for result in &results {
+        if let Ok(output) = &result.output {
+            let path = Path::new(out_dir).join(output_name(&result.name, to));
+            if let Err(err) = std::fs::write(&path, output) {
+                eprintln!("Cannot write '{}': {}", path.display(), err);
+                failed += 1;
+            }
+        }
+    }
+    eprintln!("Converted {} of {} books.", results.len() - failed, results.len());
+    if failed == 0 { Ok(()) } else { Err(Failure::Books) }
+}
+
+/// Reads and converts the books of the inputs, failing before converting any of them if
+/// two books would be written to the same file.
+fn convert_book_set(opt: &ConvertOpt, to: &str, options: &ParseOptions) -> Result<Vec<BookResult>, Failure> {
+    let books = read_book_set(&opt.inputs, opt.read.from.as_deref())?;
+    if !opt.combine {
+        let mut names: BTreeMap<String, &str> = BTreeMap::new();
+        for book in &books {
+            if let Some(other) = names.insert(output_name(&book.name, to), &book.name) {
+                return Err(Failure::Usage(format!("'{}' and '{}' would both be written to '{}'", other, book.name, output_name(&book.name, to))));
+            }
+        }
+    }
+
+    if let Some(jobs) = opt.jobs {
+        #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+        if let Err(err) = rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global() {
+            return Err(Failure::Usage(format!("cannot start {} jobs: {}", jobs, err)));
+        }
+        #[cfg(not(all(feature = "parallel", not(target_arch = "wasm32"))))]
+        if jobs != 1 {
+            eprintln!("warning: built without the parallel feature, --jobs {} is ignored and books are converted one at a time", jobs);
+        }
+    }
+    Ok(match opt.layout() {
+        Some(layout) => format_usfm_books(books, &layout, options),
+        _ => transform_books(books, to, options),
+    })
+}
+
+/// Prints the warnings and errors of converted books, giving back how many failed.
+fn report_books(results: &[BookResult]) -> usize {
+    let mut failed = 0;
+    for result in results {
+        for warning in &result.warnings {
+            eprintln!("{}: warning: {}", result.name, warning);
+        }
+        if let Err(err) = &result.output {
+            eprintln!("{}: {}", result.name, err);
+            failed += 1;
+        }
+    }
+    failed
 }
 
+/// The name of the file a book is written to in `--out-dir`: that of its input with the
+/// extension of the output format.
+fn output_name(name: &str, to: &str) -> String {
+    let stem = Path::new(name).file_stem().map_or_else(|| name.to_string(), |stem| stem.to_string_lossy().to_string());
+    format!("{}.{}", stem, to)
+}
+
+fn validate(opt: &ValidateOpt) -> Result<(), Failure> {
+    opt.read.check_from()?;
+    let options = opt.read.parse_options();
+    let books = if is_book_set(&opt.inputs) {
+        read_book_set(&opt.inputs, opt.read.from.as_deref())?
+    } else {
+        vec![read_input(&opt.inputs[0], opt.read.from.as_deref())?]
+    };
+    let total = books.len();
+    let mut failed = 0;
+    for book in books {
+        let name = book.name.clone();
+        // Every schema issue is listed, where `ParseOptions::validate_usj` stops at the first
+        if matches!(book.format.as_str(), "usj" | "json") {
+            if let Ok(json) = serde_json::from_str(&book.content) {
+                let issues = validate_usj(&json);
+                for issue in &issues {
+                    eprintln!("{}: {}", name, issue);
+                }
+                if !issues.is_empty() {
+                    failed += 1;
+                    continue;
+                }
+            }
+        }
+        match deserialize_with_options(book.content, &book.format, &options) {
+            Ok((_, warnings)) => {
+                for warning in &warnings {
+                    eprintln!("{}: warning: {}", name, warning);
+                }
+                if opt.strict && !warnings.is_empty() {
+                    failed += 1;
+                }
+            }
+            Err(err) => {
+                eprintln!("{}: {}", name, err);
+                failed += 1;
+            }
+        }
+    }
+    eprintln!("{} of {} books valid.", total - failed, total);
+    if failed == 0 { Ok(()) } else { Err(Failure::Books) }
+}
+
+/// Whether the inputs hold several books: several inputs, a directory or an archive.
+fn is_book_set(inputs: &[String]) -> bool {
+    inputs.len() > 1 || Path::new(&inputs[0]).is_dir() || inputs[0].to_ascii_lowercase().ends_with(".zip")
+}
+
+/// Reads the books of a directory, an archive or a list of files, `--from` giving the
+/// format of every book when it is set.
+fn read_book_set(inputs: &[String], from: Option<&str>) -> Result<Vec<BookSource>, Failure> {
+    if inputs.len() > 1 {
+        return inputs.iter().map(|input| read_input(input, from)).collect();
+    }
+    let mut books = read_books(Path::new(&inputs[0])).map_err(|err| {
+        eprintln!("{}", err);
+        Failure::Books
+    })?;
+    if let Some(from) = from {
+        for book in &mut books {
+            book.format = from.to_string();
+        }
+    }
+    Ok(books)
+}
+
+/// Reads one book from a file or, for `-`, from standard input. Its format is `from`, or comes
+/// from the extension of the file, or from the content.
+fn read_input(input: &str, from: Option<&str>) -> Result<BookSource, Failure> {
+    let mut content = String::new();
+    let read = if input == "-" {
+        std::io::stdin().read_to_string(&mut content).map(|_| ())
+    } else {
+        std::fs::read_to_string(input).map(|text| content = text)
+    };
+    if let Err(err) = read {
+        eprintln!("Cannot read '{}': {}", input, err);
+        return Err(Failure::Books);
+    }
+    let name = if input == "-" { "<stdin>".to_string() } else { input.to_string() };
+    let format = from
+        .or_else(|| format_from_extension(input))
+        .or_else(|| detect_format(&content))
+        .ok_or_else(|| Failure::Usage(format!("cannot tell the format of '{}', pass --from", name)))?;
+    Ok(BookSource { name, format: format.to_string(), content })
+}
+
+/// Writes the output to a file or, for `-`, to standard output.
+fn write_output(output: &str, content: &str) -> Result<(), Failure> {
+    let written = if output == "-" {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(content.as_bytes()).and_then(|_| stdout.flush())
+    } else {
+        std::fs::write(output, content)
+    };
+    match written {
+        Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+            eprintln!("Cannot write '{}': {}", output, err);
+            Err(Failure::Books)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Prints an error about a book, and fails the command.
+fn report(name: &str, err: HallomaiError) -> Failure {
+    eprintln!("{}: {}", name, err);
+    Failure::Books
+}
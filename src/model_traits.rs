@@ -8,14 +8,14 @@ use crate::aosj_string::element::Element;
 /// This trait provides an interface for constructing and managing the elements
 /// and attributes of a document model, allowing for flexible implementations.
 pub trait AosjModel {
-    /// Creates a new instance of the model.
-    fn new() -> Self;
+    /// What the model assembles into once the whole document has been read.
+    type Output;
     /// Pushes an element to the parent elements stack.
     fn push_element(&mut self, attributes: BTreeMap<String,String>, tag_name: String);
     /// Retrieves a formatted string of attributes.
     fn get_attributes(&self) -> String;
     /// Adds root metadata to the model.
-    fn add_root_metadata(&mut self, version_value: &String);
+    fn add_root_metadata(&mut self, version_value: &str);
     /// Starts a new book with given attributes.
     fn start_book(&mut self, attributes: String);
     /// Ends the current book.
@@ -34,14 +34,46 @@ pub trait AosjModel {
     fn add_milestone(&mut self, attributes: String);
     /// Starts adding a character marker.
     fn start_add_char_marker(&mut self, attributes: String);
+    /// Adds the attributes read after the start of the current character marker.
+    fn add_char_attributes(&mut self, attributes: String);
     /// Ends the character marker addition.
     fn end_add_char_marker(&mut self, txt: &mut Vec<String>);
     /// Starts adding a note with given attributes.
     fn start_add_note(&mut self, attributes: String);
     /// Ends the note addition.
     fn end_add_note(&mut self, txt: &mut Vec<String>);
-    /// Assembles the model into a JSON string.
-    fn assemble_model(&self) -> String;
+    /// Adds an optional line break to the current paragraph.
+    fn add_optbreak(&mut self, attributes: String);
+    /// Starts a scripture reference, whose text is read until `end_ref`.
+    fn start_ref(&mut self, attributes: String);
+    /// Ends the current scripture reference.
+    fn end_ref(&mut self, txt: &mut Vec<String>);
+    /// Starts a figure, whose caption is read until `end_figure`.
+    fn start_figure(&mut self, attributes: String);
+    /// Ends the current figure.
+    fn end_figure(&mut self, txt: &mut Vec<String>);
+    /// Starts a table.
+    fn start_table(&mut self, attributes: String);
+    /// Ends the current table.
+    fn end_table(&mut self);
+    /// Starts a row of the current table.
+    fn start_row(&mut self, attributes: String);
+    /// Ends the current row.
+    fn end_row(&mut self);
+    /// Starts a cell of the current row.
+    fn start_cell(&mut self, attributes: String);
+    /// Ends the current cell.
+    fn end_cell(&mut self, txt: &mut Vec<String>);
+    /// Starts a sidebar, whose paragraphs and tables are read until `end_sidebar`.
+    fn start_sidebar(&mut self, attributes: String);
+    /// Ends the current sidebar.
+    fn end_sidebar(&mut self);
+    /// Keeps the USFM markup opening the next object, when reading with `ParseOptions::lossless`.
+    fn keep_usfm_open(&mut self, _open: String) {}
+    /// Keeps the USFM markup closing the current object, when reading with `ParseOptions::lossless`.
+    fn keep_usfm_close(&mut self, _close: String) {}
+    /// Assembles the model into its final representation.
+    fn assemble_model(self) -> Self::Output;
     /// Returns a mutable reference to the parent elements stack.
     fn parent_els(&mut self) -> &mut Vec<Element>;
 
@@ -0,0 +1,89 @@
+/// # What the USFM reader does with a marker it does not know.
+///
+/// User-defined `\z` markers are always accepted, whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownMarkerPolicy {
+    /// Stop with `HallomaiError::UnknownMarker`.
+    #[default]
+    Error,
+    /// Read the marker as a `char` when it is closed by `\marker*` further in
+    /// the paragraph, as a `para` otherwise, and report a `Warning`.
+    Lenient,
+}
+
+/// # How the level 1 of numbered markers such as `\q1` is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MarkerLevels {
+    /// Keep `\q` and `\q1` as they were read.
+    #[default]
+    Preserve,
+    /// Leave level 1 implicit: `\q1` becomes `\q`. `\toc1` and `\toca1` keep their number.
+    Implicit,
+    /// Always write the level: `\q` becomes `\q1`.
+    Explicit,
+}
+
+impl std::str::FromStr for MarkerLevels {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "preserve" => Ok(MarkerLevels::Preserve),
+            "implicit" => Ok(MarkerLevels::Implicit),
+            "explicit" => Ok(MarkerLevels::Explicit),
+            _ => Err(format!("Unknown marker levels '{}', expected 'preserve', 'implicit' or 'explicit'", value)),
+        }
+    }
+}
+
+/// # Options for reading a document.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParseOptions {
+    pub unknown_markers: UnknownMarkerPolicy,
+    pub marker_levels: MarkerLevels,
+    /// Check USJ input against the bundled USJ schema, failing with
+    /// `HallomaiError::Schema` on the first issue.
+    pub validate_usj: bool,
+    /// Keep the text of USFM input as written and the markup of every object, so that
+    /// writing the document back to USFM gives the same bytes. Objects changed in
+    /// between are written the usual way.
+    pub lossless: bool,
+}
+
+impl ParseOptions {
+    /// Options that read legacy and custom USFM instead of failing on it.
+    pub fn lenient() -> Self {
+        ParseOptions {
+            unknown_markers: UnknownMarkerPolicy::Lenient,
+            ..ParseOptions::default()
+        }
+    }
+}
+
+/// # The line endings written by `format_usfm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+/// # How `format_usfm` lays out USFM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsfmLayout {
+    /// Start every verse on a line of its own, rather than where it falls in its paragraph.
+    pub verse_on_new_line: bool,
+    /// Break the lines longer than this many characters at the spaces between words.
+    pub max_line_width: Option<usize>,
+    pub line_ending: LineEnding,
+}
+
+impl Default for UsfmLayout {
+    fn default() -> Self {
+        UsfmLayout {
+            verse_on_new_line: true,
+            max_line_width: None,
+            line_ending: LineEnding::Lf,
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::structs_model::{Content, InParaObject};
+use crate::structs_model::root::Root;
+use crate::utils_usfm;
+
+/// Writes the typed model as plain text: one line per paragraph, heading or table row, with
+/// the chapter numbers on their own line and the verse numbers before their text. Notes,
+/// figures, milestones and identification lines such as `\h` or `\toc1` are left out.
+pub fn serialize_to_text(root: &Root) -> String {
+    let mut out = String::new();
+    write_blocks(&root.content, &mut out);
+    out
+}
+
+fn write_blocks(content: &[Content], out: &mut String) {
+    for block in content {
+        match block {
+            Content::Book(_) => {}
+            Content::Chapter(chapter) => write_line(&chapter.number, out),
+            Content::Para(para) => {
+                if !is_identification(&para.marker) {
+                    let mut line = String::new();
+                    write_in_para(para.content.iter().flatten(), &mut line);
+                    write_line(&collapse_whitespace(&line), out);
+                }
+            }
+            Content::Table(table) => {
+                for row in table.content.iter().flatten() {
+                    let cells: Vec<String> = row.content.iter().flatten().map(|cell| {
+                        let mut text = String::new();
+                        write_in_para(cell.content.iter().flatten(), &mut text);
+                        collapse_whitespace(&text)
+                    }).collect();
+                    write_line(&cells.join("\t"), out);
+                }
+            }
+            Content::Sidebar(sidebar) => write_blocks(sidebar.content.as_deref().unwrap_or_default(), out),
+        }
+    }
+}
+
+fn write_in_para<'a>(content: impl Iterator<Item = &'a InParaObject>, line: &mut String) {
+    for object in content {
+        match object {
+            InParaObject::String(text) => line.push_str(text),
+            InParaObject::Verse(verse) => {
+                if !line.is_empty() && !line.ends_with(char::is_whitespace) {
+                    line.push(' ');
+                }
+                line.push_str(&verse.number);
+                line.push(' ');
+            }
+            InParaObject::Char(char) => write_in_para(char.content.iter().flatten(), line),
+            InParaObject::Ref(r#ref) => r#ref.content.iter().flatten().for_each(|text| line.push_str(text)),
+            InParaObject::Note(_) | InParaObject::Milestone(_) | InParaObject::Figure(_) | InParaObject::OptBreak => {}
+        }
+    }
+}
+
+/// Writes a line, unless it holds no text.
+fn write_line(text: &str, out: &mut String) {
+    if !text.trim().is_empty() {
+        out.push_str(text);
+        out.push('\n');
+    }
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Paragraphs that describe the book rather than being read: `\ide`, `\usfm`, `\sts`, `\rem`,
+/// `\h` and the `\toc` and `\toca` lines.
+fn is_identification(marker: &str) -> bool {
+    matches!(utils_usfm::split_level(marker).0, "ide" | "usfm" | "sts" | "rem" | "h" | "toc" | "toca")
+}
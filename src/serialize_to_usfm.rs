@@ -1,127 +1,478 @@
 #![allow(dead_code)]
 
-use serde_json::Value;
-use std::io::{BufWriter, Write};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use crate::utils_usfm;
+use crate::options::{LineEnding, UsfmLayout};
+use crate::structs_model::{CellObject, CharMarkerObject, Content, FigureObject, InParaObject, MilestoneObject, NoteObject, RefObject, Root, UsfmSource};
 
-pub fn serialize_to_usfm(usj: Value) -> String {
-    let mut writer = BufWriter::new(Vec::new());
+/// The markup written before and after the content of an object.
+type Markup = (String, String);
 
-    if let Some(version) = usj.get("version").and_then(|v| v.as_str()) {
-        writeln!(writer, "\\usfm {}", version).unwrap();
+/// What an in-paragraph object is written in: character markers nested in another one are
+/// written `\+marker`, and the character markers of a note content are left unclosed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Nesting {
+    Para,
+    Note,
+    Char,
+}
+
+pub fn serialize_to_usfm(root: &Root) -> String {
+    UsfmWriter::new(&UsfmLayout::default(), true).write(root)
+}
+
+/// Writes `root` in canonical USFM laid out as asked by `layout`, whatever markup
+/// and whitespace it was read with.
+pub fn format_usfm(root: &Root, layout: &UsfmLayout) -> String {
+    UsfmWriter::new(layout, false).write(root)
+}
+
+struct UsfmWriter<'a> {
+    out: String,
+    layout: &'a UsfmLayout,
+    /// Write the objects read with `ParseOptions::lossless` with their own markup and text.
+    keep_source: bool,
+}
+
+impl<'a> UsfmWriter<'a> {
+    fn new(layout: &'a UsfmLayout, keep_source: bool) -> Self {
+        UsfmWriter {
+            out: String::new(),
+            layout,
+            keep_source,
+        }
     }
 
-    if let Some(content) = usj.get("content").and_then(|c| c.as_array()) {
+    fn write(mut self, root: &Root) -> String {
+        let mut content = root.content.iter().peekable();
+
+        // \usfm directly follows the \id line
+        if let Some(book) = content.next_if(|c| matches!(c, Content::Book(_))) {
+            self.write_block(book);
+        }
+        if !root.version.is_empty() {
+            self.write_object(version_markup(), &root.source, |writer| writer.write_text(&root.version));
+        }
         for element in content {
-            if let Some(obj) = element.as_object() {
-                match obj.get("type").and_then(|t| t.as_str()) {
-                    Some("book") => {
-                        write!(writer, "\\id {} ", obj.get("code").unwrap().as_str().unwrap()).unwrap();
-                        if let Some(content) = obj.get("content").and_then(|c| c.as_array()) {
-                            for value in content {
-                                if let Some(text) = value.as_str() {
-                                    write!(writer, "{}", text).unwrap();
-                                }
-                            }
-                        }
-                        writeln!(writer).unwrap();
+            self.write_block(element);
+        }
+
+        if let Some(width) = self.layout.max_line_width {
+            self.out = wrap(&self.out, width);
+        }
+        if self.layout.line_ending == LineEnding::CrLf {
+            self.out = self.out.replace('\n', "\r\n");
+        }
+        self.out
+    }
+
+    /// Writes an object between its markup, or between the markup it was read with if
+    /// the object would still be written the same way.
+    fn write_object(&mut self, markup: Markup, source: &Option<UsfmSource>, content: impl FnOnce(&mut Self)) {
+        match source {
+            Some(source) if self.keep_source && source.markup == markup => {
+                self.out.push_str(&source.open);
+                content(self);
+                self.out.push_str(&source.close);
+            }
+            _ => {
+                self.write_markup(&markup.0);
+                content(self);
+                self.write_markup(&markup.1);
+            }
+        }
+    }
+
+    /// Writes markup, with no space left at the end of a line, no space doubled and
+    /// no empty line.
+    fn write_markup(&mut self, markup: &str) {
+        let mut markup = markup;
+        if markup.starts_with('\n') {
+            self.out.truncate(self.out.trim_end_matches([' ', '\t']).len());
+            if self.out.is_empty() || self.out.ends_with('\n') {
+                markup = &markup[1..];
+            }
+        } else if self.out.is_empty() || self.out.ends_with(char::is_whitespace) {
+            markup = markup.trim_start_matches(' ');
+        }
+        self.out.push_str(markup);
+    }
+
+    /// Writes text; canonical USFM has its whitespace collapsed to single spaces.
+    fn write_text(&mut self, text: &str) {
+        if self.keep_source {
+            self.out.push_str(text);
+            return;
+        }
+        let mut words = text.split_whitespace().peekable();
+        if text.starts_with(char::is_whitespace) && words.peek().is_some() && !self.out.is_empty() && !self.out.ends_with(char::is_whitespace) {
+            self.out.push(' ');
+        }
+        while let Some(word) = words.next() {
+            self.out.push_str(word);
+            if words.peek().is_some() {
+                self.out.push(' ');
+            }
+        }
+        if text.ends_with(char::is_whitespace) && !text.trim().is_empty() {
+            self.out.push(' ');
+        }
+    }
+
+    /// Writes an object of the root content, or of a sidebar.
+    fn write_block(&mut self, block: &Content) {
+        match block {
+            Content::Book(book) => {
+                self.write_object(book_markup(&book.code), &book.source, |writer| {
+                    for text in book.content.iter().flatten() {
+                        writer.write_text(text);
                     }
-                    Some("para") => {
-                        let marker = obj.get("marker").unwrap().as_str().unwrap();
-                        write!(writer, r"\{} ", marker).unwrap();
-                        if let Some(content) = obj.get("content").and_then(|c| c.as_array()) {
-                            for value in content {
-                                write_content(value, &mut writer, false);
-                            }
-                        }
-                        writeln!(writer).unwrap();
+                });
+            }
+            Content::Para(para) => {
+                self.write_object(para_markup(&para.marker), &para.source, |writer| {
+                    writer.write_in_para(para.content.iter().flatten(), Nesting::Para);
+                });
+            }
+            Content::Sidebar(sidebar) => {
+                self.write_object(sidebar_markup(&sidebar.marker, &sidebar.category), &sidebar.source, |writer| {
+                    for block in sidebar.content.iter().flatten() {
+                        writer.write_block(block);
                     }
-                    Some("chapter") => {
-                        let number = obj.get("number").unwrap().as_str().unwrap();
-                        writeln!(writer, r"\c {}", number).unwrap();
+                });
+            }
+            Content::Chapter(chapter) => {
+                let markup = number_markup("c", &chapter.number, &chapter.altnumber, &chapter.pubnumber, true);
+                self.write_object(markup, &chapter.source, |_| {});
+            }
+            Content::Table(table) => {
+                for row in table.content.iter().flatten() {
+                    self.write_object(para_markup(&row.marker), &row.source, |writer| {
+                        for cell in row.content.iter().flatten() {
+                            writer.write_object(cell_markup(cell), &cell.source, |writer| {
+                                writer.write_in_para(cell.content.iter().flatten(), Nesting::Para);
+                            });
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    fn write_in_para<'b>(&mut self, content: impl Iterator<Item = &'b InParaObject>, nesting: Nesting) {
+        let mut content = content.peekable();
+        while let Some(object) = content.next() {
+            match object {
+                InParaObject::String(text) => {
+                    self.write_text(text);
+                }
+                InParaObject::Verse(verse) => {
+                    let markup = number_markup("v", &verse.number, &verse.altnumber, &verse.pubnumber, self.layout.verse_on_new_line);
+                    self.write_object(markup, &verse.source, |_| {});
+                }
+                InParaObject::Char(char) => {
+                    self.write_object(char_markup(char, nesting, content.peek().copied()), &char.source, |writer| {
+                        writer.write_in_para(char.content.iter().flatten(), Nesting::Char);
+                    });
+                }
+                InParaObject::Milestone(ms) => {
+                    self.write_object(milestone_markup(ms), &ms.source, |_| {});
+                }
+                InParaObject::Note(note) => {
+                    self.write_object(note_markup(note), &note.source, |writer| {
+                        writer.write_in_para(note.content.iter().flatten(), Nesting::Note);
+                    });
+                }
+                InParaObject::OptBreak => {
+                    self.out.push_str("//");
+                }
+                InParaObject::Ref(r#ref) => {
+                    self.write_object(ref_markup(r#ref), &r#ref.source, |writer| {
+                        for text in r#ref.content.iter().flatten() {
+                            writer.write_text(text);
+                        }
+                    });
+                }
+                InParaObject::Figure(figure) => {
+                    self.write_object(figure_markup(figure), &figure.source, |writer| {
+                        for text in figure.content.iter().flatten() {
+                            writer.write_text(text);
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Breaks the lines longer than `width` characters at the spaces between words,
+/// never inside the attributes of a marker nor right after a marker.
+fn wrap(usfm: &str, width: usize) -> String {
+    let mut wrapped = String::new();
+    for line in usfm.split_inclusive('\n') {
+        let (line, newline) = line.strip_suffix('\n').map_or((line, ""), |line| (line, "\n"));
+        // the text of \id ends with its line
+        if line.starts_with(r"\id ") {
+            wrapped.push_str(line);
+            wrapped.push_str(newline);
+            continue;
+        }
+        let mut words = Vec::new();
+        let mut start = 0;
+        let mut in_attributes = false;
+        for (i, c) in line.char_indices() {
+            match c {
+                '|' => in_attributes = true,
+                '\\' => in_attributes = false,
+                ' ' if !in_attributes => {
+                    // `\v 1` and `\bd word` stay together
+                    let after_marker = line[start..i].rsplit_once('\\').is_some_and(|(_, marker)| !marker.contains(['*', ' ']));
+                    if !after_marker {
+                        words.push(&line[start..i]);
+                        start = i + 1;
                     }
-                    _ => {}
+                }
+                _ => {}
+            }
+        }
+        words.push(&line[start..]);
+
+        let mut column = 0;
+        for (i, word) in words.into_iter().enumerate() {
+            let length = word.chars().count();
+            if i > 0 {
+                if column + 1 + length > width {
+                    wrapped.push('\n');
+                    column = 0;
+                } else {
+                    wrapped.push(' ');
+                    column += 1;
                 }
             }
+            wrapped.push_str(word);
+            column += length;
         }
+        wrapped.push_str(newline);
     }
-    let buffer = writer.into_inner().expect("Failed to retrieve buffer");
+    wrapped
+}
 
-    let output_string = String::from_utf8(buffer).expect("Failed to convert buffer to string");
-    output_string
+fn version_markup() -> Markup {
+    ("\n\\usfm ".to_string(), "\n".to_string())
 }
 
+fn book_markup(code: &str) -> Markup {
+    (format!("\n\\id {} ", code), "\n".to_string())
+}
+
+/// The markup of a paragraph or of a table row.
+fn para_markup(marker: &str) -> Markup {
+    (format!("\n\\{} ", marker), "\n".to_string())
+}
+
+fn sidebar_markup(marker: &str, category: &Option<String>) -> Markup {
+    let mut open = format!("\n\\{}", marker);
+    if let Some(category) = category {
+        write!(open, r" \cat {}\cat*", category).unwrap();
+    }
+    open.push('\n');
+    (open, "\n\\esbe\n".to_string())
+}
+
+fn cell_markup(cell: &CellObject) -> Markup {
+    match &cell.colspan {
+        Some(colspan) => (format!(r" \{}-{} ", cell.marker, colspan), String::new()),
+        None => (format!(r" \{} ", cell.marker), String::new()),
+    }
+}
 
-fn write_content(content: &Value, writer: &mut BufWriter<Vec<u8>>, in_char: bool) {
-    match content {
-        Value::String(text) => {
-            write!(writer, "{}", text).unwrap();
+/// The markup of a chapter (`c`) or of a verse (`v`) with its alternate and publishing numbers.
+fn number_markup(marker: &str, number: &str, altnumber: &Option<String>, pubnumber: &Option<String>, new_line: bool) -> Markup {
+    let mut open = String::new();
+    if marker == "c" {
+        writeln!(open, "\n\\c {}", number).unwrap();
+        if let Some(altnumber) = altnumber {
+            writeln!(open, r"\ca {}\ca*", altnumber).unwrap();
         }
-        Value::Object(obj) => {
-            match obj.get("type").and_then(|t| t.as_str()) {
-                Some("verse") => {
-                    let number = obj.get("number").unwrap().as_str().unwrap();
-                    write!(writer, "\n\\v {} ", number).unwrap();
-                }
-                Some("char") => {
-                    let marker = obj.get("marker").unwrap().as_str().unwrap();
-                    let mut is_nested: String = "".to_string();
-                    if in_char {
-                        is_nested = "+".to_string();
-                        write!(writer, r"\{}{} ", is_nested, marker).unwrap();
-                    } else {
-                        write!(writer, r"\{} ", marker).unwrap();
-                    }
+        if let Some(pubnumber) = pubnumber {
+            writeln!(open, r"\cp {}", pubnumber).unwrap();
+        }
+    } else {
+        open.push(if new_line { '\n' } else { ' ' });
+        write!(open, r"\v {} ", number).unwrap();
+        if let Some(altnumber) = altnumber {
+            write!(open, r"\va {}\va* ", altnumber).unwrap();
+        }
+        if let Some(pubnumber) = pubnumber {
+            write!(open, r"\vp {}\vp* ", pubnumber).unwrap();
+        }
+    }
+    (open, String::new())
+}
 
-                    if let Some(content) = obj.get("content").and_then(|c| c.as_array()) {
-                        for value in content {
-                            write_content(value, writer, true);
-                        }
-                    }
+/// The markup of a character marker followed by `next`. In a note, `\ft`, `\fq`, ... without
+/// attributes are left unclosed when the next object is one of them or the end of the note.
+fn char_markup(char: &CharMarkerObject, nesting: Nesting, next: Option<&InParaObject>) -> Markup {
+    let nested = if nesting == Nesting::Char { "+" } else { "" };
+    let open = format!(r"\{}{} ", nested, char.marker);
 
-                    if obj.get("marker").and_then(|d| d.as_str()) == Some("w") {
-                        if let Some(default) = obj.get("default").and_then(|d| d.as_str()) {
-                            write!(writer, "|{} ", default).unwrap();
-                        } else {
-                            write!(writer, " | ").unwrap();
-                            for (key, value) in obj.iter() {
-                                if key != "type" && key != "marker" && key != "content" && key != "default" {
-                                    write!(writer, "{}={}", key, value).unwrap();
-                                }
-                            }
-                        }
-                    }
+    let mut attributes: BTreeMap<&str, &str> = char.additional_properties.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    attributes.extend(char.link_id.as_deref().map(|v| ("link-id", v)));
+    attributes.extend(char.link_href.as_deref().map(|v| ("link-href", v)));
+    attributes.extend(char.srcloc.as_deref().map(|v| ("srcloc", v)));
+    attributes.extend(char.strong.as_deref().map(|v| ("strong", v)));
 
-                    for (key, value) in obj.iter() {
-                        if key != "type" && key != "marker" && key != "content" && key != "default"{
-                            write!(writer, r"{}={} ", key, value).unwrap();
-                        }
-                    }
-                    if !is_nested.is_empty() {
-                        write!(writer, r"\{}{}*", is_nested, marker).unwrap();
-                        is_nested.clear();
-                    } else {
-                        write!(writer, r"\{}*", marker).unwrap();
-                    }
-                }
-                Some("ms") => {
-                    let marker = obj.get("marker").unwrap().as_str().unwrap();
-                    write!(writer, r"\{}", marker).unwrap();
-
-                    let mut first_key_value = true;
-                    for (key, value) in obj.iter() {
-                        if key != "type" && key != "marker" && key != "content" {
-                            if first_key_value {
-                                write!(writer, " | ").unwrap();
-                                first_key_value = false;
-                            }
-                            write!(writer, r"{}={} ", key, value).unwrap();
-                        }
+    let mut close = attributes_markup(utils_usfm::default_attribute(&char.marker), &attributes);
+    let ends_with_note_content = |object: Option<&InParaObject>| match object {
+        None => true,
+        Some(InParaObject::Char(next)) => utils_usfm::is_note_content_marker(&next.marker),
+        Some(_) => false,
+    };
+    let unclosed = nesting == Nesting::Note && close.is_empty()
+        && utils_usfm::is_note_content_marker(&char.marker) && ends_with_note_content(next);
+    if !unclosed {
+        write!(close, r"\{}{}*", nested, char.marker).unwrap();
+    }
+    (open, close)
+}
+
+fn milestone_markup(ms: &MilestoneObject) -> Markup {
+    let mut attributes: BTreeMap<&str, &str> = ms.additional_properties.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    attributes.extend(ms.who.as_deref().map(|v| ("who", v)));
+    attributes.extend(ms.sid.as_deref().map(|v| ("sid", v)));
+    attributes.extend(ms.eid.as_deref().map(|v| ("eid", v)));
+
+    let separator = if attributes.is_empty() { "" } else { " " };
+    (format!(r"\{}{}{}\*", ms.marker, separator, attributes_markup(None, &attributes)), String::new())
+}
+
+/// The `|key="value"` list of attributes. A lone default attribute is written as `|value`
+/// when its value cannot be mistaken for markup.
+fn attributes_markup(default: Option<&str>, attributes: &BTreeMap<&str, &str>) -> String {
+    let mut markup = String::new();
+    if let Some((key, value)) = attributes.first_key_value().filter(|_| attributes.len() == 1) {
+        if (*key == "default" || Some(*key) == default) && !value.is_empty() && !value.contains(['|', '\\', '"']) {
+            write!(markup, "|{}", value).unwrap();
+            return markup;
+        }
+    }
+    let mut separator = "|";
+    for (key, value) in attributes {
+        write!(markup, r#"{}{}="{}""#, separator, key, escape_attribute(value)).unwrap();
+        separator = " ";
+    }
+    markup
+}
+
+fn escape_attribute(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', r#"\""#)
+}
+
+fn note_markup(note: &NoteObject) -> Markup {
+    let mut open = format!(r"\{} {} ", note.marker, note.caller.as_deref().unwrap_or("+"));
+    if let Some(category) = &note.category {
+        write!(open, r"\cat {}\cat* ", category).unwrap();
+    }
+    (open, format!(r"\{}*", note.marker))
+}
+
+fn ref_markup(r#ref: &RefObject) -> Markup {
+    let mut attributes: BTreeMap<&str, &str> = r#ref.additional_properties.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    attributes.extend(r#ref.loc.as_deref().map(|v| ("loc", v)));
+    attributes.extend(r#ref.gen.as_deref().map(|v| ("gen", v)));
+    let mut close = attributes_markup(utils_usfm::default_attribute("ref"), &attributes);
+    close.push_str(r"\ref*");
+    (r"\ref ".to_string(), close)
+}
+
+fn figure_markup(figure: &FigureObject) -> Markup {
+    let mut close = String::new();
+    let mut separator = "|";
+    for (key, value) in [("alt", &figure.alt), ("src", &figure.file), ("size", &figure.size), ("loc", &figure.loc), ("copy", &figure.copy), ("ref", &figure.r#ref)] {
+        if let Some(value) = value {
+            write!(close, r#"{}{}="{}""#, separator, key, escape_attribute(value)).unwrap();
+            separator = " ";
+        }
+    }
+    write!(close, r"\{}*", figure.marker).unwrap();
+    (format!(r"\{} ", figure.marker), close)
+}
+
+/// Records the markup the writer gives every object read with `ParseOptions::lossless`,
+/// so that the markup it was read with is only written back while the object is unchanged.
+pub(crate) fn keep_usfm_markup(root: &mut Root) {
+    stamp(&mut root.source, version_markup());
+    keep_block_markup(&mut root.content);
+}
+
+fn stamp(source: &mut Option<UsfmSource>, markup: Markup) {
+    if let Some(source) = source {
+        source.markup = markup;
+    }
+}
+
+fn keep_block_markup(content: &mut [Content]) {
+    for block in content {
+        match block {
+            Content::Book(book) => stamp(&mut book.source, book_markup(&book.code)),
+            Content::Para(para) => {
+                stamp(&mut para.source, para_markup(&para.marker));
+                keep_in_para_markup(para.content.as_deref_mut().unwrap_or_default(), Nesting::Para);
+            }
+            Content::Sidebar(sidebar) => {
+                stamp(&mut sidebar.source, sidebar_markup(&sidebar.marker, &sidebar.category));
+                keep_block_markup(sidebar.content.as_deref_mut().unwrap_or_default());
+            }
+            Content::Chapter(chapter) => {
+                let markup = number_markup("c", &chapter.number, &chapter.altnumber, &chapter.pubnumber, true);
+                stamp(&mut chapter.source, markup);
+            }
+            Content::Table(table) => {
+                for row in table.content.iter_mut().flatten() {
+                    stamp(&mut row.source, para_markup(&row.marker));
+                    for cell in row.content.iter_mut().flatten() {
+                        let markup = cell_markup(cell);
+                        stamp(&mut cell.source, markup);
+                        keep_in_para_markup(cell.content.as_deref_mut().unwrap_or_default(), Nesting::Para);
                     }
-                    write!(writer, r"\*").unwrap();
                 }
-                _ => {}
             }
         }
-        _ => {}
     }
-}
\ No newline at end of file
+}
+
+fn keep_in_para_markup(content: &mut [InParaObject], nesting: Nesting) {
+    for index in 0..content.len() {
+        let (current, rest) = content[index..].split_first_mut().unwrap();
+        match current {
+            InParaObject::Verse(verse) => {
+                let markup = number_markup("v", &verse.number, &verse.altnumber, &verse.pubnumber, true);
+                stamp(&mut verse.source, markup);
+            }
+            InParaObject::Char(char) => {
+                let markup = char_markup(char, nesting, rest.first());
+                stamp(&mut char.source, markup);
+                keep_in_para_markup(char.content.as_deref_mut().unwrap_or_default(), Nesting::Char);
+            }
+            InParaObject::Milestone(ms) => {
+                let markup = milestone_markup(ms);
+                stamp(&mut ms.source, markup);
+            }
+            InParaObject::Note(note) => {
+                let markup = note_markup(note);
+                stamp(&mut note.source, markup);
+                keep_in_para_markup(note.content.as_deref_mut().unwrap_or_default(), Nesting::Note);
+            }
+            InParaObject::Ref(r#ref) => {
+                let markup = ref_markup(r#ref);
+                stamp(&mut r#ref.source, markup);
+            }
+            InParaObject::Figure(figure) => {
+                let markup = figure_markup(figure);
+                stamp(&mut figure.source, markup);
+            }
+            InParaObject::OptBreak | InParaObject::String(_) => {}
+        }
+    }
+}
@@ -1,60 +1,74 @@
 #![allow(dead_code)]
-use serde_json::Value;
+use crate::structs_model::model::{Content, InParaObject};
+use crate::structs_model::root::Root;
 
 
-pub fn serialize_to_usj(mut content: String) -> Value {
-    let mut json_value: Value = serde_json::from_str(content.as_mut_str()).expect("Invalid JSON string");
-    if let Value::Object(ref mut map) = json_value {
-        map.insert("type".to_string(), Value::String("USJ".to_string()));
-    }
-    let json_with_sid = add_sid_to_usj(json_value);
-    // let formatted_json = to_string_pretty(&json_with_sid).expect("Failed to format JSON");
-    // let mut file = File::create(output_file_path).expect("Unable to create file");
-    // file.write_all(formatted_json.as_bytes()).expect("Unable to write data");
-    json_with_sid
+/// # Parses the JSON produced by an `AosjModel` into a typed `Root`.
+///
+/// The `sid` of every chapter and verse is computed from the book code.
+pub fn usj_to_root(content: &str) -> Root {
+    let mut root: Root = serde_json::from_str(content).expect("Invalid JSON string");
+    add_sid_to_usj(&mut root);
+    root
+}
+
+pub fn serialize_to_usj(root: &Root) -> String {
+    serde_json::to_string(root).expect("Failed to serialize USJ")
 }
 
-fn add_sid_to_usj(mut usj_json: Value) -> Value {
+/// Sets the `sid` of every chapter, and of every verse wherever it is nested:
+/// in paragraphs, tables, sidebars or character markers.
+pub fn add_sid_to_usj(root: &mut Root) {
 
     let mut book_code = String::new();
     let mut chapter_number = String::new();
 
-    if let Some(content_array) = usj_json.get_mut("content").and_then(|c| c.as_array_mut()) {
-        for content in content_array.iter_mut() {
-            if let Some(content_obj) = content.as_object_mut() {
-                match content_obj.get("type").and_then(|t| t.as_str()) {
-                    Some("book") => {
-
-                        if let Some(code) = content_obj.get("code").and_then(|c| c.as_str()) {
-                            book_code = code.to_string();
-                        }
-                    },
-                    Some("chapter") => {
-
-                        if let Some(number) = content_obj.get("number").and_then(|n| n.as_str()) {
-                            chapter_number = number.to_string();
-                            let sid = format!("{} {}", book_code, chapter_number);
-                            content_obj.insert("sid".to_string(), Value::String(sid));
-                        }
-                    },
-                    Some("para") => {
-                        if let Some(para_content) = content_obj.get_mut("content").and_then(|c| c.as_array_mut()) {
-                            for para_item in para_content.iter_mut() {
-                                if let Some(para_obj) = para_item.as_object_mut() {
-                                    if para_obj.get("type").and_then(|t| t.as_str()) == Some("verse") {
-                                        if let Some(number) = para_obj.get("number").and_then(|n| n.as_str()) {
-                                            let sid = format!("{} {}:{}", book_code, chapter_number, number);
-                                            para_obj.insert("sid".to_string(), Value::String(sid));
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    },
-                    _ => (),
+    add_sid_to_content(&mut root.content, &mut book_code, &mut chapter_number);
+}
+
+fn add_sid_to_content(content: &mut [Content], book_code: &mut String, chapter_number: &mut String) {
+    for content in content.iter_mut() {
+        match content {
+            Content::Book(book) => {
+                *book_code = book.code.clone();
+            }
+            Content::Chapter(chapter) => {
+                *chapter_number = chapter.number.clone();
+                chapter.sid = Some(format!("{} {}", book_code, chapter_number));
+            }
+            Content::Para(para) => {
+                add_sid_to_in_para(para.content.iter_mut().flatten(), book_code, chapter_number);
+            }
+            Content::Table(table) => {
+                for row in table.content.iter_mut().flatten() {
+                    for cell in row.content.iter_mut().flatten() {
+                        add_sid_to_in_para(cell.content.iter_mut().flatten(), book_code, chapter_number);
+                    }
                 }
             }
+            Content::Sidebar(sidebar) => {
+                if let Some(content) = sidebar.content.as_mut() {
+                    add_sid_to_content(content, book_code, chapter_number);
+                }
+            }
+        }
+    }
+}
+
+fn add_sid_to_in_para<'a>(content: impl Iterator<Item = &'a mut InParaObject>, book_code: &str, chapter_number: &str) {
+    for para_item in content {
+        match para_item {
+            InParaObject::Verse(verse) => {
+                let number = verse.range().map_or_else(|| verse.number.clone(), |range| range.sid_number());
+                verse.sid = Some(format!("{} {}:{}", book_code, chapter_number, number));
+            }
+            InParaObject::Char(char) => {
+                add_sid_to_in_para(char.content.iter_mut().flatten(), book_code, chapter_number);
+            }
+            InParaObject::Note(note) => {
+                add_sid_to_in_para(note.content.iter_mut().flatten(), book_code, chapter_number);
+            }
+            _ => (),
         }
     }
-    usj_json
 }
@@ -1,196 +1,246 @@
 #![allow(dead_code)]
 
-use serde_json::Value;
+use std::collections::BTreeMap;
+use crate::structs_model::{CellObject, Content, InParaObject, RowObject};
+use crate::structs_model::root::{self, Root};
+use crate::utils_usfm;
 use quick_xml::events::{Event, BytesEnd, BytesStart, BytesText};
 use quick_xml::writer::Writer;
 use std::io::BufWriter;
 
-pub fn serialize_to_usx(usj: Value) -> String {
-
+/// Writes the typed model as USX, walking the tree directly.
+pub fn serialize_to_usx(root: &Root) -> String {
     let mut writer = Writer::new_with_indent(BufWriter::new(Vec::new()), b' ', 4);
 
-    if let Some(version) = usj.get("version").and_then(|v| v.as_str()) {
-        let usx_start = BytesStart::new("usx");
-        let usx_start = usx_start.with_attributes(vec![("version", version)]);
-        writer.write_event(Event::Start(usx_start)).unwrap();
+    let usx_start = BytesStart::new("usx");
+    let usx_start = usx_start.with_attributes(vec![("version", root::written_version(&root.version))]);
+    writer.write_event(Event::Start(usx_start)).unwrap();
+
+    let mut milestones = OpenMilestones::default();
+    writer = write_blocks(&root.content, writer, &mut milestones);
+    writer = milestones.close_verse(writer);
+    writer = milestones.close_chapter(writer);
+    writer.write_event(Event::End(BytesEnd::new("usx"))).unwrap();
+    let buffer = writer.into_inner().into_inner().expect("Failed to retrieve buffer");
+
+
+    String::from_utf8(buffer).expect("Failed to convert buffer to string")
+}
+
+
+/// # The chapter and verse whose USX 3 end milestone is still to be written.
+///
+/// A verse ends where the next one starts, or at the end of the last
+/// paragraph holding its text; a chapter ends where the next one starts.
+#[derive(Default)]
+struct OpenMilestones {
+    chapter: Option<String>,
+    verse: Option<String>,
+}
+
+impl OpenMilestones {
+    fn close_verse(&mut self, mut writer: Writer<BufWriter<Vec<u8>>>) -> Writer<BufWriter<Vec<u8>>> {
+        if let Some(eid) = self.verse.take() {
+            writer.write_event(Event::Empty(BytesStart::new("verse").with_attributes([("eid", eid.as_str())]))).unwrap();
+        }
+        writer
     }
 
-    if let Some(content) = usj.get("content").and_then(|c| c.as_array()) {
-        for element in content {
-            if let Some(obj) = element.as_object() {
-                match obj.get("type").and_then(|t| t.as_str()) {
-                    Some("book") => {
-                        let mut book_start = BytesStart::new("book");
-                        for (key, value) in obj.iter() {
-                            if key != "type" && key != "content" {
-                                let attr_key =
-                                    if key == "marker" {
-                                        "style"
-                                    } else {
-                                        key
-                                    };
-                                if let Some(attr_value) = value.as_str() {
-                                    book_start.push_attribute((attr_key, attr_value));
-                                }
-                            }
-                        }
-                        writer.write_event(Event::Start(book_start)).unwrap();
-                        if let Some(content) = obj.get("content").and_then(|c| c.as_array()) {
-                            for value in content {
-                                if let Some(text) = value.as_str() {
-                                    writer.write_event(Event::Text(BytesText::new(text))).unwrap();
-                                }
-                            }
-                        }
-                        writer.write_event(Event::End(BytesEnd::new("book"))).unwrap();
-                    }
-                    Some("para") => {
-                        let mut para_start = BytesStart::new("para");
-                        for (key, value) in obj.iter() {
-                            if key != "type" && key != "content" {
-                                let attr_key =
-                                    if key == "marker" {
-                                        "style"
-                                    } else {
-                                        key
-                                    };
-                                if let Some(attr_value) = value.as_str() {
-                                    para_start.push_attribute((attr_key, attr_value));
-                                }
-                            }
-                        }
-                        writer.write_event(Event::Start(para_start)).unwrap();
-                        if let Some(content) = obj.get("content").and_then(|c| c.as_array()) {
-                            for value in content {
-                                writer = write_content(value, writer);
-                            }
-                        }
-                        writer.write_event(Event::End(BytesEnd::new("para"))).unwrap();
+    fn close_chapter(&mut self, mut writer: Writer<BufWriter<Vec<u8>>>) -> Writer<BufWriter<Vec<u8>>> {
+        if let Some(eid) = self.chapter.take() {
+            writer.write_event(Event::Empty(BytesStart::new("chapter").with_attributes([("eid", eid.as_str())]))).unwrap();
+        }
+        writer
+    }
+
+    /// Closes the open verse at the end of a block, unless it goes on in the block `next`.
+    fn end_block(&mut self, next: Option<&Content>, writer: Writer<BufWriter<Vec<u8>>>) -> Writer<BufWriter<Vec<u8>>> {
+        if continues_verse(next) {
+            writer
+        } else {
+            self.close_verse(writer)
+        }
+    }
+}
+
+/// Whether a verse open at the end of a block goes on in the block `next`:
+/// a paragraph of verse text or a table, that does not start with a verse of its own.
+fn continues_verse(next: Option<&Content>) -> bool {
+    let first = match next {
+        Some(Content::Para(para)) => {
+            if utils_usfm::heading_markers().iter().any(|heading| heading == utils_usfm::split_level(&para.marker).0) {
+                return false;
+            }
+            para.content.iter().flatten().next()
+        }
+        Some(Content::Table(table)) => table.content.iter().flatten().next()
+            .and_then(|row| row.content.iter().flatten().next())
+            .and_then(|cell| cell.content.iter().flatten().next()),
+        _ => return false,
+    };
+    !matches!(first, Some(InParaObject::Verse(_)))
+}
+
+/// Writes the objects of the root content, or of a sidebar.
+fn write_blocks(content: &[Content], mut writer: Writer<BufWriter<Vec<u8>>>, milestones: &mut OpenMilestones) -> Writer<BufWriter<Vec<u8>>> {
+    for (i, element) in content.iter().enumerate() {
+        writer = write_block(element, content.get(i + 1), writer, milestones);
+    }
+    writer
+}
+
+/// Writes an object of the root content, or of a sidebar.
+fn write_block(element: &Content, next: Option<&Content>, mut writer: Writer<BufWriter<Vec<u8>>>, milestones: &mut OpenMilestones) -> Writer<BufWriter<Vec<u8>>> {
+    match element {
+        Content::Book(book) => {
+            let book_start = usx_start("book", &[("marker", Some(&book.marker)), ("code", Some(&book.code))], &book.additional_properties);
+            writer.write_event(Event::Start(book_start)).unwrap();
+            for text in book.content.iter().flatten() {
+                writer.write_event(Event::Text(BytesText::new(text))).unwrap();
+            }
+            writer.write_event(Event::End(BytesEnd::new("book"))).unwrap();
+        }
+        Content::Para(para) => {
+            writer.write_event(Event::Start(usx_start("para", &[("marker", Some(&para.marker))], &para.additional_properties))).unwrap();
+            for value in para.content.iter().flatten() {
+                writer = write_content(value, writer, milestones);
+            }
+            writer = milestones.end_block(next, writer);
+            writer.write_event(Event::End(BytesEnd::new("para"))).unwrap();
+        }
+        Content::Sidebar(sidebar) => {
+            let fields = [("marker", Some(&sidebar.marker)), ("category", sidebar.category.as_ref())];
+            writer.write_event(Event::Start(usx_start("sidebar", &fields, &sidebar.additional_properties))).unwrap();
+            if let Some(content) = &sidebar.content {
+                writer = write_blocks(content, writer, milestones);
+            }
+            writer.write_event(Event::End(BytesEnd::new("sidebar"))).unwrap();
+        }
+        Content::Chapter(chapter) => {
+            writer = milestones.close_verse(writer);
+            writer = milestones.close_chapter(writer);
+            milestones.chapter = chapter.sid.clone();
+            let fields = [
+                ("marker", Some(&chapter.marker)),
+                ("number", Some(&chapter.number)),
+                ("sid", chapter.sid.as_ref()),
+                ("altnumber", chapter.altnumber.as_ref()),
+                ("pubnumber", chapter.pubnumber.as_ref()),
+            ];
+            writer.write_event(Event::Empty(usx_start("chapter", &fields, &chapter.additional_properties))).unwrap();
+        }
+        Content::Table(table) => {
+            writer.write_event(Event::Start(usx_start("table", &[("marker", table.marker.as_ref())], &table.additional_properties))).unwrap();
+            let rows: &[RowObject] = table.content.as_deref().unwrap_or_default();
+            for (row_index, row) in rows.iter().enumerate() {
+                writer.write_event(Event::Start(usx_start("row", &[("marker", Some(&row.marker))], &row.additional_properties))).unwrap();
+                let cells: &[CellObject] = row.content.as_deref().unwrap_or_default();
+                for (cell_index, cell) in cells.iter().enumerate() {
+                    let fields = [("marker", Some(&cell.marker)), ("align", cell.align.as_ref()), ("colspan", cell.colspan.as_ref())];
+                    writer.write_event(Event::Start(usx_start("cell", &fields, &cell.additional_properties))).unwrap();
+                    for value in cell.content.iter().flatten() {
+                        writer = write_content(value, writer, milestones);
                     }
-                    Some("chapter") => {
-                        let mut chapter_start = BytesStart::new("chapter");
-                        for (key, value) in obj.iter() {
-                            if key != "type" && key != "content" {
-                                let attr_key =
-                                    if key == "marker" {
-                                        "style"
-                                    } else {
-                                        key
-                                    };
-                                if let Some(attr_value) = value.as_str() {
-                                    chapter_start.push_attribute((attr_key, attr_value));
-                                }
-                            }
-                        }
-                        writer.write_event(Event::Empty(chapter_start)).unwrap();
+                    if row_index + 1 == rows.len() && cell_index + 1 == cells.len() {
+                        writer = milestones.end_block(next, writer);
                     }
-                    _ => {}
+                    writer.write_event(Event::End(BytesEnd::new("cell"))).unwrap();
                 }
+                writer.write_event(Event::End(BytesEnd::new("row"))).unwrap();
             }
+            writer.write_event(Event::End(BytesEnd::new("table"))).unwrap();
         }
     }
-    writer.write_event(Event::End(BytesEnd::new("usx"))).unwrap();
-    let buffer = writer.into_inner().into_inner().expect("Failed to retrieve buffer");
-
-    let output_string = String::from_utf8(buffer).expect("Failed to convert buffer to string");
-    output_string
+    writer
 }
 
+/// Opens a USX element with the attributes an object has in USJ, `marker` becoming `style`.
+///
+/// The attributes come in the order of their USJ names, any other attribute of the object
+/// included.
+fn usx_start<'a>(tag_name: &'a str, fields: &[(&'a str, Option<&'a String>)], additional_properties: &'a BTreeMap<String, String>) -> BytesStart<'a> {
+    let mut attributes: BTreeMap<&str, &str> = fields.iter()
+        .filter_map(|&(key, value)| value.map(|value| (key, value.as_str())))
+        .collect();
+    attributes.extend(additional_properties.iter().map(|(key, value)| (key.as_str(), value.as_str())));
+    let mut start = BytesStart::new(tag_name);
+    for (key, value) in attributes {
+        let attr_key = if key == "marker" { "style" } else { key };
+        start.push_attribute((attr_key, value));
+    }
+    start
+}
 
-fn write_content(content: &Value, mut writer: Writer<BufWriter<Vec<u8>>>) -> Writer<BufWriter<Vec<u8>>> {
+fn write_content(content: &InParaObject, mut writer: Writer<BufWriter<Vec<u8>>>, milestones: &mut OpenMilestones) -> Writer<BufWriter<Vec<u8>>> {
     match content {
-        Value::String(text) => {
+        InParaObject::String(text) => {
             writer.write_event(Event::Text(BytesText::new(text.as_str()))).unwrap();
         }
-
-        Value::Object(obj) => {
-            match obj.get("type").and_then(|t| t.as_str()) {
-                Some("char") => {
-                    let mut char_start = BytesStart::new("char");
-                    for (key, value) in obj.iter() {
-                        if key != "type" && key != "content" {
-                            let attr_key =
-                                if key == "marker" {
-                                    "style"
-                                } else {
-                                    key
-                                };
-                            if let Some(attr_value) = value.as_str() {
-                                char_start.push_attribute((attr_key, attr_value));
-                            }
-                        }
-                    }
-                    writer.write_event(Event::Start(char_start)).unwrap();
-                    if let Some(content) = obj.get("content").and_then(|c| c.as_array()) {
-                        for value in content {
-                            writer = write_content(value, writer);
-                        }
-                    }
-                    writer.write_event(Event::End(BytesEnd::new("char"))).unwrap();
-                }
-                Some("verse") => {
-                    let mut verse_start = BytesStart::new("verse");
-                    for (key, value) in obj.iter() {
-                        if key != "type" && key != "content" {
-                            let attr_key =
-                                if key == "marker" {
-                                    "style"
-                                } else {
-                                    key
-                                };
-                            if let Some(attr_value) = value.as_str() {
-                                verse_start.push_attribute((attr_key, attr_value));
-                            }
-                        }
-                    }
-                    writer.write_event(Event::Empty(verse_start)).unwrap();
-                }
-                Some("ms") => {
-                    let mut ms_start = BytesStart::new("ms");
-
-                    for (key, value) in obj.iter() {
-                        if key != "type" && key != "content" {
-                            let attr_key =
-                                if key == "marker" {
-                                    "style"
-                                } else {
-                                    key
-                                };
-                            if let Some(attr_value) = value.as_str() {
-                                ms_start.push_attribute((attr_key, attr_value));
-                            }
-                        }
-                    }
-
-                    writer.write_event(Event::Empty(ms_start)).unwrap();
-                }
-                Some("note") => {
-                    let mut note_start = BytesStart::new("note");
-                    for (key, value) in obj.iter() {
-                        if key != "type" && key != "content" {
-                            let attr_key =
-                                if key == "marker" {
-                                    "style"
-                                } else {
-                                    key
-                                };
-                            if let Some(attr_value) = value.as_str() {
-                                note_start.push_attribute((attr_key, attr_value));
-                            }
-                        }
-                    }
-                    writer.write_event(Event::Start(note_start)).unwrap();
-                    if let Some(content) = obj.get("content").and_then(|c| c.as_array()) {
-                        for value in content {
-                            writer = write_content(value, writer);
-                        }
-                    }
-                    writer.write_event(Event::End(BytesEnd::new("note"))).unwrap();
-                }
-                _ => {}
+        InParaObject::Char(char) => {
+            let fields = [
+                ("marker", Some(&char.marker)),
+                ("link-id", char.link_id.as_ref()),
+                ("link-href", char.link_href.as_ref()),
+                ("srcloc", char.srcloc.as_ref()),
+                ("strong", char.strong.as_ref()),
+            ];
+            writer.write_event(Event::Start(usx_start("char", &fields, &char.additional_properties))).unwrap();
+            for value in char.content.iter().flatten() {
+                writer = write_content(value, writer, milestones);
+            }
+            writer.write_event(Event::End(BytesEnd::new("char"))).unwrap();
+        }
+        InParaObject::Verse(verse) => {
+            writer = milestones.close_verse(writer);
+            milestones.verse = verse.sid.clone();
+            let fields = [
+                ("marker", Some(&verse.marker)),
+                ("number", Some(&verse.number)),
+                ("sid", verse.sid.as_ref()),
+                ("altnumber", verse.altnumber.as_ref()),
+                ("pubnumber", verse.pubnumber.as_ref()),
+            ];
+            writer.write_event(Event::Empty(usx_start("verse", &fields, &verse.additional_properties))).unwrap();
+        }
+        InParaObject::Milestone(ms) => {
+            let fields = [("marker", Some(&ms.marker)), ("who", ms.who.as_ref()), ("sid", ms.sid.as_ref()), ("eid", ms.eid.as_ref())];
+            writer.write_event(Event::Empty(usx_start("ms", &fields, &ms.additional_properties))).unwrap();
+        }
+        InParaObject::Note(note) => {
+            let fields = [("marker", Some(&note.marker)), ("caller", note.caller.as_ref()), ("category", note.category.as_ref())];
+            writer.write_event(Event::Start(usx_start("note", &fields, &note.additional_properties))).unwrap();
+            for value in note.content.iter().flatten() {
+                writer = write_content(value, writer, milestones);
+            }
+            writer.write_event(Event::End(BytesEnd::new("note"))).unwrap();
+        }
+        InParaObject::OptBreak => {
+            writer.write_event(Event::Empty(BytesStart::new("optbreak"))).unwrap();
+        }
+        InParaObject::Ref(reference) => {
+            let fields = [("loc", reference.loc.as_ref()), ("gen", reference.gen.as_ref())];
+            writer.write_event(Event::Start(usx_start("ref", &fields, &reference.additional_properties))).unwrap();
+            for text in reference.content.iter().flatten() {
+                writer.write_event(Event::Text(BytesText::new(text))).unwrap();
+            }
+            writer.write_event(Event::End(BytesEnd::new("ref"))).unwrap();
+        }
+        InParaObject::Figure(figure) => {
+            let fields = [
+                ("marker", Some(&figure.marker)),
+                ("alt", figure.alt.as_ref()),
+                ("file", figure.file.as_ref()),
+                ("size", figure.size.as_ref()),
+                ("loc", figure.loc.as_ref()),
+                ("copy", figure.copy.as_ref()),
+                ("ref", figure.r#ref.as_ref()),
+            ];
+            writer.write_event(Event::Start(usx_start("figure", &fields, &figure.additional_properties))).unwrap();
+            for text in figure.content.iter().flatten() {
+                writer.write_event(Event::Text(BytesText::new(text))).unwrap();
             }
+            writer.write_event(Event::End(BytesEnd::new("figure"))).unwrap();
         }
-        _ => {}
     }
     writer
 }
@@ -1,9 +1,20 @@
 #![allow(dead_code)]
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::structs_model::usfm_source::UsfmSource;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BookObject {
+    #[serde(skip)]
     pub(crate) index: usize,
-    pub(crate) marker: String,
-    pub(crate) code: String,
-    pub(crate) content: Option<Vec<String>>,
+    pub marker: String,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
 }
 
 impl BookObject {
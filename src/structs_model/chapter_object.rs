@@ -1,10 +1,24 @@
 #![allow(dead_code)]
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::structs_model::usfm_source::UsfmSource;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChapterObject {
+    #[serde(skip)]
     pub(crate) index: usize,
-    pub(crate) marker: String,
-    pub(crate) number: String,
-    pub(crate) altnumber: Option<String>,
-    pub(crate) pubnumber: Option<String>,
+    pub marker: String,
+    pub number: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altnumber: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubnumber: Option<String>,
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
 }
 
 impl ChapterObject {
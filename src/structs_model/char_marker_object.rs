@@ -1,14 +1,29 @@
 #![allow(dead_code)]
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
 use crate::structs_model::model::InParaObject;
+use crate::structs_model::usfm_source::UsfmSource;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CharMarkerObject {
+    #[serde(skip)]
     pub(crate) index: usize,
-    pub(crate) marker: String,
-    pub(crate) content: Option<Vec<InParaObject>>,
-    pub(crate) link_id: Option<String>,
-    pub(crate) link_href: Option<String>,
-    pub(crate) srcloc: Option<String>,
-    pub(crate) strong: Option<String>,
+    pub marker: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Vec<InParaObject>>,
+    #[serde(default, rename = "link-id", skip_serializing_if = "Option::is_none")]
+    pub link_id: Option<String>,
+    #[serde(default, rename = "link-href", skip_serializing_if = "Option::is_none")]
+    pub link_href: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub srcloc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strong: Option<String>,
+    /// Any other attribute (`lemma`, `x-occurrence`, ...).
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
 }
 
 impl CharMarkerObject {
@@ -39,6 +54,11 @@ impl CharMarkerObject {
                     nt.init_index();
                     tot_len += nt.index;
                 }
+                InParaObject::Ref(rf) => {
+                    rf.init_index();
+                    tot_len += rf.index;
+                }
+                InParaObject::OptBreak => {}
             }
         }
         self.index = tot_len;
@@ -1,11 +1,31 @@
 #![allow(dead_code)]
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::structs_model::usfm_source::UsfmSource;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FigureObject {
+    #[serde(skip)]
     pub(crate) index: usize,
-    pub(crate) marker: String,
-    pub(crate) content: Option<Vec<String>>,
-    pub(crate) file: Option<String>,
-    pub(crate) size: Option<String>,
-    pub(crate) r#ref: Option<String>,
+    pub marker: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r#ref: Option<String>,
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
 }
 
 impl FigureObject {
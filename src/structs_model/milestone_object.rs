@@ -1,11 +1,23 @@
 #![allow(dead_code)]
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::structs_model::usfm_source::UsfmSource;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MilestoneObject {
+    #[serde(skip)]
     pub(crate) index: usize,
-    pub(crate) marker: String,
-    pub(crate) who: Option<String>,
-    pub(crate) additional_properties: Option<HashMap<String, String>>,
+    pub marker: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub who: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eid: Option<String>,
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
 }
 
 impl MilestoneObject {
@@ -12,3 +12,22 @@ pub mod sidebar_object;
 pub mod table_object;
 pub mod figure_object;
 pub mod row_object;
+pub mod ref_object;
+pub mod usfm_source;
+
+pub use root::Root;
+pub use model::{Content, InParaObject};
+pub use book_object::BookObject;
+pub use chapter_object::ChapterObject;
+pub use para_marker_object::ParaMarkerObject;
+pub use verse_object::{VerseObject, VerseRange};
+pub use char_marker_object::CharMarkerObject;
+pub use note_object::NoteObject;
+pub use milestone_object::MilestoneObject;
+pub use cell_object::CellObject;
+pub use sidebar_object::SidebarObject;
+pub use table_object::TableObject;
+pub use figure_object::FigureObject;
+pub use row_object::RowObject;
+pub use ref_object::RefObject;
+pub use usfm_source::UsfmSource;
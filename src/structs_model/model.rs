@@ -1,16 +1,31 @@
 #![allow(dead_code)]
+use serde::{Deserialize, Serialize};
+use crate::aosj_string::element::Element;
 use crate::structs_model;
 
+/// # Everything that can appear inside a paragraph-like object.
+///
+/// Plain text is stored as `String`; every other variant is tagged by its
+/// USJ `type`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum InParaObject {
     Verse(structs_model::verse_object::VerseObject),
     Char(structs_model::char_marker_object::CharMarkerObject),
+    #[serde(rename = "ms")]
     Milestone(structs_model::milestone_object::MilestoneObject),
     Figure(structs_model::figure_object::FigureObject),
     Note(structs_model::note_object::NoteObject),
+    Ref(structs_model::ref_object::RefObject),
+    /// An optional line break, `//` in USFM.
+    OptBreak,
+    #[serde(untagged)]
     String(String),
 }
 
-
+/// # Top-level objects of a USJ document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum Content {
     Book(structs_model::book_object::BookObject),
     Chapter(structs_model::chapter_object::ChapterObject),
@@ -19,16 +34,49 @@ pub enum Content {
     Sidebar(structs_model::sidebar_object::SidebarObject),
 }
 
+/// # Builds a `Root` from the callbacks of the readers.
+///
+/// This is the typed counterpart of `AosjStringModel`: objects that can hold
+/// content are kept on `stack` while they are open and moved into their parent
+/// when they are closed.
 pub struct Model {
-    book: structs_model::book_object::BookObject,
-    chapter: structs_model::chapter_object::ChapterObject,
-    para: structs_model::para_marker_object::ParaMarkerObject,
-    table: structs_model::table_object::TableObject,
-    sidebar: structs_model::sidebar_object::SidebarObject,
-    verse: structs_model::verse_object::VerseObject,
-    char: structs_model::char_marker_object::CharMarkerObject,
-    milestone: structs_model::milestone_object::MilestoneObject,
-    figure: structs_model::figure_object::FigureObject,
-    note: structs_model::note_object::NoteObject,
-    string: String,
-}
\ No newline at end of file
+    pub(crate) root: structs_model::root::Root,
+    pub(crate) stack: Vec<OpenObject>,
+    pub(crate) parent_els: Vec<Element>,
+    /// The USFM markup of the next object, for `ParseOptions::lossless`.
+    pub(crate) usfm_open: Option<String>,
+    /// The USFM markup closing the current object.
+    pub(crate) usfm_close: Option<String>,
+}
+
+/// An object whose content is still being read.
+pub(crate) enum OpenObject {
+    Book(structs_model::book_object::BookObject),
+    Para(structs_model::para_marker_object::ParaMarkerObject),
+    Char(structs_model::char_marker_object::CharMarkerObject),
+    Note(structs_model::note_object::NoteObject),
+    Table(structs_model::table_object::TableObject),
+    Row(structs_model::row_object::RowObject),
+    Cell(structs_model::cell_object::CellObject),
+    Sidebar(structs_model::sidebar_object::SidebarObject),
+    Figure(structs_model::figure_object::FigureObject),
+    Ref(structs_model::ref_object::RefObject),
+}
+
+impl OpenObject {
+    /// Returns how the object was written in USFM; tables have no markup of their own.
+    pub(crate) fn source_mut(&mut self) -> Option<&mut Option<structs_model::usfm_source::UsfmSource>> {
+        match self {
+            OpenObject::Book(book) => Some(&mut book.source),
+            OpenObject::Para(para) => Some(&mut para.source),
+            OpenObject::Char(char) => Some(&mut char.source),
+            OpenObject::Note(note) => Some(&mut note.source),
+            OpenObject::Row(row) => Some(&mut row.source),
+            OpenObject::Cell(cell) => Some(&mut cell.source),
+            OpenObject::Sidebar(sidebar) => Some(&mut sidebar.source),
+            OpenObject::Figure(figure) => Some(&mut figure.source),
+            OpenObject::Ref(r#ref) => Some(&mut r#ref.source),
+            OpenObject::Table(_) => None,
+        }
+    }
+}
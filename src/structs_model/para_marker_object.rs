@@ -1,10 +1,20 @@
 #![allow(dead_code)]
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
 use crate::structs_model::model::InParaObject;
+use crate::structs_model::usfm_source::UsfmSource;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ParaMarkerObject {
+    #[serde(skip)]
     pub(crate) index: usize,
-    pub(crate) marker: String,
-    pub(crate) content: Option<Vec<InParaObject>>,
+    pub marker: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Vec<InParaObject>>,
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
 }
 
 impl ParaMarkerObject {
@@ -37,6 +47,11 @@ impl ParaMarkerObject {
                 InParaObject::Note(nt) => {
                     tot_len += nt.index;
                 }
+                InParaObject::Ref(rf) => {
+                    rf.init_index();
+                    tot_len += rf.index;
+                }
+                InParaObject::OptBreak => {}
             }
         }
         self.index = tot_len;
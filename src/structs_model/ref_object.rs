@@ -0,0 +1,32 @@
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::structs_model::usfm_source::UsfmSource;
+
+/// # A reference to a passage of scripture, e.g. `\ref Mark 1:1|loc="MRK 1:1"\ref*`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RefObject {
+    #[serde(skip)]
+    pub(crate) index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Vec<String>>,
+    /// The passage referred to, e.g. `MRK 1:1`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gen: Option<String>,
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
+}
+
+impl RefObject {
+    pub fn init_index(&mut self) {
+        let mut tot_len: usize = 0;
+        for strs in self.content.iter().flatten() {
+            tot_len += strs.chars().collect::<Vec<_>>().len();
+        }
+        self.index = tot_len;
+    }
+}
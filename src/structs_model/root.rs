@@ -1,14 +1,52 @@
 #![allow(dead_code)]
+use serde::{Deserialize, Serialize, Serializer};
 use crate::structs_model::book_object::BookObject;
-use crate::structs_model::model::Content;
+use crate::options::MarkerLevels;
+use crate::structs_model::model::{Content, InParaObject};
+use crate::structs_model::usfm_source::UsfmSource;
+use crate::utils_usfm;
 
+/// # The root of a USJ document.
+///
+/// Every reader produces a `Root` and every writer consumes one, so a document
+/// can be inspected or modified in Rust between parsing and serializing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Root {
-    pub(crate) r#type: String,
-    pub(crate) version: String,
-    pub(crate) content: Vec<Content>,
+    #[serde(default = "usj_type")]
+    pub r#type: String,
+    /// The version of the `\usfm` line, empty when the document had none.
+    #[serde(default, serialize_with = "serialize_version")]
+    pub version: String,
+    #[serde(default)]
+    pub content: Vec<Content>,
+    /// How the `\usfm` line was written, kept by `ParseOptions::lossless`.
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
+}
+
+fn usj_type() -> String {
+    "USJ".to_string()
+}
+
+/// The version written to USX and USJ: the one read, or 3.0 when the document gave none.
+pub(crate) fn written_version(version: &str) -> &str {
+    if version.is_empty() { "3.0" } else { version }
+}
+
+fn serialize_version<S: Serializer>(version: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(written_version(version))
 }
 
 impl Root {
+    pub fn new(version: &str) -> Self {
+        Root {
+            r#type: usj_type(),
+            version: version.to_string(),
+            content: Vec::new(),
+            source: None,
+        }
+    }
+
     pub fn add_book(&mut self, book: BookObject) {
         self.content.push(Content::Book(book));
     }
@@ -16,4 +54,59 @@ impl Root {
     pub fn set_version(&mut self, new_version: &str) {
         self.version = new_version.to_string();
     }
+
+    /// Writes the level 1 of every numbered paragraph and character marker, e.g. `\q1`,
+    /// as asked by `levels`.
+    pub fn normalize_marker_levels(&mut self, levels: MarkerLevels) {
+        if levels != MarkerLevels::Preserve {
+            normalize_content(&mut self.content, levels);
+        }
+    }
+
+    /// Returns the book code of the first `\id`, if any.
+    pub fn book_code(&self) -> Option<&str> {
+        self.content.iter().find_map(|c| match c {
+            Content::Book(book) => Some(book.code.as_str()),
+            _ => None,
+        })
+    }
+}
+
+fn normalize_content(content: &mut [Content], levels: MarkerLevels) {
+    for content in content.iter_mut() {
+        match content {
+            Content::Para(para) => {
+                para.marker = utils_usfm::normalize_level(&para.marker, levels);
+                normalize_in_para(para.content.iter_mut().flatten(), levels);
+            }
+            Content::Table(table) => {
+                for row in table.content.iter_mut().flatten() {
+                    for cell in row.content.iter_mut().flatten() {
+                        normalize_in_para(cell.content.iter_mut().flatten(), levels);
+                    }
+                }
+            }
+            Content::Sidebar(sidebar) => {
+                if let Some(content) = sidebar.content.as_mut() {
+                    normalize_content(content, levels);
+                }
+            }
+            Content::Book(_) | Content::Chapter(_) => {}
+        }
+    }
+}
+
+fn normalize_in_para<'a>(objects: impl Iterator<Item = &'a mut InParaObject>, levels: MarkerLevels) {
+    for object in objects {
+        match object {
+            InParaObject::Char(char) => {
+                char.marker = utils_usfm::normalize_level(&char.marker, levels);
+                normalize_in_para(char.content.iter_mut().flatten(), levels);
+            }
+            InParaObject::Note(note) => {
+                normalize_in_para(note.content.iter_mut().flatten(), levels);
+            }
+            _ => {}
+        }
+    }
 }
@@ -1,11 +1,22 @@
 #![allow(dead_code)]
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
 use crate::structs_model::cell_object::CellObject;
 use crate::structs_model::model::InParaObject;
+use crate::structs_model::usfm_source::UsfmSource;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RowObject {
+    #[serde(skip)]
     pub(crate) index: usize,
-    pub(crate) marker: String,
-    pub(crate) content: Option<Vec<CellObject>>,
+    pub r#type: String,
+    pub marker: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Vec<CellObject>>,
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
 }
 
 impl RowObject {
@@ -34,6 +45,11 @@ impl RowObject {
                     InParaObject::Note(nt) => {
                         tot_len += nt.index;
                     }
+                    InParaObject::Ref(rf) => {
+                        rf.init_index();
+                        tot_len += rf.index;
+                    }
+                    InParaObject::OptBreak => {}
                 }
             }
         }
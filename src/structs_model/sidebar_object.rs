@@ -1,12 +1,22 @@
 #![allow(dead_code)]
-use crate::structs_model::model::InParaObject;
-use crate::structs_model::para_marker_object::ParaMarkerObject;
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::structs_model::model::{Content, InParaObject};
+use crate::structs_model::usfm_source::UsfmSource;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SidebarObject {
+    #[serde(skip)]
     pub(crate) index: usize,
-    pub(crate) marker: String,
-    pub(crate) content: Option< Vec<ParaMarkerObject> >,
-    pub(crate) category: Option<String>,
+    pub marker: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Vec<Content>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
 }
 
 impl SidebarObject {
@@ -15,7 +25,8 @@ impl SidebarObject {
         if self.marker.eq("b") {
             tot_len += 1;
         }
-        for para_marker_obj in self.content.iter_mut().flatten() {
+        for content in self.content.iter_mut().flatten() {
+            let Content::Para(para_marker_obj) = content else { continue };
             for in_para_object in para_marker_obj.content.iter_mut().flatten() {
                 match in_para_object {
                     InParaObject::String(st) => {
@@ -38,6 +49,11 @@ impl SidebarObject {
                     InParaObject::Note(nt) => {
                         tot_len += nt.index;
                     }
+                    InParaObject::Ref(rf) => {
+                        rf.init_index();
+                        tot_len += rf.index;
+                    }
+                    InParaObject::OptBreak => {}
                 }
             }
         }
@@ -1,11 +1,19 @@
 #![allow(dead_code)]
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
 use crate::structs_model::model::InParaObject;
 use crate::structs_model::row_object::RowObject;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TableObject {
+    #[serde(skip)]
     pub(crate) index: usize,
-    pub(crate) marker: String,
-    pub(crate) content: Option<Vec<RowObject>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Vec<RowObject>>,
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
 }
 
 impl TableObject {
@@ -35,6 +43,11 @@ impl TableObject {
                         InParaObject::Note(nt) => {
                             tot_len += nt.index;
                         }
+                        InParaObject::Ref(rf) => {
+                            rf.init_index();
+                            tot_len += rf.index;
+                        }
+                        InParaObject::OptBreak => {}
                     }
                 }
             }
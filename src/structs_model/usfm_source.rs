@@ -0,0 +1,25 @@
+#![allow(dead_code)]
+
+/// # How an object was written in the USFM it was read from.
+///
+/// Kept when reading with `ParseOptions::lossless`, so that the USFM writer gives
+/// back the original whitespace and spelling of the markup. An object whose
+/// marker or attributes were changed since it was read is written the usual way.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsfmSource {
+    /// Everything from the line breaks before the opening marker to the content, e.g. `"\n\\v 1 "`.
+    pub open: String,
+    /// Everything from the end of the content to the end of the object, e.g. `"|Man\\w*"`.
+    pub close: String,
+    /// The markup the writer gave the object when it was read.
+    pub(crate) markup: (String, String),
+}
+
+impl UsfmSource {
+    pub fn new(open: String) -> Self {
+        UsfmSource {
+            open,
+            ..UsfmSource::default()
+        }
+    }
+}
@@ -1,10 +1,26 @@
 #![allow(dead_code)]
+use std::fmt;
+use std::str::FromStr;
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::structs_model::usfm_source::UsfmSource;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VerseObject {
+    #[serde(skip)]
     pub(crate) index: usize,
-    pub(crate) marker: String,
-    pub(crate) number: String,
-    pub(crate) altnumber: Option<String>,
-    pub(crate) pubnumber: Option<String>,
+    pub marker: String,
+    pub number: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altnumber: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubnumber: Option<String>,
+    #[serde(flatten)]
+    pub additional_properties: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub source: Option<UsfmSource>,
 }
 impl VerseObject {
     pub fn init_index(&mut self) {
@@ -15,4 +31,88 @@ impl VerseObject {
     pub fn get_index(self) -> usize {
         self.index
     }
+
+    /// `number` read as a `VerseRange`, `None` when it is not a valid verse number.
+    pub fn range(&self) -> Option<VerseRange> {
+        self.number.parse().ok()
+    }
+}
+
+/// # A verse number: `1`, a segment such as `1a`, or a range such as `3-4` or `5a-6b`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerseRange {
+    pub start: u32,
+    pub start_segment: Option<String>,
+    /// The last verse of a range, `None` for a single verse.
+    pub end: Option<u32>,
+    pub end_segment: Option<String>,
+}
+
+impl VerseRange {
+    /// The last verse covered, `start` for a single verse.
+    pub fn last(&self) -> u32 {
+        self.end.unwrap_or(self.start)
+    }
+
+    /// Whether the verse `verse` is (at least partly) covered.
+    pub fn contains(&self, verse: u32) -> bool {
+        self.start <= verse && verse <= self.last()
+    }
+
+    /// The verses covered, as written in a `sid`: without segment letters, so that `1a` is
+    /// `1`, `5a-6b` is `5-6` and `3a-3b` is `3`.
+    pub fn sid_number(&self) -> String {
+        match self.end {
+            Some(end) if end != self.start => format!("{}-{}", self.start, end),
+            _ => self.start.to_string(),
+        }
+    }
+}
+
+/// Splits `12b` into `12` and `Some("b")`.
+fn parse_verse(value: &str) -> Option<(u32, Option<String>)> {
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, segment) = value.split_at(digits);
+    if !segment.chars().all(|c| c.is_alphabetic()) {
+        return None;
+    }
+    Some((number.parse().ok()?, Some(segment.to_string()).filter(|s| !s.is_empty())))
+}
+
+impl FromStr for VerseRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid verse number '{}', expected e.g. 1, 1a, 3-4 or 5a-6b", value);
+        let (start, end) = match value.split_once('-') {
+            Some((start, end)) => (start, Some(end)),
+            None => (value, None),
+        };
+        let (start, start_segment) = parse_verse(start).ok_or_else(invalid)?;
+        let (end, end_segment) = match end.map(parse_verse) {
+            Some(Some((end, end_segment))) => (Some(end), end_segment),
+            Some(None) => return Err(invalid()),
+            None => (None, None),
+        };
+        let range = VerseRange { start, start_segment, end, end_segment };
+        if start == 0 || range.end == Some(0) {
+            return Err(format!("Invalid verse number '{}', verses start at 1", value));
+        }
+        if let Some(end) = range.end {
+            if end < start || (end == start && range.end_segment <= range.start_segment) {
+                return Err(format!("Verse range '{}' ends before it starts", value));
+            }
+        }
+        Ok(range)
+    }
+}
+
+impl fmt::Display for VerseRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.start, self.start_segment.as_deref().unwrap_or_default())?;
+        if let Some(end) = self.end {
+            write!(f, "-{}{}", end, self.end_segment.as_deref().unwrap_or_default())?;
+        }
+        Ok(())
+    }
 }
@@ -1,3 +1,5 @@
+use crate::options::MarkerLevels;
+
 pub fn char_markers() -> Vec<String> {
     vec![
         "qs".to_string(),
@@ -48,7 +50,16 @@ pub fn char_markers() -> Vec<String> {
         "sup".to_string(),
         "ior".to_string(),
         "iqt".to_string(),
+        "cat".to_string(),
+        "ca".to_string(),
+        "va".to_string(),
+        "vp".to_string(),
         "w".to_string(), // à traiter séparément
+        "wg".to_string(),
+        "wh".to_string(),
+        "wa".to_string(),
+        "rb".to_string(),
+        "jmp".to_string(),
     ]
 }
 
@@ -120,10 +131,153 @@ pub fn para_markers() -> Vec<String> {
 }
 
 
+/// Markers that take a numbered level, with the highest level they accept,
+/// e.g. `\q1` to `\q4`, and whether the number is required. Level 1 may be
+/// written without its number, except for `\toc1` and `\toca1`: `\toc` is not a marker.
+pub fn numbered_markers() -> Vec<(String, u32, bool)> {
+    vec![
+        ("q".to_string(), 4, false),
+        ("qm".to_string(), 3, false),
+        ("pi".to_string(), 3, false),
+        ("ph".to_string(), 3, false),
+        ("li".to_string(), 4, false),
+        ("lim".to_string(), 4, false),
+        ("liv".to_string(), 4, false),
+        ("mt".to_string(), 4, false),
+        ("mte".to_string(), 2, false),
+        ("ms".to_string(), 3, false),
+        ("s".to_string(), 4, false),
+        ("sd".to_string(), 4, false),
+        ("imt".to_string(), 4, false),
+        ("imte".to_string(), 2, false),
+        ("is".to_string(), 2, false),
+        ("iq".to_string(), 3, false),
+        ("ili".to_string(), 2, false),
+        ("io".to_string(), 4, false),
+        ("toc".to_string(), 3, true),
+        ("toca".to_string(), 3, true),
+    ]
+}
+
+/// Returns the highest level of a numbered marker, or `None` if the marker takes no level.
+pub fn max_level(tag_name: &str) -> Option<u32> {
+    numbered_markers().into_iter().find(|(name, _, _)| name == tag_name).map(|(_, max, _)| max)
+}
+
+/// Whether a numbered marker is always written with its level, as `\toc1` is.
+pub fn requires_level(tag_name: &str) -> bool {
+    numbered_markers().into_iter().any(|(name, _, required)| required && name == tag_name)
+}
+
+/// Splits a marker into its name and level, e.g. `q2` into `("q", Some(2))`.
+pub fn split_level(marker: &str) -> (&str, Option<u32>) {
+    let name = marker.trim_end_matches(|c: char| c.is_ascii_digit());
+    (name, marker[name.len()..].parse().ok())
+}
+
+/// Writes the level of a numbered marker as asked by `levels`; other markers are returned unchanged.
+/// The markers that require their number always get it, e.g. `\toc` becomes `\toc1`.
+pub fn normalize_level(marker: &str, levels: MarkerLevels) -> String {
+    let (name, level) = split_level(marker);
+    if max_level(name).is_none() {
+        return marker.to_string();
+    }
+    if requires_level(name) {
+        return format!("{}{}", name, level.unwrap_or(1));
+    }
+    match (levels, level) {
+        (MarkerLevels::Implicit, Some(1)) => name.to_string(),
+        (MarkerLevels::Explicit, None) => format!("{}1", name),
+        _ => marker.to_string(),
+    }
+}
+
+pub fn cell_markers() -> Vec<String> {
+    vec![
+        "th".to_string(),
+        "thr".to_string(),
+        "thc".to_string(),
+        "tc".to_string(),
+        "tcr".to_string(),
+        "tcc".to_string(),
+    ]
+}
+
+/// Returns the USJ `align` of a table cell marker, e.g. `end` for `\tcr`.
+pub fn cell_align(tag_name: &str) -> &'static str {
+    if tag_name.ends_with('r') {
+        "end"
+    } else if tag_name.ends_with('c') && tag_name != "tc" {
+        "center"
+    } else {
+        "start"
+    }
+}
+
+/// Returns the attribute that `\marker text|value\marker*` gives a value to, e.g. `lemma` for `\w`.
+pub fn default_attribute(marker: &str) -> Option<&'static str> {
+    match marker.trim_start_matches('+') {
+        "w" => Some("lemma"),
+        "rb" => Some("gloss"),
+        "xt" | "jmp" => Some("link-href"),
+        "ref" => Some("loc"),
+        milestone if milestone.strip_suffix("-s").is_some_and(|name| split_level(name).0 == "qt") => Some("who"),
+        _ => None,
+    }
+}
+
+/// Character markers of a note content that USFM leaves unclosed: each one ends where the
+/// next one or the note ends.
+pub fn is_note_content_marker(marker: &str) -> bool {
+    matches!(marker, "fr" | "fq" | "fqa" | "fk" | "fl" | "fw" | "fp" | "ft" | "xo" | "xk" | "xq" | "xt" | "xta")
+}
+
 pub fn note_markers() -> Vec<String> {
     vec![
         "f".to_string(),
-        "ft".to_string(),
-        "fqa".to_string(),
+        "fe".to_string(),
+        "x".to_string(),
+        "ef".to_string(),
+        "ex".to_string(),
     ]
-}
\ No newline at end of file
+}
+/// Paragraph markers that hold titles, headings or introductions rather than
+/// verse text: a verse never goes on into them.
+pub fn heading_markers() -> Vec<String> {
+    vec![
+        "ide".to_string(),
+        "usfm".to_string(),
+        "sts".to_string(),
+        "rem".to_string(),
+        "h".to_string(),
+        "toc".to_string(),
+        "toca".to_string(),
+        "imt".to_string(),
+        "is".to_string(),
+        "ip".to_string(),
+        "ipi".to_string(),
+        "im".to_string(),
+        "imi".to_string(),
+        "ipq".to_string(),
+        "imq".to_string(),
+        "ipr".to_string(),
+        "iq".to_string(),
+        "ib".to_string(),
+        "ili".to_string(),
+        "iot".to_string(),
+        "io".to_string(),
+        "iex".to_string(),
+        "imte".to_string(),
+        "mt".to_string(),
+        "mte".to_string(),
+        "ms".to_string(),
+        "mr".to_string(),
+        "s".to_string(),
+        "sr".to_string(),
+        "r".to_string(),
+        "d".to_string(),
+        "sp".to_string(),
+        "sd".to_string(),
+        "cl".to_string(),
+    ]
+}
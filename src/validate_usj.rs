@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+/// # A place where a USJ document does not follow `assets/usj_schema.json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationIssue {
+    /// JSON pointer to the offending value, e.g. `/content/1/sid`.
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for ValidationIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at '{}'", self.message, self.path)
+    }
+}
+
+fn usj_schema() -> &'static Value {
+    static SCHEMA: OnceLock<Value> = OnceLock::new();
+    SCHEMA.get_or_init(|| serde_json::from_str(include_str!("../assets/usj_schema.json")).expect("Invalid bundled USJ schema"))
+}
+
+/// Checks a USJ document against the bundled USJ schema.
+///
+/// Every issue is returned, in document order; an empty list means the document is valid.
+///
+/// The schema is bundled as published. On top of it, the USJ that USFM and USX convert to
+/// is accepted as valid:
+/// - `char` objects with `lemma`, `closed` (`"true"` or `"false"`) or `x-*` attributes, as
+///   `\w` in USFM and `<char>` in USX have;
+/// - verse numbers with segment letters, e.g. `1a` or `3b-4`.
+///
+/// # Example
+/// ```
+/// use hallomai::validate_usj;
+///
+/// let usj = serde_json::json!({"type": "USJ", "version": "3.0", "content": [
+///     {"type": "book", "marker": "id", "code": "GEN", "banana": "split"}
+/// ]});
+/// let issues = validate_usj(&usj);
+/// assert_eq!(issues[0].path, "/content/0/banana");
+/// assert_eq!(issues[0].message, "unexpected property 'banana'");
+/// ```
+pub fn validate_usj(usj: &Value) -> Vec<ValidationIssue> {
+    let mut validator = Validator {
+        schema: usj_schema(),
+        patterns: HashMap::new(),
+        issues: Vec::new(),
+    };
+    validator.check(validator.schema, usj, "");
+    validator.issues
+}
+
+/// A verse number or range whose verses may have a segment letter.
+const SEGMENTED_VERSE_NUMBER: &str = "^[1-9][0-9]{0,2}[a-z]?(-[1-9][0-9]{0,2}[a-z]?)?$";
+
+/// Interprets the part of JSON Schema used by the USJ schema.
+struct Validator {
+    schema: &'static Value,
+    patterns: HashMap<&'static str, Regex>,
+    issues: Vec<ValidationIssue>,
+}
+
+impl Validator {
+    fn issue(&mut self, path: &str, message: String) {
+        self.issues.push(ValidationIssue {
+            path: path.to_string(),
+            message,
+        });
+    }
+
+    fn resolve(&self, schema: &'static Value) -> &'static Value {
+        match schema.get("$ref").and_then(Value::as_str) {
+            Some(reference) => self.schema.pointer(reference.trim_start_matches('#')).expect("Unresolved $ref in the USJ schema"),
+            None => schema,
+        }
+    }
+
+    fn check(&mut self, schema: &'static Value, value: &Value, path: &str) {
+        let schema = self.resolve(schema);
+        if let Some(alternatives) = schema.get("anyOf").and_then(Value::as_array) {
+            self.check_any_of(alternatives, value, path);
+        }
+        if let Some(expected) = schema.get("type").and_then(Value::as_str) {
+            if !has_type(value, expected) {
+                self.issue(path, format!("expected {}, found {}", expected, type_name(value)));
+                return;
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if value != expected {
+                self.issue(path, format!("expected {}, found {}", expected, value));
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                let allowed: Vec<String> = allowed.iter().map(Value::to_string).collect();
+                self.issue(path, format!("expected one of {}, found {}", allowed.join(", "), value));
+            }
+        }
+        if let (Some(pattern), Some(text)) = (schema.get("pattern").and_then(Value::as_str), value.as_str()) {
+            if !self.matches(pattern, text) {
+                self.issue(path, format!("'{}' does not match the pattern {}", text, pattern));
+            }
+        }
+        if let (Some(minimum), Some(number)) = (schema.get("minimum").and_then(Value::as_f64), value.as_f64()) {
+            if number < minimum {
+                self.issue(path, format!("{} is less than the minimum {}", number, minimum));
+            }
+        }
+        if let Some(obj) = value.as_object() {
+            self.check_object(schema, obj, path);
+        }
+        if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+            for (i, item) in array.iter().enumerate() {
+                self.check(items, item, &format!("{}/{}", path, i));
+            }
+        }
+    }
+
+    /// Objects are told apart by their `type`, so that the issues of the
+    /// alternative they were meant to be are reported, not a bare mismatch.
+    fn check_any_of(&mut self, alternatives: &'static [Value], value: &Value, path: &str) {
+        let alternatives: Vec<&'static Value> = alternatives.iter().map(|alternative| self.resolve(alternative)).collect();
+        if let Some(object_type) = value.get("type").filter(|_| value.is_object()) {
+            let meant = alternatives.iter().find(|alternative| {
+                let type_schema = alternative.pointer("/properties/type");
+                type_schema.and_then(|t| t.get("const")) == Some(object_type)
+                    || type_schema.and_then(|t| t.get("enum")).and_then(Value::as_array).is_some_and(|allowed| allowed.contains(object_type))
+            });
+            match meant {
+                Some(alternative) => self.check(alternative, value, path),
+                None => self.issue(&format!("{}/type", path), format!("unexpected type {}", object_type)),
+            }
+            return;
+        }
+        let issues = self.issues.len();
+        for alternative in &alternatives {
+            self.check(alternative, value, path);
+            let valid = self.issues.len() == issues;
+            self.issues.truncate(issues);
+            if valid {
+                return;
+            }
+        }
+        let mut expected: Vec<&str> = alternatives.iter().filter_map(|alternative| alternative.get("type").and_then(Value::as_str)).collect();
+        expected.dedup();
+        self.issue(path, format!("expected {}, found {}", expected.join(" or "), type_name(value)));
+    }
+
+    fn check_object(&mut self, schema: &'static Value, obj: &Map<String, Value>, path: &str) {
+        for key in schema.get("required").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str) {
+            if !obj.contains_key(key) {
+                self.issue(&format!("{}/{}", path, escape(key)), format!("missing required property '{}'", key));
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let pattern_properties = schema.get("patternProperties").and_then(Value::as_object);
+        for (key, value) in obj {
+            let key_path = format!("{}/{}", path, escape(key));
+            if self.is_known_deviation(schema, key, value) {
+                continue;
+            }
+            if let Some(property) = properties.and_then(|properties| properties.get(key)) {
+                self.check(property, value, &key_path);
+                continue;
+            }
+            let matching: Vec<&'static Value> = pattern_properties.into_iter().flatten()
+                .filter(|(pattern, _)| self.matches(pattern, key))
+                .map(|(_, property)| property)
+                .collect();
+            for property in &matching {
+                self.check(property, value, &key_path);
+            }
+            if matching.is_empty() && schema.get("additionalProperties") == Some(&Value::Bool(false)) {
+                self.issue(&key_path, format!("unexpected property '{}'", key));
+            }
+        }
+    }
+
+    /// Whether a property is one of the deviations from the schema listed by `validate_usj`.
+    fn is_known_deviation(&mut self, schema: &'static Value, key: &str, value: &Value) -> bool {
+        let is_definition = |name: &str| self.schema.pointer(&format!("/$defs/{}", name)).is_some_and(|definition| std::ptr::eq(definition, schema));
+        if is_definition("charMarkerObject") {
+            match key {
+                "lemma" => value.is_string(),
+                "closed" => value == "true" || value == "false",
+                _ => key.starts_with("x-") && value.is_string(),
+            }
+        } else if is_definition("verseObject") && key == "number" {
+            value.as_str().is_some_and(|number| self.matches(SEGMENTED_VERSE_NUMBER, number))
+        } else {
+            false
+        }
+    }
+
+    fn matches(&mut self, pattern: &'static str, text: &str) -> bool {
+        self.patterns
+            .entry(pattern)
+            .or_insert_with(|| Regex::new(pattern).expect("Invalid pattern in the USJ schema"))
+            .is_match(text)
+    }
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Escapes a key for a JSON pointer.
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
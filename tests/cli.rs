@@ -0,0 +1,16 @@
+use std::process::Command;
+
+fn exit_code(args: &[&str]) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_hallomai")).args(args).output().unwrap().status.code()
+}
+
+#[test]
+fn test_exit_codes_of_the_command_line() {
+    assert_eq!(exit_code(&[]), Some(2));
+    assert_eq!(exit_code(&["convert", "--bogus"]), Some(2));
+    assert_eq!(exit_code(&["convert", "assets/usfm/65-3JN.usfm"]), Some(2));
+    assert_eq!(exit_code(&["convert", "missing.usfm", "--to", "usx"]), Some(1));
+    assert_eq!(exit_code(&["convert", "assets/usfm/65-3JN.usfm", "--to", "usx"]), Some(0));
+    assert_eq!(exit_code(&["--help"]), Some(0));
+    assert_eq!(exit_code(&["--version"]), Some(0));
+}